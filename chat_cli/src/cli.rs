@@ -0,0 +1,72 @@
+use std::path::PathBuf;
+
+use chat_core::RowID;
+use clap::{Parser, Subcommand};
+
+/// Terminal client of chat_server and notify_server
+#[derive(Debug, Parser)]
+#[command(name = "chat", version)]
+pub struct Cli {
+    /// Base url of chat_server
+    #[arg(long, env = "CHAT_SERVER", default_value = "http://localhost:8080")]
+    pub server: String,
+    /// Base url of notify_server
+    #[arg(long, env = "CHAT_NOTIFY", default_value = "http://localhost:6687")]
+    pub notify: String,
+    /// Token to use instead of the one saved by `signin`
+    #[arg(long, env = "CHAT_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    /// Where `signin` saves the token, `~/.config/chat/token` by default
+    #[arg(long, env = "CHAT_TOKEN_FILE")]
+    pub token_file: Option<PathBuf>,
+    /// Print one JSON object per line instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Sign in and save the token
+    Signin {
+        #[arg(long)]
+        email: String,
+        /// Prompted for without echo if not given
+        #[arg(long, env = "CHAT_PASSWORD", hide_env_values = true)]
+        password: Option<String>,
+    },
+    /// List the users of the workspace
+    Users,
+    /// List the chats of the user
+    Chats,
+    /// Print messages of a chat, oldest first
+    Messages {
+        chat_id: RowID,
+        #[arg(long, default_value_t = 20)]
+        limit: u32,
+        /// Only messages older than this id
+        #[arg(long)]
+        before: Option<RowID>,
+    },
+    /// Print new messages of a chat as they arrive
+    Tail {
+        chat_id: RowID,
+        /// Recent messages printed first
+        #[arg(long, default_value_t = 10)]
+        history: u32,
+    },
+    /// Send a message, `-` reads the content from stdin
+    Send {
+        chat_id: RowID,
+        content: String,
+        /// Upload and attach a file, can be repeated
+        #[arg(long = "file", short = 'f')]
+        files: Vec<PathBuf>,
+    },
+    /// Upload files and print their urls
+    Upload {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+}
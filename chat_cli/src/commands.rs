@@ -0,0 +1,270 @@
+use std::{
+    fs,
+    io::{self, Read, Stdout, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context};
+use chat_client::{ChatClient, CreateMessage, EventOptions, ListMessages, UploadFile};
+use chat_core::{notify::NotifyEvent, Message, RowID};
+use futures::StreamExt;
+
+use crate::{
+    cli::{Cli, Command},
+    output::Printer,
+};
+
+/// Messages fetched at once after events were lost
+const RELOAD_LIMIT: u32 = 100;
+
+pub async fn run(cli: Cli) -> anyhow::Result<()> {
+    let client = ChatClient::new(&cli.server, &cli.notify);
+    let printer = Printer::new(io::stdout(), cli.json);
+
+    if let Command::Signin { email, password } = &cli.command {
+        let password = match password {
+            Some(password) => password.clone(),
+            None => read_password()?,
+        };
+        client.signin(email, &password).await?;
+        let path = token_file(&cli)?;
+        save_token(&path, &client.token().await?)?;
+        eprintln!("signed in, token saved to {}", path.display());
+        return Ok(());
+    }
+
+    let token = match &cli.token {
+        Some(token) => token.clone(),
+        None => {
+            let path = token_file(&cli)?;
+            let token = fs::read_to_string(&path).with_context(|| {
+                format!("not signed in, run `chat signin` ({})", path.display())
+            })?;
+            token.trim().to_string()
+        }
+    };
+    client.set_token(token).await;
+
+    match cli.command {
+        Command::Signin { .. } => unreachable!(),
+        Command::Users => users(&client, printer).await,
+        Command::Chats => chats(&client, printer).await,
+        Command::Messages {
+            chat_id,
+            limit,
+            before,
+        } => messages(&client, printer, chat_id, limit, before).await,
+        Command::Tail { chat_id, history } => tail(&client, printer, chat_id, history).await,
+        Command::Send {
+            chat_id,
+            content,
+            files,
+        } => send(&client, printer, chat_id, content, &files).await,
+        Command::Upload { files } => upload(&client, printer, &files).await,
+    }
+}
+
+async fn users(client: &ChatClient, mut printer: Printer<Stdout>) -> anyhow::Result<()> {
+    for user in client.list_users().await? {
+        printer.user(&user)?;
+    }
+    Ok(())
+}
+
+async fn chats(client: &ChatClient, mut printer: Printer<Stdout>) -> anyhow::Result<()> {
+    for chat in client.list_chats().await? {
+        printer.chat(&chat)?;
+    }
+    Ok(())
+}
+
+async fn messages(
+    client: &ChatClient,
+    printer: Printer<Stdout>,
+    chat_id: RowID,
+    limit: u32,
+    before: Option<RowID>,
+) -> anyhow::Result<()> {
+    let mut printer = printer.with_users(&client.list_users().await?);
+    for message in recent_messages(client, chat_id, limit, before).await? {
+        printer.message(&message)?;
+    }
+    Ok(())
+}
+
+async fn tail(
+    client: &ChatClient,
+    printer: Printer<Stdout>,
+    chat_id: RowID,
+    history: u32,
+) -> anyhow::Result<()> {
+    let mut printer = printer.with_users(&client.list_users().await?);
+    // fails early if the user can't read the chat
+    let mut last_id = 0;
+    for message in recent_messages(client, chat_id, history, None).await? {
+        last_id = message.id;
+        printer.message(&message)?;
+    }
+
+    let options = EventOptions {
+        types: vec!["NewMessage".to_string()],
+        chats: vec![chat_id],
+        ..Default::default()
+    };
+    let mut events = Box::pin(client.events(options));
+    while let Some(envelope) = events.next().await {
+        let messages = match envelope?.event {
+            NotifyEvent::NewMessage(message) => vec![message],
+            NotifyEvent::Resync(_) => {
+                eprintln!("events were lost, reloading");
+                recent_messages(client, chat_id, RELOAD_LIMIT, None).await?
+            }
+            _ => continue,
+        };
+        // messages sent between loading the history and connecting may arrive twice
+        for message in messages {
+            if message.id <= last_id {
+                continue;
+            }
+            last_id = message.id;
+            printer.message(&message)?;
+        }
+    }
+    Ok(())
+}
+
+async fn send(
+    client: &ChatClient,
+    mut printer: Printer<Stdout>,
+    chat_id: RowID,
+    content: String,
+    files: &[PathBuf],
+) -> anyhow::Result<()> {
+    let content = if content == "-" {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        content.trim_end().to_string()
+    } else {
+        content
+    };
+    if content.is_empty() {
+        bail!("message content is empty");
+    }
+
+    let files = if files.is_empty() {
+        vec![]
+    } else {
+        client.upload(&read_files(files)?).await?
+    };
+    let input = CreateMessage { content, files };
+    let message = client.send_message(chat_id, &input).await?;
+    printer.message(&message)
+}
+
+async fn upload(
+    client: &ChatClient,
+    mut printer: Printer<Stdout>,
+    files: &[PathBuf],
+) -> anyhow::Result<()> {
+    for url in client.upload(&read_files(files)?).await? {
+        printer.url(&url)?;
+    }
+    Ok(())
+}
+
+/// The latest messages before `before`, oldest first
+async fn recent_messages(
+    client: &ChatClient,
+    chat_id: RowID,
+    limit: u32,
+    before: Option<RowID>,
+) -> anyhow::Result<Vec<Message>> {
+    let input = ListMessages {
+        last_id: before,
+        limit,
+    };
+    let mut messages = client.list_messages(chat_id, &input).await?;
+    messages.reverse();
+    Ok(messages)
+}
+
+fn read_files(paths: &[PathBuf]) -> anyhow::Result<Vec<UploadFile>> {
+    paths
+        .iter()
+        .map(|path| {
+            let filename = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| anyhow!("invalid file name: {}", path.display()))?;
+            let data = fs::read(path).with_context(|| format!("read {}", path.display()))?;
+            Ok(UploadFile {
+                filename: filename.to_string(),
+                data: data.into(),
+            })
+        })
+        .collect()
+}
+
+fn read_password() -> anyhow::Result<String> {
+    rpassword::prompt_password("password: ").context("read password")
+}
+
+fn token_file(cli: &Cli) -> anyhow::Result<PathBuf> {
+    if let Some(path) = &cli.token_file {
+        return Ok(path.clone());
+    }
+    let home = std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?;
+    Ok(Path::new(&home).join(".config/chat/token"))
+}
+
+fn save_token(path: &Path, token: &str) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("open {}", path.display()))?;
+    // files saved before keep their mode when opened, restrict them before writing
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(token.as_bytes())
+        .with_context(|| format!("write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn t_save_token() {
+        let dir = std::env::temp_dir().join(format!("chat-cli-{}", std::process::id()));
+        let path = dir.join("token");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "old token").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        save_token(&path, "token").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "token");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_file(&path).unwrap();
+        save_token(&path, "new").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
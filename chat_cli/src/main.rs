@@ -0,0 +1,34 @@
+mod cli;
+mod commands;
+mod output;
+
+use clap::Parser;
+
+use cli::Cli;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    commands::run(cli).await
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn t_cli_args() {
+        Cli::command().debug_assert();
+
+        let cli =
+            Cli::try_parse_from(["chat", "send", "1", "hi", "-f", "a.txt", "--json"]).unwrap();
+        assert!(cli.json);
+        assert!(matches!(
+            cli.command,
+            cli::Command::Send { chat_id: 1, ref files, .. } if files.len() == 1
+        ));
+        assert!(Cli::try_parse_from(["chat", "upload"]).is_err());
+    }
+}
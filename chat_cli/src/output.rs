@@ -0,0 +1,148 @@
+use std::{collections::HashMap, io::Write};
+
+use chat_core::{Chat, Message, RowID, User};
+use chrono::Local;
+use serde::Serialize;
+use serde_json::json;
+
+/// Writes records as text or as JSON lines
+pub struct Printer<W> {
+    out: W,
+    json: bool,
+    /// Names of the senders of messages
+    names: HashMap<RowID, String>,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(out: W, json: bool) -> Self {
+        Self {
+            out,
+            json,
+            names: HashMap::new(),
+        }
+    }
+
+    pub fn with_users(mut self, users: &[User]) -> Self {
+        self.names = users
+            .iter()
+            .map(|user| (user.id, user.fullname.clone()))
+            .collect();
+        self
+    }
+
+    pub fn user(&mut self, user: &User) -> anyhow::Result<()> {
+        if self.json {
+            return self.json_line(user);
+        }
+        writeln!(self.out, "{}\t{}\t{}", user.id, user.fullname, user.email)?;
+        Ok(())
+    }
+
+    pub fn chat(&mut self, chat: &Chat) -> anyhow::Result<()> {
+        if self.json {
+            return self.json_line(chat);
+        }
+        let members: Vec<_> = chat.members.iter().map(ToString::to_string).collect();
+        writeln!(
+            self.out,
+            "{}\t{}\t{:?}\t{}",
+            chat.id,
+            chat.name.as_deref().unwrap_or("-"),
+            chat.typ,
+            members.join(",")
+        )?;
+        Ok(())
+    }
+
+    pub fn message(&mut self, message: &Message) -> anyhow::Result<()> {
+        if self.json {
+            return self.json_line(message);
+        }
+        let sender = match self.names.get(&message.sender_id) {
+            Some(name) => name.clone(),
+            None => format!("user {}", message.sender_id),
+        };
+        let time = message.created_at.with_timezone(&Local);
+        writeln!(
+            self.out,
+            "[{}] {}: {}",
+            time.format("%Y-%m-%d %H:%M:%S"),
+            sender,
+            message.content
+        )?;
+        for file in &message.files {
+            writeln!(self.out, "    file: {}", file)?;
+        }
+        Ok(())
+    }
+
+    pub fn url(&mut self, url: &str) -> anyhow::Result<()> {
+        if self.json {
+            return self.json_line(&json!({ "url": url }));
+        }
+        writeln!(self.out, "{}", url)?;
+        Ok(())
+    }
+
+    fn json_line<T: Serialize>(&mut self, value: &T) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.out, value)?;
+        writeln!(self.out)?;
+        // `tail` is usually piped
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn message(sender_id: RowID) -> Message {
+        Message {
+            id: 1,
+            chat_id: 2,
+            sender_id,
+            content: "hello".to_string(),
+            files: vec!["/files/1/a.txt".to_string()],
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn t_print_messages() {
+        let user = User {
+            id: 3,
+            fullname: "Alice".to_string(),
+            email: "alice@a.com".to_string(),
+            password_hash: Default::default(),
+            ws_id: 1,
+            is_bot: false,
+            created_at: Utc::now(),
+        };
+
+        let mut out = vec![];
+        let mut printer = Printer::new(&mut out, false).with_users(&[user]);
+        printer.message(&message(3)).unwrap();
+        printer.message(&message(4)).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines[0].ends_with("] Alice: hello"));
+        assert_eq!(lines[1], "    file: /files/1/a.txt");
+        assert!(lines[2].ends_with("] user 4: hello"));
+
+        let mut out = vec![];
+        let mut printer = Printer::new(&mut out, true);
+        printer.message(&message(3)).unwrap();
+        printer.url("/files/1/a.txt").unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<serde_json::Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["content"], "hello");
+        assert_eq!(lines[0]["sender_id"], 3);
+        assert_eq!(lines[1], json!({ "url": "/files/1/a.txt" }));
+    }
+}
@@ -0,0 +1,203 @@
+use bytes::Bytes;
+use chat_core::{Chat, Message, RowID, User};
+use reqwest::{
+    multipart::{Form, Part},
+    Method,
+};
+use serde_json::json;
+
+use crate::{
+    types::FinishedUpload, ApiKey, ChatClient, ChatSettings, ClientResult, CreateApiKey,
+    CreateChat, CreateMessage, CreateUpload, DndSchedule, FileMeta, ListMessages, NewApiKey,
+    ThumbnailSize, UpdateChat, UpdateChatSettings, UpdateDndSchedule, UploadFile, UploadSession,
+};
+
+/// Byte offset the chunk of a resumable upload starts at
+const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+
+impl ChatClient {
+    /// Users of the workspace
+    pub async fn list_users(&self) -> ClientResult<Vec<User>> {
+        self.get_json("/api/users").await
+    }
+
+    pub async fn list_chats(&self) -> ClientResult<Vec<Chat>> {
+        self.get_json("/api/chat").await
+    }
+
+    pub async fn create_chat(&self, input: &CreateChat) -> ClientResult<Chat> {
+        self.send_json(Method::POST, "/api/chat", input).await
+    }
+
+    pub async fn get_chat(&self, id: RowID) -> ClientResult<Chat> {
+        self.get_json(&format!("/api/chat/{}", id)).await
+    }
+
+    pub async fn update_chat(&self, id: RowID, input: &UpdateChat) -> ClientResult<Chat> {
+        self.send_json(Method::PATCH, &format!("/api/chat/{}", id), input)
+            .await
+    }
+
+    /// The messages of the chat are deleted with it
+    pub async fn delete_chat(&self, id: RowID) -> ClientResult<()> {
+        let path = format!("/api/chat/{}", id);
+        self.send(Method::DELETE, &path, |req| req).await?;
+        Ok(())
+    }
+
+    pub async fn add_members(&self, chat_id: RowID, members: &[RowID]) -> ClientResult<Chat> {
+        let path = format!("/api/chat/{}/members", chat_id);
+        let input = json!({ "members": members });
+        self.send_json(Method::POST, &path, &input).await
+    }
+
+    /// Remove members, or leave the chat with the id of the user
+    pub async fn remove_members(&self, chat_id: RowID, members: &[RowID]) -> ClientResult<Chat> {
+        let path = format!("/api/chat/{}/members", chat_id);
+        let input = json!({ "members": members });
+        self.send_json(Method::DELETE, &path, &input).await
+    }
+
+    pub async fn list_messages(
+        &self,
+        chat_id: RowID,
+        input: &ListMessages,
+    ) -> ClientResult<Vec<Message>> {
+        let path = format!("/api/chat/{}/message", chat_id);
+        let resp = self
+            .send(Method::GET, &path, |req| req.query(input))
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    pub async fn send_message(
+        &self,
+        chat_id: RowID,
+        input: &CreateMessage,
+    ) -> ClientResult<Message> {
+        let path = format!("/api/chat/{}/message", chat_id);
+        self.send_json(Method::PUT, &path, input).await
+    }
+
+    pub async fn get_chat_settings(&self, chat_id: RowID) -> ClientResult<ChatSettings> {
+        self.get_json(&format!("/api/chat/{}/settings", chat_id))
+            .await
+    }
+
+    pub async fn update_chat_settings(
+        &self,
+        chat_id: RowID,
+        input: &UpdateChatSettings,
+    ) -> ClientResult<ChatSettings> {
+        let path = format!("/api/chat/{}/settings", chat_id);
+        self.send_json(Method::PUT, &path, input).await
+    }
+
+    /// Fails with 404 if no schedule is set
+    pub async fn get_dnd(&self) -> ClientResult<DndSchedule> {
+        self.get_json("/api/dnd").await
+    }
+
+    pub async fn update_dnd(&self, input: &UpdateDndSchedule) -> ClientResult<DndSchedule> {
+        self.send_json(Method::PUT, "/api/dnd", input).await
+    }
+
+    pub async fn delete_dnd(&self) -> ClientResult<()> {
+        self.send(Method::DELETE, "/api/dnd", |req| req).await?;
+        Ok(())
+    }
+
+    /// Upload files in one request, return their urls in order
+    pub async fn upload(&self, files: &[UploadFile]) -> ClientResult<Vec<String>> {
+        let form = || {
+            files.iter().fold(Form::new(), |form, file| {
+                let part = Part::stream(file.data.clone()).file_name(file.filename.clone());
+                form.part("file", part)
+            })
+        };
+        let resp = self
+            .send(Method::POST, "/api/upload", |req| req.multipart(form()))
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Content of a file by the url returned on upload, or of one of its thumbnails
+    pub async fn download(&self, url: &str, size: Option<ThumbnailSize>) -> ClientResult<Bytes> {
+        let path = format!("/api{}", url);
+        let resp = self
+            .send(Method::GET, &path, |req| match size {
+                Some(size) => req.query(&[("size", size)]),
+                None => req,
+            })
+            .await?;
+        Ok(resp.bytes().await?)
+    }
+
+    pub async fn file_meta(&self, url: &str) -> ClientResult<FileMeta> {
+        let path = url.replacen("/files/", "/api/files-meta/", 1);
+        self.get_json(&path).await
+    }
+
+    /// Start a resumable upload
+    pub async fn create_upload(&self, input: &CreateUpload) -> ClientResult<UploadSession> {
+        self.send_json(Method::POST, "/api/uploads", input).await
+    }
+
+    pub async fn get_upload(&self, id: &str) -> ClientResult<UploadSession> {
+        self.get_json(&format!("/api/uploads/{}", id)).await
+    }
+
+    /// Append a chunk at `offset`, which has to be the `received` of the session
+    pub async fn append_upload(
+        &self,
+        id: &str,
+        offset: i64,
+        chunk: Bytes,
+    ) -> ClientResult<UploadSession> {
+        let path = format!("/api/uploads/{}", id);
+        let resp = self
+            .send(Method::PATCH, &path, |req| {
+                req.header(UPLOAD_OFFSET_HEADER, offset).body(chunk.clone())
+            })
+            .await?;
+        Ok(resp.json().await?)
+    }
+
+    /// Complete an upload once every byte is received, return the url of the file
+    pub async fn finish_upload(&self, id: &str) -> ClientResult<String> {
+        let path = format!("/api/uploads/{}/finish", id);
+        let resp = self.send(Method::POST, &path, |req| req).await?;
+        let output: FinishedUpload = resp.json().await?;
+        Ok(output.url)
+    }
+
+    /// Bots of the workspace, only for its owner
+    pub async fn list_bots(&self) -> ClientResult<Vec<User>> {
+        self.get_json("/api/bots").await
+    }
+
+    pub async fn create_bot(&self, fullname: &str) -> ClientResult<User> {
+        let input = json!({ "fullname": fullname });
+        self.send_json(Method::POST, "/api/bots", &input).await
+    }
+
+    pub async fn list_api_keys(&self, bot_id: RowID) -> ClientResult<Vec<ApiKey>> {
+        self.get_json(&format!("/api/bots/{}/keys", bot_id)).await
+    }
+
+    pub async fn create_api_key(
+        &self,
+        bot_id: RowID,
+        input: &CreateApiKey,
+    ) -> ClientResult<NewApiKey> {
+        let path = format!("/api/bots/{}/keys", bot_id);
+        self.send_json(Method::POST, &path, input).await
+    }
+
+    /// The key is rejected right away
+    pub async fn revoke_api_key(&self, bot_id: RowID, key_id: RowID) -> ClientResult<()> {
+        let path = format!("/api/bots/{}/keys/{}", bot_id, key_id);
+        self.send(Method::DELETE, &path, |req| req).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde::Deserialize;
+
+use crate::{ChatClient, ClientError, ClientResult, CreateUser, SigninInput, SigninOutput};
+
+/// Tokens are renewed this long before they expire
+const REFRESH_MARGIN_SECS: u64 = 300;
+
+/// chat_server has no refresh tokens, an expired or rejected token is replaced by signing in
+/// again with the credentials it was obtained with
+#[derive(Default)]
+pub(crate) struct Session {
+    token: Option<String>,
+    expires_at: Option<u64>,
+    credentials: Option<SigninInput>,
+}
+
+impl ChatClient {
+    pub async fn signup(&self, input: CreateUser) -> ClientResult<()> {
+        let credentials = SigninInput {
+            email: input.email.clone(),
+            password: input.password.clone(),
+        };
+        let token = self.fetch_token("/signup", &input).await?;
+        self.inner
+            .session
+            .lock()
+            .await
+            .set(token, Some(credentials));
+        Ok(())
+    }
+
+    /// Sign in and keep the credentials to renew the token
+    pub async fn signin(&self, email: &str, password: &str) -> ClientResult<()> {
+        let credentials = SigninInput {
+            email: email.to_string(),
+            password: password.to_string(),
+        };
+        let token = self.fetch_token("/signin", &credentials).await?;
+        self.inner
+            .session
+            .lock()
+            .await
+            .set(token, Some(credentials));
+        Ok(())
+    }
+
+    /// Use a token obtained elsewhere. It can't be renewed unless the client signed in before.
+    pub async fn set_token(&self, token: impl Into<String>) {
+        let mut session = self.inner.session.lock().await;
+        let credentials = session.credentials.take();
+        session.set(token.into(), credentials);
+    }
+
+    /// The current token, renewed if it is about to expire
+    pub async fn token(&self) -> ClientResult<String> {
+        let mut session = self.inner.session.lock().await;
+        let token = session.token.clone().ok_or(ClientError::Unauthenticated)?;
+        if !session.expiring() {
+            return Ok(token);
+        }
+        match session.credentials.clone() {
+            Some(credentials) => {
+                let token = self.fetch_token("/signin", &credentials).await?;
+                session.set(token.clone(), Some(credentials));
+                Ok(token)
+            }
+            // let the server decide
+            None => Ok(token),
+        }
+    }
+
+    /// Replace a token the server rejected, unless another request already did
+    pub(crate) async fn refresh(&self, rejected: &str) -> ClientResult<String> {
+        let mut session = self.inner.session.lock().await;
+        match (&session.token, &session.credentials) {
+            (Some(token), _) if token != rejected => Ok(token.clone()),
+            (_, Some(credentials)) => {
+                let credentials = credentials.clone();
+                let token = self.fetch_token("/signin", &credentials).await?;
+                session.set(token.clone(), Some(credentials));
+                Ok(token)
+            }
+            _ => Err(ClientError::Unauthenticated),
+        }
+    }
+
+    async fn fetch_token<T: serde::Serialize>(&self, path: &str, body: &T) -> ClientResult<String> {
+        let resp = self
+            .inner
+            .http
+            .post(self.url(path))
+            .json(body)
+            .send()
+            .await?;
+        if !resp.status().is_success() {
+            return Err(ClientError::from_response(resp).await);
+        }
+        let output: SigninOutput = resp.json().await?;
+        Ok(output.token)
+    }
+}
+
+impl Session {
+    fn set(&mut self, token: String, credentials: Option<SigninInput>) {
+        self.expires_at = token_expiry(&token);
+        self.token = Some(token);
+        self.credentials = credentials;
+    }
+
+    fn expiring(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.expires_at
+            .is_some_and(|exp| exp <= now + REFRESH_MARGIN_SECS)
+    }
+}
+
+/// `exp` of a jwt, read without verifying the signature
+fn token_expiry(token: &str) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Claims {
+        exp: u64,
+    }
+
+    let payload = token.split('.').nth(1)?;
+    let payload = URL_SAFE_NO_PAD.decode(payload).ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+    Some(claims.exp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_token_expiry() {
+        let payload = URL_SAFE_NO_PAD.encode(r#"{"uid":1,"exp":1700000000}"#);
+        let token = format!("eyJhbGciOiJFZERTQSJ9.{}.sig", payload);
+        assert_eq!(token_expiry(&token), Some(1700000000));
+        assert_eq!(token_expiry("nothing"), None);
+
+        let mut session = Session::default();
+        session.set(token, None);
+        assert!(session.expiring());
+        session.set("nothing".to_string(), None);
+        assert!(!session.expiring());
+    }
+}
@@ -0,0 +1,53 @@
+use chat_core::notify::DecodeError;
+use reqwest::StatusCode;
+use thiserror::Error;
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// The server rejected the request, `message` is the `error` of its response
+    #[error("{status}: {message}")]
+    Api { status: StatusCode, message: String },
+    #[error("not signed in")]
+    Unauthenticated,
+    #[error("http error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Event(#[from] DecodeError),
+}
+
+impl ClientError {
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Api { status, .. } => Some(*status),
+            ClientError::Unauthenticated => Some(StatusCode::UNAUTHORIZED),
+            ClientError::Http(e) => e.status(),
+            ClientError::Event(_) => None,
+        }
+    }
+
+    /// The token is missing, invalid or expired. `verify_token` answers 403 with a plain
+    /// `invalid token` for tokens it can't verify.
+    pub fn is_token_rejected(&self) -> bool {
+        match self {
+            ClientError::Api { status, message } => {
+                *status == StatusCode::UNAUTHORIZED
+                    || (*status == StatusCode::FORBIDDEN && message == "invalid token")
+            }
+            ClientError::Unauthenticated => true,
+            _ => false,
+        }
+    }
+
+    /// Build the error of a failed response from its `{"error": "..."}` body
+    pub(crate) async fn from_response(resp: reqwest::Response) -> Self {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        let message = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|v| v.get("error")?.as_str().map(ToOwned::to_owned))
+            .unwrap_or(body);
+        ClientError::Api { status, message }
+    }
+}
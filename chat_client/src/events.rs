@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use async_stream::stream;
+use chat_core::notify::{EventEnvelope, NotifyEvent};
+use futures::{Stream, StreamExt};
+use reqwest_eventsource::{Error as SseError, Event, EventSource};
+use tokio::time;
+use tracing::warn;
+
+use crate::{ChatClient, ClientError, ClientResult, EventOptions};
+
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+impl ChatClient {
+    /// Events of notify_server's `/events`. The stream reconnects with backoff when the
+    /// connection is lost or closed by the server, resuming after the last received event, and
+    /// renews the token when it is rejected. It only ends with an error that reconnecting
+    /// can't fix. Events that fail to decode are yielded as errors and skipped.
+    pub fn events(&self, options: EventOptions) -> impl Stream<Item = ClientResult<EventEnvelope>> {
+        let client = self.clone();
+        let url = format!("{}/events", self.inner.notify_url);
+        let mut query = vec![];
+        if !options.types.is_empty() {
+            query.push(("types", options.types.join(",")));
+        }
+        if !options.chats.is_empty() {
+            let chats: Vec<_> = options.chats.iter().map(ToString::to_string).collect();
+            query.push(("chats", chats.join(",")));
+        }
+        if options.lite {
+            query.push(("lite", "true".to_string()));
+        }
+
+        stream! {
+            let mut last_id = options.last_event_id;
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                let token = match client.token().await {
+                    Ok(token) => token,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                let mut req = client.inner.http.get(&url).query(&query).bearer_auth(&token);
+                if let Some(id) = &last_id {
+                    req = req.header(LAST_EVENT_ID_HEADER, id);
+                }
+                let mut es = EventSource::new(req).expect("a GET request can be cloned");
+
+                while let Some(event) = es.next().await {
+                    match event {
+                        Ok(Event::Open) => backoff = MIN_BACKOFF,
+                        Ok(Event::Message(msg)) => match EventEnvelope::decode(&msg.data) {
+                            Ok(envelope) => {
+                                if envelope.id.is_some() {
+                                    last_id.clone_from(&envelope.id);
+                                }
+                                let disconnect = matches!(envelope.event, NotifyEvent::Disconnect(_));
+                                yield Ok(envelope);
+                                if disconnect {
+                                    break;
+                                }
+                            }
+                            Err(e) => yield Err(e.into()),
+                        },
+                        Err(SseError::InvalidStatusCode(status, resp)) if status.is_client_error() => {
+                            let err = ClientError::from_response(resp).await;
+                            if !err.is_token_rejected() {
+                                yield Err(err);
+                                return;
+                            }
+                            if let Err(e) = client.refresh(&token).await {
+                                yield Err(e);
+                                return;
+                            }
+                            // reconnect right away with the new token
+                            backoff = Duration::ZERO;
+                            break;
+                        }
+                        Err(SseError::StreamEnded) => break,
+                        Err(e) => {
+                            warn!("event stream error: {}", e);
+                            break;
+                        }
+                    }
+                }
+                // reconnecting is up to us
+                es.close();
+
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{
+        body::Body,
+        extract::State,
+        http::{header, HeaderMap, StatusCode},
+        response::{IntoResponse, Response},
+        routing::{get, post},
+        Json, Router,
+    };
+    use chat_core::notify::{Disconnect, ReadMarker};
+    use chrono::Utc;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Authorization and Last-Event-ID of every connection to `/events`
+    type Connections = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    fn sse(id: Option<u32>, event: NotifyEvent) -> String {
+        let envelope = EventEnvelope::new(event, id.map(|id| id.to_string()), Utc::now());
+        format!("data: {}\n\n", serde_json::to_string(&envelope).unwrap())
+    }
+
+    fn marker(id: u32) -> String {
+        let event = NotifyEvent::ReadMarker(ReadMarker {
+            chat_id: 1,
+            message_id: id as _,
+        });
+        sse(Some(id), event)
+    }
+
+    fn event_stream(data: String, keep_open: bool) -> Response {
+        let head = futures::stream::once(async move { Ok::<_, std::io::Error>(data) });
+        let body = match keep_open {
+            true => Body::from_stream(head.chain(futures::stream::pending())),
+            false => Body::from_stream(head),
+        };
+        ([(header::CONTENT_TYPE, "text/event-stream")], body).into_response()
+    }
+
+    /// The connection is closed after two events, the token is rejected once, then the server
+    /// asks the client to disconnect
+    async fn events_handler(State(conns): State<Connections>, headers: HeaderMap) -> Response {
+        let header = |name| headers.get(name).map(|v| v.to_str().unwrap().to_string());
+        let auth = header(header::AUTHORIZATION.as_str()).unwrap_or_default();
+        let n = {
+            let mut conns = conns.lock().unwrap();
+            conns.push((auth, header(LAST_EVENT_ID_HEADER)));
+            conns.len()
+        };
+        match n {
+            1 => event_stream(marker(1) + &marker(2), false),
+            2 => (StatusCode::FORBIDDEN, "invalid token").into_response(),
+            3 => {
+                let reason = "too slow to keep up with events".to_string();
+                let disconnect = sse(None, NotifyEvent::Disconnect(Disconnect { reason }));
+                event_stream(marker(3) + &disconnect, true)
+            }
+            _ => event_stream(marker(4), true),
+        }
+    }
+
+    #[tokio::test]
+    async fn t_events_resume() {
+        let conns = Connections::default();
+        let signins = Arc::new(Mutex::new(0));
+        let signin = {
+            let signins = signins.clone();
+            move || async move {
+                let mut n = signins.lock().unwrap();
+                *n += 1;
+                Json(json!({ "token": format!("token-{}", n) }))
+            }
+        };
+        let router = Router::new()
+            .route("/signin", post(signin))
+            .route("/events", get(events_handler))
+            .with_state(conns.clone());
+        let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", ls.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(ls, router).await.unwrap() });
+
+        let client = ChatClient::new(&url, &url);
+        client.signin("a@b.com", "pass").await.unwrap();
+        let events = client.events(Default::default());
+        let envelopes: Vec<_> = events.take(5).map(Result::unwrap).collect().await;
+
+        // every event once, in order, with the disconnect in between
+        let ids: Vec<_> = envelopes.iter().map(|ev| ev.id.as_deref()).collect();
+        assert_eq!(ids, [Some("1"), Some("2"), Some("3"), None, Some("4")]);
+        assert!(matches!(envelopes[3].event, NotifyEvent::Disconnect(_)));
+
+        // resumed after the last event each time, with a new token after the rejected one
+        let conns = conns.lock().unwrap().clone();
+        let expected = [
+            ("token-1", None),
+            ("token-1", Some("2")),
+            ("token-2", Some("2")),
+            ("token-2", Some("3")),
+        ];
+        assert_eq!(conns.len(), expected.len());
+        for ((auth, last_id), (token, expected_id)) in conns.iter().zip(expected) {
+            assert_eq!(auth, &format!("Bearer {}", token));
+            assert_eq!(last_id.as_deref(), expected_id);
+        }
+        assert_eq!(*signins.lock().unwrap(), 2);
+    }
+}
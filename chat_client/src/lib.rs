@@ -0,0 +1,101 @@
+mod api;
+mod auth;
+mod error;
+mod events;
+mod types;
+
+use std::sync::Arc;
+
+use reqwest::{Method, RequestBuilder, Response};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Mutex;
+
+pub use error::{ClientError, ClientResult};
+pub use types::*;
+
+use auth::Session;
+
+/// Client of chat_server and notify_server, cheap to clone and share between tasks
+#[derive(Clone)]
+pub struct ChatClient {
+    inner: Arc<ClientInner>,
+}
+
+struct ClientInner {
+    http: reqwest::Client,
+    chat_url: String,
+    notify_url: String,
+    session: Mutex<Session>,
+}
+
+impl ChatClient {
+    /// `chat_url` and `notify_url` are the base urls of the servers, e.g. `http://localhost:6688`
+    pub fn new(chat_url: impl Into<String>, notify_url: impl Into<String>) -> Self {
+        Self::with_http_client(reqwest::Client::new(), chat_url, notify_url)
+    }
+
+    pub fn with_http_client(
+        http: reqwest::Client,
+        chat_url: impl Into<String>,
+        notify_url: impl Into<String>,
+    ) -> Self {
+        let trim = |url: String| url.trim_end_matches('/').to_string();
+        let inner = ClientInner {
+            http,
+            chat_url: trim(chat_url.into()),
+            notify_url: trim(notify_url.into()),
+            session: Mutex::new(Session::default()),
+        };
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.inner.chat_url, path)
+    }
+
+    /// Send an authenticated request to chat_server. If the token is rejected, the client signs
+    /// in again and retries once, so `build` may be called twice.
+    async fn send<F>(&self, method: Method, path: &str, build: F) -> ClientResult<Response>
+    where
+        F: Fn(RequestBuilder) -> RequestBuilder,
+    {
+        let url = self.url(path);
+        let request = |token: &str| {
+            let req = self.inner.http.request(method.clone(), &url);
+            build(req).bearer_auth(token).send()
+        };
+
+        let token = self.token().await?;
+        let resp = request(&token).await?;
+        if resp.status().is_success() {
+            return Ok(resp);
+        }
+        let err = ClientError::from_response(resp).await;
+        if !err.is_token_rejected() {
+            return Err(err);
+        }
+
+        let token = self.refresh(&token).await?;
+        let resp = request(&token).await?;
+        if !resp.status().is_success() {
+            return Err(ClientError::from_response(resp).await);
+        }
+        Ok(resp)
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> ClientResult<T> {
+        let resp = self.send(Method::GET, path, |req| req).await?;
+        Ok(resp.json().await?)
+    }
+
+    async fn send_json<B, T>(&self, method: Method, path: &str, body: &B) -> ClientResult<T>
+    where
+        B: Serialize,
+        T: DeserializeOwned,
+    {
+        let resp = self.send(method, path, |req| req.json(body)).await?;
+        Ok(resp.json().await?)
+    }
+}
@@ -0,0 +1,194 @@
+//! Request and response bodies of chat_server that aren't chat_core models
+
+use bytes::Bytes;
+use chat_core::{utils::ApiScope, RowID};
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateUser {
+    pub fullname: String,
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SigninInput {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SigninOutput {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateChat {
+    pub name: Option<String>,
+    pub members: Vec<RowID>,
+    pub public: bool,
+}
+
+/// Fields not given are kept
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdateChat {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CreateMessage {
+    pub content: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+}
+
+/// A page of messages older than `last_id`, newest first
+#[derive(Debug, Clone, Serialize)]
+pub struct ListMessages {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_id: Option<RowID>,
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    Mentions,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChatSettings {
+    pub chat_id: RowID,
+    pub level: NotifyLevel,
+    /// Every message is silent until then
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdateChatSettings {
+    pub level: NotifyLevel,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+/// Daily window in the local time of `timezone`, it wraps around midnight if `end_time` is earlier
+#[derive(Debug, Clone, Deserialize)]
+pub struct DndSchedule {
+    pub ws_id: RowID,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub timezone: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateDndSchedule {
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    /// The server defaults to UTC
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+}
+
+/// A file sent in a multipart upload
+#[derive(Debug, Clone)]
+pub struct UploadFile {
+    pub filename: String,
+    pub data: Bytes,
+}
+
+/// Thumbnails of images are scaled to fit in a square of the size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThumbnailSize {
+    Small,
+    Medium,
+    Large,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileMeta {
+    pub id: RowID,
+    pub ws_id: RowID,
+    pub uploader_id: RowID,
+    pub filename: String,
+    pub size: i64,
+    pub mime: String,
+    pub hash: String,
+    pub url: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateUpload {
+    pub filename: String,
+    pub size: i64,
+}
+
+/// A resumable upload, chunks are appended at `received`
+#[derive(Debug, Clone, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub ws_id: RowID,
+    pub user_id: RowID,
+    pub filename: String,
+    pub size: i64,
+    pub received: i64,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct FinishedUpload {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Never expires if not given
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// An API key of a bot without the key itself
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    pub id: RowID,
+    pub user_id: RowID,
+    pub ws_id: RowID,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_by: RowID,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// The key is only returned when it's created, bots use it with `set_token`
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+/// Which events `/events` delivers, everything by default
+#[derive(Debug, Clone, Default)]
+pub struct EventOptions {
+    /// Event types, e.g. `NewMessage`
+    pub types: Vec<String>,
+    pub chats: Vec<RowID>,
+    /// `NewMessage` is sent as `MessagePreview`
+    pub lite: bool,
+    /// Resume after this event
+    pub last_event_id: Option<String>,
+}
@@ -0,0 +1,10 @@
+use chat_core::notify::EventEnvelope;
+
+/// Print the JSON schema of the events streamed by notify_server
+fn main() -> anyhow::Result<()> {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&EventEnvelope::schema())?
+    );
+    Ok(())
+}
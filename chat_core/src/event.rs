@@ -0,0 +1,31 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{Chat, Message, RowID};
+
+/// notify_server is woken up through this channel when events are appended,
+/// the payload is the id of the event
+pub const EVENTS_CHANNEL: &str = "chat_events";
+
+/// Domain events chat_server appends to the `events` table in the transaction of the change,
+/// notify_server consumes them in id order. `actor` is the user who made the change.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatEvent {
+    ChatCreated {
+        actor: RowID,
+        chat: Chat,
+    },
+    ChatUpdated {
+        actor: RowID,
+        old: Chat,
+        new: Chat,
+    },
+    ChatDeleted {
+        actor: RowID,
+        chat: Chat,
+    },
+    MessageCreated {
+        message: Message,
+        members: Vec<RowID>,
+    },
+}
@@ -1,7 +1,10 @@
+pub mod event;
 pub mod middlewares;
+pub mod notify;
 pub mod utils;
 
 use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
@@ -15,6 +18,7 @@ pub struct User {
     #[serde(skip)]
     pub password_hash: String,
     pub ws_id: RowID,
+    pub is_bot: bool,
     pub created_at: DateTime<Utc>,
 }
 
@@ -26,7 +30,7 @@ pub struct Workspace {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, JsonSchema)]
 pub struct Chat {
     pub id: RowID,
     pub ws_id: RowID,
@@ -38,7 +42,7 @@ pub struct Chat {
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, sqlx::Type)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, sqlx::Type)]
 #[sqlx(type_name = "chat_type", rename_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
 pub enum ChatType {
@@ -48,7 +52,7 @@ pub enum ChatType {
     PublicChannel,
 }
 
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, JsonSchema)]
 pub struct Message {
     pub id: RowID,
     pub chat_id: RowID,
@@ -57,3 +61,41 @@ pub struct Message {
     pub files: Vec<String>,
     pub created_at: DateTime<Utc>,
 }
+
+impl Message {
+    /// Users mentioned as `<@id>` in the content
+    pub fn mentions(&self) -> Vec<RowID> {
+        let mut ret = vec![];
+        let mut rest = self.content.as_str();
+        while let Some(start) = rest.find("<@") {
+            rest = &rest[start + 2..];
+            let Some(end) = rest.find('>') else {
+                break;
+            };
+            if let Ok(id) = rest[..end].parse() {
+                if !ret.contains(&id) {
+                    ret.push(id);
+                }
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_mentions() {
+        let message = Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 1,
+            content: "hi <@2> and <@3>, <@2> <@x> <@<@4> <@5".to_string(),
+            files: vec![],
+            created_at: Utc::now(),
+        };
+        assert_eq!(message.mentions(), vec![2, 3, 4]);
+    }
+}
@@ -43,7 +43,7 @@ where
         return (StatusCode::UNAUTHORIZED, "missing token").into_response();
     }
 
-    let user = match state.verify(token.as_ref()) {
+    let user = match state.verify(token.as_ref()).await {
         Ok(user) => user,
         Err(_) => {
             return (StatusCode::FORBIDDEN, "invalid token").into_response();
@@ -76,10 +76,11 @@ mod test {
         dk: JwtDecodingKey,
     }
 
+    #[async_trait::async_trait]
     impl VerifyToken for AppState {
         type Error = anyhow::Error;
 
-        fn verify(&self, token: &str) -> Result<crate::utils::UserCliams, Self::Error> {
+        async fn verify(&self, token: &str) -> Result<crate::utils::UserCliams, Self::Error> {
             self.inner.dk.verify(token)
         }
     }
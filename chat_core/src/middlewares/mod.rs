@@ -2,6 +2,7 @@ mod auth;
 mod request_id;
 mod server_time;
 
+use async_trait::async_trait;
 use axum::Router;
 use http::HeaderName;
 use request_id::RequestIdMaker;
@@ -47,7 +48,9 @@ pub fn set_global_layer(app: Router) -> Router {
     app.layer(service)
 }
 
+/// Resolve a jwt or an API key to the user it authenticates
+#[async_trait]
 pub trait VerifyToken {
     type Error: std::fmt::Debug;
-    fn verify(&self, token: &str) -> Result<UserCliams, Self::Error>;
+    async fn verify(&self, token: &str) -> Result<UserCliams, Self::Error>;
 }
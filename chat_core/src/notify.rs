@@ -0,0 +1,233 @@
+use chrono::{DateTime, Utc};
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{Chat, Message, RowID};
+
+/// Version of the event wire format, bumped on incompatible changes
+pub const EVENT_VERSION: u32 = 1;
+
+/// An event on the streams of notify_server, sent as
+/// `{"type": "NewMessage", "version": 1, "id": "...", "ts": "...", "data": {...}}`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct EventEnvelope {
+    #[serde(flatten)]
+    pub event: NotifyEvent,
+    pub version: u32,
+    /// Resume the stream after this event with `Last-Event-ID`, missing for events that can't
+    /// be replayed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub ts: DateTime<Utc>,
+    /// The event updates the state without notifying the user
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub silent: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "data")]
+pub enum NotifyEvent {
+    NewChat(Chat),
+    /// Sent to every member, `members` are the added ones
+    MemberAdded(ChatChange),
+    /// Sent to the removed users, `members` are the removed ones
+    RemovedFromChat(ChatChange),
+    /// The name or type of the chat changed
+    ChatUpdated(ChatChange),
+    NewMessage(Message),
+    /// `NewMessage` on a stream in lite mode
+    MessagePreview(MessagePreview),
+    ReadMarker(ReadMarker),
+    Typing(Typing),
+    PresenceChanged(PresenceChanged),
+    /// Events were lost and the client has to reload its state
+    Resync(Resync),
+    /// The stream is closed after this event
+    Disconnect(Disconnect),
+}
+
+/// A change of a chat made by `actor`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChatChange {
+    pub actor: RowID,
+    pub chat: Chat,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<RowID>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MessagePreview {
+    pub id: RowID,
+    pub chat_id: RowID,
+    pub sender_id: RowID,
+    /// The beginning of the content
+    pub preview: String,
+    pub file_count: usize,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The user has read the chat up to the message, synced between its connections
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ReadMarker {
+    pub chat_id: RowID,
+    pub message_id: RowID,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Typing {
+    pub chat_id: RowID,
+    pub user_id: RowID,
+    pub typing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Offline,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PresenceChanged {
+    pub user_id: RowID,
+    pub status: PresenceStatus,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Resync {
+    /// The id to resume from after reloading, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Disconnect {
+    pub reason: String,
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("unsupported event version {0}, expect at most {EVENT_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("invalid event: {0}")]
+    Invalid(#[from] serde_json::Error),
+}
+
+impl EventEnvelope {
+    pub fn new(event: NotifyEvent, id: Option<String>, ts: DateTime<Utc>) -> Self {
+        Self {
+            event,
+            version: EVENT_VERSION,
+            id,
+            ts,
+            silent: false,
+        }
+    }
+
+    /// Decode the data of an SSE event or a websocket frame. Events of newer versions are
+    /// rejected before their content is looked at.
+    pub fn decode(data: &str) -> Result<Self, DecodeError> {
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+
+        let Version { version } = serde_json::from_str(data)?;
+        if version > EVENT_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        Ok(serde_json::from_str(data)?)
+    }
+
+    /// JSON schema of the wire format
+    pub fn schema() -> RootSchema {
+        schema_for!(EventEnvelope)
+    }
+}
+
+impl NotifyEvent {
+    /// The `type` of the event, also the SSE event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            NotifyEvent::NewChat(_) => "NewChat",
+            NotifyEvent::MemberAdded(_) => "MemberAdded",
+            NotifyEvent::RemovedFromChat(_) => "RemovedFromChat",
+            NotifyEvent::ChatUpdated(_) => "ChatUpdated",
+            NotifyEvent::NewMessage(_) => "NewMessage",
+            NotifyEvent::MessagePreview(_) => "MessagePreview",
+            NotifyEvent::ReadMarker(_) => "ReadMarker",
+            NotifyEvent::Typing(_) => "Typing",
+            NotifyEvent::PresenceChanged(_) => "PresenceChanged",
+            NotifyEvent::Resync(_) => "Resync",
+            NotifyEvent::Disconnect(_) => "Disconnect",
+        }
+    }
+
+    /// The chat the event belongs to, if any
+    pub fn chat_id(&self) -> Option<RowID> {
+        match self {
+            NotifyEvent::NewChat(chat) => Some(chat.id),
+            NotifyEvent::MemberAdded(change)
+            | NotifyEvent::RemovedFromChat(change)
+            | NotifyEvent::ChatUpdated(change) => Some(change.chat.id),
+            NotifyEvent::NewMessage(message) => Some(message.chat_id),
+            NotifyEvent::MessagePreview(preview) => Some(preview.chat_id),
+            NotifyEvent::ReadMarker(marker) => Some(marker.chat_id),
+            NotifyEvent::Typing(typing) => Some(typing.chat_id),
+            NotifyEvent::PresenceChanged(_)
+            | NotifyEvent::Resync(_)
+            | NotifyEvent::Disconnect(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn t_event_wire_format() {
+        let event = NotifyEvent::ReadMarker(ReadMarker {
+            chat_id: 1,
+            message_id: 2,
+        });
+        let ts = "2024-01-01T00:00:00Z".parse().unwrap();
+        let envelope = EventEnvelope::new(event, Some("a:1".to_string()), ts);
+        let data = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(
+            data,
+            json!({
+                "type": "ReadMarker",
+                "version": 1,
+                "id": "a:1",
+                "ts": "2024-01-01T00:00:00Z",
+                "data": { "chat_id": 1, "message_id": 2 },
+            })
+        );
+
+        let decoded = EventEnvelope::decode(&data.to_string()).unwrap();
+        assert!(matches!(decoded.event, NotifyEvent::ReadMarker(m) if m.message_id == 2));
+        assert!(!decoded.silent);
+
+        let newer = json!({ "type": "Unknown", "version": 2, "ts": ts, "data": {} });
+        let ret = EventEnvelope::decode(&newer.to_string());
+        assert!(matches!(ret, Err(DecodeError::UnsupportedVersion(2))));
+        let unknown = json!({ "type": "Unknown", "version": 1, "ts": ts, "data": {} });
+        let ret = EventEnvelope::decode(&unknown.to_string());
+        assert!(matches!(ret, Err(DecodeError::Invalid(_))));
+    }
+
+    #[test]
+    fn t_event_schema() {
+        let schema = serde_json::to_string_pretty(&EventEnvelope::schema()).unwrap();
+        assert_eq!(
+            schema.trim(),
+            include_str!("../schema/event.json").trim(),
+            "the event schema changed, update it with `cargo run -p chat-core --example event_schema > chat_core/schema/event.json`"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+
+use super::UserCliams;
+use crate::RowID;
+
+/// API keys start with it, so they can be told apart from jwts
+pub const API_KEY_PREFIX: &str = "chat_";
+/// Random bytes of a key
+const API_KEY_BYTES: usize = 24;
+
+/// What an API key can be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "api_scope", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ApiScope {
+    /// GET requests to chat_server
+    Read,
+    /// Other requests to chat_server, e.g. sending messages and uploading files
+    Write,
+    /// Streams and requests to notify_server
+    Events,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::Read => "read",
+            ApiScope::Write => "write",
+            ApiScope::Events => "events",
+        }
+    }
+}
+
+/// A new key and its hash. Only the hash is stored, the key is shown once.
+pub fn generate_api_key() -> (String, String) {
+    let mut bytes = [0u8; API_KEY_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let key = format!("{}{}", API_KEY_PREFIX, hex::encode(bytes));
+    let hash = hash_api_key(&key);
+    (key, hash)
+}
+
+/// Keys are random, a plain sha256 is enough and cheap to check on every request
+pub fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+pub fn is_api_key(token: &str) -> bool {
+    token.starts_with(API_KEY_PREFIX)
+}
+
+/// The bot a live key belongs to, with the scopes of the key
+pub async fn verify_api_key(pool: &PgPool, key: &str) -> anyhow::Result<UserCliams> {
+    let row: Option<(RowID, RowID, Vec<ApiScope>)> = sqlx::query_as(
+        r#"
+        UPDATE api_keys SET last_used_at = NOW()
+        WHERE key_hash = $1 AND revoked_at IS NULL AND (expires_at IS NULL OR expires_at > NOW())
+        RETURNING user_id, ws_id, scopes
+        "#,
+    )
+    .bind(hash_api_key(key))
+    .fetch_optional(pool)
+    .await?;
+    let (uid, ws_id, scopes) = row.ok_or_else(|| anyhow::anyhow!("invalid api key"))?;
+    Ok(UserCliams {
+        uid,
+        ws_id,
+        scopes: Some(scopes),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn t_generate_api_key() {
+        let (key, hash) = generate_api_key();
+        assert!(is_api_key(&key));
+        assert_eq!(key.len(), API_KEY_PREFIX.len() + API_KEY_BYTES * 2);
+        assert_eq!(hash, hash_api_key(&key));
+        assert_eq!(hash.len(), 64);
+        assert_ne!(generate_api_key().0, key);
+    }
+}
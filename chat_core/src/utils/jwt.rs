@@ -2,7 +2,9 @@ use jsonwebtoken::{
     get_current_timestamp, Algorithm, DecodingKey, EncodingKey, Header, Validation,
 };
 use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
 
+use super::ApiScope;
 use crate::{RowID, User};
 
 pub struct JwtEncodingKey {
@@ -19,6 +21,8 @@ pub struct JwtDecodingKey {
 pub struct UserCliams {
     pub uid: RowID,
     pub ws_id: RowID,
+    /// Scopes of the API key of a bot, `None` for users signed in with a jwt
+    pub scopes: Option<Vec<ApiScope>>,
 }
 
 impl JwtEncodingKey {
@@ -77,11 +81,41 @@ impl Cliams {
     }
 }
 
+impl UserCliams {
+    /// Requests authenticated with an API key, only bots have them
+    pub fn is_bot(&self) -> bool {
+        self.scopes.is_some()
+    }
+
+    /// Owners manage the workspace, e.g. its bots and webhooks. Bots never do.
+    pub async fn is_workspace_owner(&self, pool: &PgPool) -> anyhow::Result<bool> {
+        if self.is_bot() {
+            return Ok(false);
+        }
+        let is_owner = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM workspaces WHERE id = $1 AND owner_id = $2)",
+        )
+        .bind(self.ws_id)
+        .bind(self.uid)
+        .fetch_one(pool)
+        .await?;
+        Ok(is_owner)
+    }
+
+    /// Users can do anything, bots what the scopes of their key allow
+    pub fn allows(&self, scope: ApiScope) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.contains(&scope))
+    }
+}
+
 impl From<Cliams> for UserCliams {
     fn from(value: Cliams) -> Self {
         UserCliams {
             uid: value.uid,
             ws_id: value.ws_id,
+            scopes: None,
         }
     }
 }
@@ -91,6 +125,7 @@ impl From<User> for UserCliams {
         UserCliams {
             uid: user.id,
             ws_id: user.ws_id,
+            scopes: None,
         }
     }
 }
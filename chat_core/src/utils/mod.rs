@@ -1,3 +1,7 @@
+mod api_key;
 mod jwt;
 
+pub use api_key::{
+    generate_api_key, hash_api_key, is_api_key, verify_api_key, ApiScope, API_KEY_PREFIX,
+};
 pub use jwt::{JwtDecodingKey, JwtEncodingKey, UserCliams};
@@ -8,6 +8,8 @@ pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
     pub base_dir: String,
+    #[serde(default)]
+    pub storage: StorageConfig,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -23,6 +25,26 @@ pub struct AuthConfig {
     pub sk: String,
 }
 
+/// Where uploaded files are stored, `base_dir` is still used for temporary files
+#[derive(Serialize, Deserialize, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StorageConfig {
+    #[default]
+    Local,
+    S3(S3Config),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Seconds a presigned download url is valid, downloads are proxied if not set
+    pub presign_expires: Option<u64>,
+}
+
 impl AppConfig {
     pub fn load() -> anyhow::Result<Self> {
         let ret = match (
@@ -4,10 +4,12 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use http::StatusCode;
+use http::{HeaderValue, StatusCode};
 use serde::Serialize;
 use serde_json::json;
 
+use crate::handlers::UPLOAD_OFFSET_HEADER;
+
 pub type AppResult<T> = Result<T, AppError>;
 
 #[derive(Debug, Clone, Serialize)]
@@ -22,6 +24,11 @@ pub enum AppError {
     InvalidInput(ImmutStr),
     AlreadyExist(ImmutStr),
     Forbidden(ImmutStr),
+    /// The upload isn't at the offset of the request, `offset` is where it is
+    Conflict {
+        msg: ImmutStr,
+        offset: i64,
+    },
     Internal(anyhow::Error),
 }
 
@@ -41,6 +48,13 @@ impl IntoResponse for AppError {
             Self::InvalidInput(err) => (StatusCode::UNPROCESSABLE_ENTITY, err),
             Self::AlreadyExist(err) => (StatusCode::CONFLICT, err),
             Self::Forbidden(err) => (StatusCode::FORBIDDEN, err),
+            Self::Conflict { msg, offset } => {
+                let mut resp =
+                    (StatusCode::CONFLICT, Json(json!({ "error": msg }))).into_response();
+                resp.headers_mut()
+                    .insert(UPLOAD_OFFSET_HEADER, HeaderValue::from(offset));
+                return resp;
+            }
             Self::Internal(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string().into()),
         };
 
@@ -69,6 +83,14 @@ impl AppError {
         AppError::Forbidden(msg.into())
     }
 
+    #[inline]
+    pub fn conflict(msg: impl Into<ImmutStr>, offset: i64) -> Self {
+        AppError::Conflict {
+            msg: msg.into(),
+            offset,
+        }
+    }
+
     #[inline]
     pub fn any(e: impl Into<anyhow::Error>) -> Self {
         AppError::Internal(e.into())
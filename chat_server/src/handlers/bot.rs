@@ -0,0 +1,163 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chat_core::{utils::UserCliams, RowID, User};
+
+use crate::{
+    error::AppResult,
+    models::bot::{self, ApiKey, CreateApiKey, CreateBot, NewApiKey},
+    AppState,
+};
+
+pub async fn list_bots_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+) -> AppResult<Json<Vec<User>>> {
+    bot::ensure_admin(&state.db, &user).await?;
+    let bots = bot::list(&state.db, user.ws_id).await?;
+    Ok(Json(bots))
+}
+
+pub async fn create_bot_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Json(input): Json<CreateBot>,
+) -> AppResult<(StatusCode, Json<User>)> {
+    bot::ensure_admin(&state.db, &user).await?;
+    let bot = bot::create(&state.db, user.ws_id, input).await?;
+    Ok((StatusCode::CREATED, Json(bot)))
+}
+
+pub async fn list_api_keys_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(id): Path<RowID>,
+) -> AppResult<Json<Vec<ApiKey>>> {
+    bot::ensure_admin(&state.db, &user).await?;
+    let keys = bot::list_keys(&state.db, user.ws_id, id).await?;
+    Ok(Json(keys))
+}
+
+pub async fn create_api_key_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(id): Path<RowID>,
+    Json(input): Json<CreateApiKey>,
+) -> AppResult<(StatusCode, Json<NewApiKey>)> {
+    bot::ensure_admin(&state.db, &user).await?;
+    let key = bot::create_key(&state.db, user.ws_id, id, user.uid, input).await?;
+    Ok((StatusCode::CREATED, Json(key)))
+}
+
+pub async fn revoke_api_key_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path((id, key_id)): Path<(RowID, RowID)>,
+) -> AppResult<StatusCode> {
+    bot::ensure_admin(&state.db, &user).await?;
+    bot::revoke_key(&state.db, user.ws_id, id, key_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        get_router,
+        handlers::tests::{call, json_body},
+        models::chat::{self, UpdateMembers},
+    };
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_bot_routes(pool: PgPool) {
+        let state = AppState::new_for_test(pool);
+        let router = get_router(state.clone()).await.unwrap();
+        let sign = |uid| {
+            state
+                .ek
+                .sign(&UserCliams {
+                    uid,
+                    ws_id: 1,
+                    ..Default::default()
+                })
+                .unwrap()
+        };
+        let owner = sign(1);
+
+        // only the owner manages bots
+        let resp = call(&router, "GET", "/api/bots", &sign(2), Value::Null).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = call(
+            &router,
+            "POST",
+            "/api/bots",
+            &owner,
+            json!({ "fullname": "ci" }),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let bot_id = json_body(resp).await["id"].as_i64().unwrap();
+        chat::add_members(
+            &state.db,
+            1,
+            1,
+            UpdateMembers {
+                members: vec![bot_id],
+            },
+        )
+        .await
+        .unwrap();
+
+        let uri = format!("/api/bots/{bot_id}/keys");
+        let input = json!({ "name": "read", "scopes": ["read"] });
+        let resp = call(&router, "POST", &uri, &owner, input).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let read = json_body(resp).await;
+        let read_key = read["key"].as_str().unwrap();
+        let input = json!({ "name": "write", "scopes": ["read", "write"] });
+        let resp = call(&router, "POST", &uri, &owner, input).await;
+        let write_key = json_body(resp).await["key"].as_str().unwrap().to_string();
+
+        // a read-only key can read the chat but not send messages
+        let resp = call(&router, "GET", "/api/chat/1", read_key, Value::Null).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let input = json!({ "content": "hello", "files": [] });
+        let resp = call(
+            &router,
+            "PUT",
+            "/api/chat/1/message",
+            read_key,
+            input.clone(),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = call(&router, "PUT", "/api/chat/1/message", &write_key, input).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // bots can't create chats or use chats they are not in
+        let input = json!({ "members": [1, bot_id], "public": false });
+        let resp = call(&router, "POST", "/api/chat", &write_key, input).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = call(&router, "GET", "/api/chat/2", &write_key, Value::Null).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // bots can't manage bots, even with a write key
+        let resp = call(&router, "GET", "/api/bots", &write_key, Value::Null).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // a revoked key is rejected right away
+        let uri = format!("/api/bots/{bot_id}/keys/{}", read["id"]);
+        let resp = call(&router, "DELETE", &uri, &owner, Value::Null).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let resp = call(&router, "GET", "/api/chat/1", read_key, Value::Null).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+}
@@ -1,12 +1,16 @@
 use axum::{
     extract::{Path, State},
+    http::StatusCode,
     Extension, Json,
 };
 use chat_core::{utils::UserCliams, Chat, RowID};
 
 use crate::{
-    error::AppResult,
-    models::{self, chat::CreateChat},
+    error::{AppError, AppResult},
+    models::{
+        self,
+        chat::{CreateChat, UpdateChat, UpdateMembers},
+    },
     AppState,
 };
 
@@ -15,7 +19,8 @@ pub async fn create_chat_handler(
     State(state): State<AppState>,
     Json(input): Json<CreateChat>,
 ) -> AppResult<Json<Chat>> {
-    let chat = models::chat::create(&state.db, user.ws_id, input).await?;
+    ensure_not_bot(&user)?;
+    let chat = models::chat::create(&state.db, user.ws_id, user.uid, input).await?;
     Ok(Json(chat))
 }
 
@@ -34,3 +39,117 @@ pub async fn get_chat_handler(
     let chat = models::chat::get(&state.db, id).await?;
     Ok(Json(chat))
 }
+
+pub async fn update_chat_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<AppState>,
+    Path(id): Path<RowID>,
+    Json(input): Json<UpdateChat>,
+) -> AppResult<Json<Chat>> {
+    ensure_not_bot(&user)?;
+    let chat = models::chat::update(&state.db, id, user.uid, input).await?;
+    Ok(Json(chat))
+}
+
+pub async fn delete_chat_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<AppState>,
+    Path(id): Path<RowID>,
+) -> AppResult<StatusCode> {
+    ensure_not_bot(&user)?;
+    models::chat::delete(&state.db, id, user.uid).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn add_members_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<AppState>,
+    Path(id): Path<RowID>,
+    Json(input): Json<UpdateMembers>,
+) -> AppResult<Json<Chat>> {
+    ensure_not_bot(&user)?;
+    let chat = models::chat::add_members(&state.db, id, user.uid, input).await?;
+    Ok(Json(chat))
+}
+
+pub async fn remove_members_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<AppState>,
+    Path(id): Path<RowID>,
+    Json(input): Json<UpdateMembers>,
+) -> AppResult<Json<Chat>> {
+    ensure_not_bot(&user)?;
+    let chat = models::chat::remove_members(&state.db, id, user.uid, input).await?;
+    Ok(Json(chat))
+}
+
+/// Bots post into the chats they are added to, they can't create or change chats
+fn ensure_not_bot(user: &UserCliams) -> AppResult<()> {
+    if user.is_bot() {
+        return Err(AppError::forbidden(
+            "bots can only use the chats they are added to",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        get_router,
+        handlers::tests::{call, json_body},
+    };
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_chat_manage_routes(pool: PgPool) {
+        let state = AppState::new_for_test(pool);
+        let router = get_router(state.clone()).await.unwrap();
+        let sign = |uid| {
+            state
+                .ek
+                .sign(&UserCliams {
+                    uid,
+                    ws_id: 1,
+                    ..Default::default()
+                })
+                .unwrap()
+        };
+        let (owner, member) = (sign(1), sign(2));
+        let uid: RowID = sqlx::query_scalar(
+            "INSERT INTO users (fullname, email, password_hash, ws_id) VALUES ('u', 'u@a.com', '', 1) RETURNING id",
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+
+        // user 2 is a plain member of chat 1, which user 1 owns through the workspace
+        let forbidden = [
+            ("PATCH", "/api/chat/1", json!({ "name": "mine" })),
+            ("DELETE", "/api/chat/1", Value::Null),
+            ("POST", "/api/chat/1/members", json!({ "members": [uid] })),
+            ("DELETE", "/api/chat/1/members", json!({ "members": [1] })),
+        ];
+        for (method, uri, body) in forbidden {
+            let resp = call(&router, method, uri, &member, body).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN, "{} {}", method, uri);
+        }
+
+        // the owner can, and a plain member can leave
+        let input = json!({ "members": [uid] });
+        let resp = call(&router, "POST", "/api/chat/1/members", &owner, input).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let input = json!({ "members": [2] });
+        let resp = call(&router, "DELETE", "/api/chat/1/members", &member, input).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(json_body(resp).await["members"], json!([1, uid]));
+        let resp = call(&router, "DELETE", "/api/chat/1", &owner, Value::Null).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    }
+}
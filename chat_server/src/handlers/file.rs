@@ -0,0 +1,479 @@
+use std::{ops::Bound, path::Path as StdPath};
+
+use axum::{
+    body::Body,
+    extract::{Multipart, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Extension, Json,
+};
+use axum_extra::TypedHeader;
+use chat_core::utils::UserCliams;
+use headers::Range;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::{
+    error::{AppError, AppResult},
+    models::file::{self, ChatFile, DownloadFile, FileMeta, FileUpload},
+    AppState,
+};
+
+pub async fn upload_file_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    mut multipart: Multipart,
+) -> AppResult<Json<Vec<String>>> {
+    let base = StdPath::new(&state.config.base_dir);
+    let mut files = Vec::with_capacity(5);
+    while let Some(mut field) = multipart.next_field().await? {
+        let Some(filename) = field.file_name() else {
+            continue;
+        };
+        if filename.is_empty() || filename.contains('/') {
+            continue;
+        }
+        let filename = filename.to_owned();
+
+        let mut upload = FileUpload::create(base).await?;
+        // the temporary file is removed when `upload` is dropped on an error
+        while let Some(chunk) = field.chunk().await? {
+            upload.write(&chunk).await?;
+        }
+        let size = upload.size();
+        let (chat_file, image) = upload
+            .persist(state.storage.as_ref(), user.ws_id, &filename)
+            .await?;
+        file::create_meta(&state.db, &chat_file, user.uid, &filename, size, image).await?;
+        files.push(chat_file.url());
+    }
+
+    Ok(Json(files))
+}
+
+pub async fn download_file_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(file_url): Path<String>,
+    Query(input): Query<DownloadFile>,
+    range: Option<TypedHeader<Range>>,
+    headers: HeaderMap,
+) -> AppResult<Response> {
+    let file = ChatFile::from_url(&file_url)?;
+    if file.ws_id != user.ws_id || !file::can_access(&state.db, &file.url(), user.uid).await? {
+        return Err(AppError::not_found("file not found"));
+    }
+    let meta = file::find_meta(&state.db, &file.url(), user.uid).await?;
+
+    // content is addressed by its hash, so the hash is a strong etag
+    let (key, etag) = match input.size {
+        Some(size) => (
+            file.thumbnail_key(size),
+            format!("\"{}-{}\"", file.hash, size.as_str()),
+        ),
+        None => (file.key(), format!("\"{}\"", file.hash)),
+    };
+    let Some(size) = state.storage.size(&key).await? else {
+        return Err(AppError::not_found("file not found"));
+    };
+    let (mut content_type, filename) = match meta {
+        Some(meta) => (meta.mime, meta.filename),
+        None => (file.content_type().to_string(), file.filename()),
+    };
+    if input.size.is_some() {
+        content_type = file.thumbnail_content_type().to_string();
+    }
+    let disposition = content_disposition(&filename);
+    if let Some(url) = state.storage.presign(&key, &disposition) {
+        return Ok(Redirect::temporary(&url).into_response());
+    }
+
+    let mut resp_headers = HeaderMap::new();
+    resp_headers.insert(header::ETAG, HeaderValue::from_str(&etag)?);
+    resp_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    resp_headers.insert(header::CONTENT_TYPE, HeaderValue::from_str(&content_type)?);
+    resp_headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&disposition)?,
+    );
+
+    let not_modified = headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim().trim_start_matches("W/"))
+        .any(|v| v == "*" || v == etag);
+    if not_modified {
+        return Ok((StatusCode::NOT_MODIFIED, resp_headers).into_response());
+    }
+
+    let Some(TypedHeader(range)) = range else {
+        resp_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(size));
+        let body = Body::from_stream(state.storage.read(&key, 0, size).await?);
+        return Ok((StatusCode::OK, resp_headers, body).into_response());
+    };
+
+    let Some((start, end)) = satisfiable_range(&range, size) else {
+        resp_headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes */{size}"))?,
+        );
+        return Ok((StatusCode::RANGE_NOT_SATISFIABLE, resp_headers).into_response());
+    };
+
+    let len = end - start + 1;
+    resp_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    resp_headers.insert(
+        header::CONTENT_RANGE,
+        HeaderValue::from_str(&format!("bytes {start}-{end}/{size}"))?,
+    );
+    let body = Body::from_stream(state.storage.read(&key, start, len).await?);
+    Ok((StatusCode::PARTIAL_CONTENT, resp_headers, body).into_response())
+}
+
+/// Metadata of the file, including image dimensions
+pub async fn file_meta_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(file_url): Path<String>,
+) -> AppResult<Json<FileMeta>> {
+    let file = ChatFile::from_url(&file_url)?;
+    if file.ws_id != user.ws_id || !file::can_access(&state.db, &file.url(), user.uid).await? {
+        return Err(AppError::not_found("file not found"));
+    }
+    let meta = file::find_meta(&state.db, &file.url(), user.uid).await?;
+    meta.map(Json)
+        .ok_or_else(|| AppError::not_found("file not found"))
+}
+
+/// Inline disposition keeping the original name, non-ASCII names are sent as RFC 5987 `filename*`
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded = utf8_percent_encode(filename, NON_ALPHANUMERIC);
+    format!("inline; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+
+/// Resolve the first range of the header to an inclusive `(start, end)` within `size`.
+/// Multipart ranges are not supported, only the first one is served.
+fn satisfiable_range(range: &Range, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.satisfiable_ranges(size).next()?;
+    let start = match start {
+        Bound::Included(n) => n,
+        Bound::Excluded(n) => n + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match end {
+        Bound::Included(n) => n.min(size.checked_sub(1)?),
+        Bound::Excluded(n) => n.min(size).checked_sub(1)?,
+        Bound::Unbounded => size.checked_sub(1)?,
+    };
+    (start <= end).then_some((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt as _;
+    use sqlx::PgPool;
+
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        models::{
+            message::{self, CreateMessage},
+            thumbnail::ThumbnailSize,
+        },
+        storage::{ByteStream, Storage},
+    };
+
+    #[sqlx::test(migrator = "crate::tests::MIGRATOR")]
+    async fn t_download_file(pool: PgPool) {
+        let state = AppState::new_for_test(pool);
+        let base = StdPath::new(&state.config.base_dir);
+        let mut upload = FileUpload::create(base).await.unwrap();
+        upload.write(b"hello world").await.unwrap();
+        let (file, _) = upload
+            .persist(state.storage.as_ref(), 1, "a.txt")
+            .await
+            .unwrap();
+        file::create_meta(&state.db, &file, 1, "你好 a.txt", 11, None)
+            .await
+            .unwrap();
+        let url = file.url().trim_start_matches("/files/").to_string();
+        let user = UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        };
+
+        // whole file
+        let resp = download_file_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            Path(url.clone()),
+            Query(Default::default()),
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "11");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(
+            resp.headers()[header::CONTENT_DISPOSITION],
+            "inline; filename=\"__ a.txt\"; filename*=UTF-8''%E4%BD%A0%E5%A5%BD%20a%2Etxt"
+        );
+        let etag = resp.headers()[header::ETAG].clone();
+
+        // range request
+        let resp = download_file_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            Path(url.clone()),
+            Query(Default::default()),
+            Some(TypedHeader(Range::bytes(6..).unwrap())),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()[header::CONTENT_RANGE], "bytes 6-10/11");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"world");
+
+        // cached by etag
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, etag);
+        let resp = download_file_handler(
+            State(state),
+            Extension(user),
+            Path(url),
+            Query(Default::default()),
+            None,
+            headers,
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[sqlx::test(migrator = "crate::tests::MIGRATOR")]
+    async fn t_download_thumbnail(pool: PgPool) {
+        let state = AppState::new_for_test(pool);
+        let base = StdPath::new(&state.config.base_dir);
+        let mut png = std::io::Cursor::new(vec![]);
+        image::RgbImage::new(800, 400)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let mut upload = FileUpload::create(base).await.unwrap();
+        upload.write(png.get_ref()).await.unwrap();
+        let size = upload.size();
+        let (file, image) = upload
+            .persist(state.storage.as_ref(), 1, "a.png")
+            .await
+            .unwrap();
+        file::create_meta(&state.db, &file, 1, "a.png", size, image)
+            .await
+            .unwrap();
+        let url = file.url().trim_start_matches("/files/").to_string();
+        let user = UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        };
+
+        let resp = download_file_handler(
+            State(state.clone()),
+            Extension(user.clone()),
+            Path(url.clone()),
+            Query(DownloadFile {
+                size: Some(ThumbnailSize::Small),
+            }),
+            None,
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "image/png");
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let thumb = image::load_from_memory(&body).unwrap();
+        assert_eq!((thumb.width(), thumb.height()), (128, 64));
+
+        let meta = file_meta_handler(State(state), Extension(user), Path(url))
+            .await
+            .unwrap();
+        assert_eq!((meta.width, meta.height), (Some(800), Some(400)));
+    }
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_download_file_access(pool: PgPool) {
+        let state = AppState::new_for_test(pool);
+        let base = StdPath::new(&state.config.base_dir);
+        let mut upload = FileUpload::create(base).await.unwrap();
+        upload.write(b"private file").await.unwrap();
+        let (file, _) = upload
+            .persist(state.storage.as_ref(), 1, "a.txt")
+            .await
+            .unwrap();
+        file::create_meta(&state.db, &file, 1, "a.txt", 12, None)
+            .await
+            .unwrap();
+        let url = file.url().trim_start_matches("/files/").to_string();
+        let download = |uid| {
+            download_file_handler(
+                State(state.clone()),
+                Extension(UserCliams {
+                    uid,
+                    ws_id: 1,
+                    ..Default::default()
+                }),
+                Path(url.clone()),
+                Query(Default::default()),
+                None,
+                HeaderMap::new(),
+            )
+        };
+
+        // user 2 is in the same workspace but the file is not shared with them
+        let ret = download(2).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // shared in chat 1 with user 2
+        let input = CreateMessage {
+            chat_id: 1,
+            content: "file".to_string(),
+            files: vec![file.url()],
+        };
+        message::create(&state.db, input, 1, state.storage.as_ref())
+            .await
+            .unwrap();
+        let resp = download(2).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::CONTENT_DISPOSITION],
+            "inline; filename=\"a.txt\"; filename*=UTF-8''a%2Etxt"
+        );
+
+        // the same content uploaded by user 2 under another name, each sees their own name
+        file::create_meta(&state.db, &file, 2, "b.txt", 12, None)
+            .await
+            .unwrap();
+        let resp = download(2).await.unwrap();
+        assert_eq!(
+            resp.headers()[header::CONTENT_DISPOSITION],
+            "inline; filename=\"b.txt\"; filename*=UTF-8''b%2Etxt"
+        );
+        let resp = download(1).await.unwrap();
+        assert_eq!(
+            resp.headers()[header::CONTENT_DISPOSITION],
+            "inline; filename=\"a.txt\"; filename*=UTF-8''a%2Etxt"
+        );
+    }
+
+    /// Local files behind a fake presigning front
+    struct Presigned(Box<dyn Storage>);
+
+    #[async_trait]
+    impl Storage for Presigned {
+        async fn size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+            self.0.size(key).await
+        }
+
+        async fn put_file(&self, key: &str, src: &StdPath) -> anyhow::Result<()> {
+            self.0.put_file(key, src).await
+        }
+
+        async fn read(&self, key: &str, offset: u64, len: u64) -> anyhow::Result<ByteStream> {
+            self.0.read(key, offset, len).await
+        }
+
+        fn presign(&self, key: &str, content_disposition: &str) -> Option<String> {
+            Some(format!(
+                "https://cdn.test/{key}?disposition={content_disposition}"
+            ))
+        }
+    }
+
+    #[sqlx::test(migrator = "crate::tests::MIGRATOR")]
+    async fn t_download_presigned(pool: PgPool) {
+        let state = AppState::new_for_test(pool);
+        let Ok(mut inner) = Arc::try_unwrap(state.inner) else {
+            panic!("state is shared");
+        };
+        inner.storage = Box::new(Presigned(inner.storage));
+        let state = AppState {
+            inner: Arc::new(inner),
+        };
+        let base = StdPath::new(&state.config.base_dir);
+        let mut upload = FileUpload::create(base).await.unwrap();
+        upload.write(b"hello presign").await.unwrap();
+        let (file, _) = upload
+            .persist(state.storage.as_ref(), 1, "a.txt")
+            .await
+            .unwrap();
+        file::create_meta(&state.db, &file, 1, "b.txt", 13, None)
+            .await
+            .unwrap();
+        let url = file.url().trim_start_matches("/files/").to_string();
+        let download = |size| {
+            download_file_handler(
+                State(state.clone()),
+                Extension(UserCliams {
+                    uid: 1,
+                    ws_id: 1,
+                    ..Default::default()
+                }),
+                Path(url.clone()),
+                Query(DownloadFile { size }),
+                None,
+                HeaderMap::new(),
+            )
+        };
+
+        // redirected with the name of the uploader
+        let resp = download(None).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            resp.headers()[header::LOCATION],
+            format!(
+                "https://cdn.test/{}?disposition=inline; filename=\"b.txt\"; filename*=UTF-8''b%2Etxt",
+                file.key()
+            )
+        );
+
+        // no thumbnail for a text file, nothing to redirect to
+        let ret = download(Some(ThumbnailSize::Small)).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+    }
+
+    #[test]
+    fn t_satisfiable_range() {
+        let range = |s: &'static str| -> Range {
+            let value = HeaderValue::from_static(s);
+            headers::Header::decode(&mut std::iter::once(&value)).unwrap()
+        };
+
+        assert_eq!(satisfiable_range(&range("bytes=0-9"), 100), Some((0, 9)));
+        assert_eq!(satisfiable_range(&range("bytes=90-"), 100), Some((90, 99)));
+        assert_eq!(satisfiable_range(&range("bytes=-10"), 100), Some((90, 99)));
+        assert_eq!(
+            satisfiable_range(&range("bytes=50-200"), 100),
+            Some((50, 99))
+        );
+        assert_eq!(satisfiable_range(&range("bytes=100-"), 100), None);
+        assert_eq!(satisfiable_range(&range("bytes=0-"), 0), None);
+    }
+}
@@ -1,9 +1,48 @@
 mod auth;
+mod bot;
 mod chat;
+mod file;
 mod message;
+mod preference;
+mod upload;
 mod workspace;
 
 pub use auth::*;
+pub use bot::*;
 pub use chat::*;
+pub use file::*;
 pub use message::*;
+pub use preference::*;
+pub use upload::*;
 pub use workspace::*;
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use axum::{body::Body, http::Request, response::Response, Router};
+    use http_body_util::BodyExt as _;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    /// Send a JSON request through the router
+    pub(crate) async fn call(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        token: &str,
+        body: Value,
+    ) -> Response {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", format!("Bearer {token}"))
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        router.clone().oneshot(req).await.unwrap()
+    }
+
+    pub(crate) async fn json_body(resp: Response) -> Value {
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+}
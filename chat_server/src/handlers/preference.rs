@@ -0,0 +1,56 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chat_core::{utils::UserCliams, RowID};
+
+use crate::{
+    error::AppResult,
+    models::preference::{self, ChatSettings, DndSchedule, UpdateChatSettings, UpdateDndSchedule},
+    AppState,
+};
+
+pub async fn get_chat_settings_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(id): Path<RowID>,
+) -> AppResult<Json<ChatSettings>> {
+    let settings = preference::get_chat_settings(&state.db, id, user.uid).await?;
+    Ok(Json(settings))
+}
+
+pub async fn update_chat_settings_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(id): Path<RowID>,
+    Json(input): Json<UpdateChatSettings>,
+) -> AppResult<Json<ChatSettings>> {
+    let settings = preference::update_chat_settings(&state.db, id, user.uid, input).await?;
+    Ok(Json(settings))
+}
+
+pub async fn get_dnd_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+) -> AppResult<Json<DndSchedule>> {
+    let schedule = preference::get_dnd(&state.db, user.ws_id, user.uid).await?;
+    Ok(Json(schedule))
+}
+
+pub async fn update_dnd_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Json(input): Json<UpdateDndSchedule>,
+) -> AppResult<Json<DndSchedule>> {
+    let schedule = preference::update_dnd(&state.db, user.ws_id, user.uid, input).await?;
+    Ok(Json(schedule))
+}
+
+pub async fn delete_dnd_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+) -> AppResult<StatusCode> {
+    preference::delete_dnd(&state.db, user.ws_id, user.uid).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
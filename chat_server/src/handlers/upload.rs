@@ -0,0 +1,126 @@
+use std::path::Path as StdPath;
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Extension, Json,
+};
+use chat_core::utils::UserCliams;
+use serde_json::{json, Value};
+
+use crate::{
+    error::{AppError, AppResult},
+    models::{
+        file,
+        upload::{self, CreateUpload, UploadSession},
+    },
+    AppState,
+};
+
+/// Byte offset the chunk in the request body starts at
+pub const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+
+pub async fn create_upload_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Json(input): Json<CreateUpload>,
+) -> AppResult<(StatusCode, Json<UploadSession>)> {
+    let base = StdPath::new(&state.config.base_dir);
+    let session = upload::create(&state.db, base, user.ws_id, user.uid, input).await?;
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+pub async fn get_upload_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(id): Path<String>,
+) -> AppResult<Json<UploadSession>> {
+    let session = upload::get(&state.db, &id, user.uid).await?;
+    Ok(Json(session))
+}
+
+pub async fn patch_upload_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> AppResult<Json<UploadSession>> {
+    let offset = headers
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+        .ok_or_else(|| AppError::invalid_input("missing or invalid upload-offset header"))?;
+
+    let base = StdPath::new(&state.config.base_dir);
+    let session = upload::get(&state.db, &id, user.uid).await?;
+    let session =
+        upload::append(&state.db, base, &session, offset, body.into_data_stream()).await?;
+    Ok(Json(session))
+}
+
+pub async fn finish_upload_handler(
+    State(state): State<AppState>,
+    Extension(user): Extension<UserCliams>,
+    Path(id): Path<String>,
+) -> AppResult<Json<Value>> {
+    let base = StdPath::new(&state.config.base_dir);
+    let session = upload::get(&state.db, &id, user.uid).await?;
+    let (filename, size) = (session.filename.clone(), session.size as u64);
+    let (file, image) = upload::finish(&state.db, base, state.storage.as_ref(), session).await?;
+    file::create_meta(&state.db, &file, user.uid, &filename, size, image).await?;
+    Ok(Json(json!({ "url": file.url() })))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        get_router,
+        handlers::tests::{call, json_body},
+    };
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_upload_offset_conflict(pool: PgPool) {
+        let state = AppState::new_for_test(pool);
+        let router = get_router(state.clone()).await.unwrap();
+        let token = state
+            .ek
+            .sign(&UserCliams {
+                uid: 1,
+                ws_id: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        let input = json!({ "filename": "a.txt", "size": 6 });
+        let resp = call(&router, "POST", "/api/uploads", &token, input).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let id = json_body(resp).await["id"].as_str().unwrap().to_string();
+
+        let patch = |offset: i64, body: &'static str| {
+            Request::builder()
+                .method("PATCH")
+                .uri(format!("/api/uploads/{}", id))
+                .header("Authorization", format!("Bearer {token}"))
+                .header(UPLOAD_OFFSET_HEADER, offset)
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let resp = router.clone().oneshot(patch(0, "abc")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // the client learns where to resume from
+        let resp = router.clone().oneshot(patch(0, "abc")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        assert_eq!(resp.headers()[UPLOAD_OFFSET_HEADER], "3");
+    }
+}
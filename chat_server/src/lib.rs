@@ -3,22 +3,31 @@ mod error;
 mod handlers;
 mod middlewares;
 mod models;
+mod storage;
 
-use std::{ops::Deref, sync::Arc};
+use std::{ops::Deref, path::Path, sync::Arc, time::Duration};
 
+use async_trait::async_trait;
 use axum::{
-    middleware::from_fn_with_state,
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    middleware::{from_fn, from_fn_with_state},
+    routing::{delete, get, post},
     Router,
 };
 use chat_core::{
     middlewares::{set_global_layer, verify_token, VerifyToken},
-    utils::{JwtDecodingKey, JwtEncodingKey, UserCliams},
+    utils::{is_api_key, verify_api_key, JwtDecodingKey, JwtEncodingKey, UserCliams},
 };
 pub use config::AppConfig;
 use handlers::*;
-use middlewares::ensure_chat_member;
+use middlewares::{ensure_api_scope, ensure_chat_member};
 use sqlx::PgPool;
+use storage::Storage;
+use tracing::{error, info};
+
+/// Uploads are streamed to disk, so they may exceed the default 2MB body limit
+const UPLOAD_LIMIT: usize = 1024 * 1024 * 1024;
+const UPLOAD_CLEAN_INTERVAL: u64 = 600;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -31,23 +40,60 @@ pub struct AppStateInner {
     pub(crate) dk: JwtDecodingKey,
     pub(crate) ek: JwtEncodingKey,
     pub(crate) db: PgPool,
+    pub(crate) storage: Box<dyn Storage>,
 }
 
 pub async fn get_router(state: AppState) -> anyhow::Result<Router> {
     let chat = Router::new()
-        .route("/chat/:id", get(get_chat_handler))
+        .route(
+            "/chat/:id",
+            get(get_chat_handler)
+                .patch(update_chat_handler)
+                .delete(delete_chat_handler),
+        )
+        .route(
+            "/chat/:id/members",
+            post(add_members_handler).delete(remove_members_handler),
+        )
         .route(
             "/chat/:id/message",
             get(list_message_handler).put(send_message_handler),
         )
+        .route(
+            "/chat/:id/settings",
+            get(get_chat_settings_handler).put(update_chat_settings_handler),
+        )
         .layer(from_fn_with_state(state.clone(), ensure_chat_member))
         .route("/chat", get(list_chat_handler).post(create_chat_handler));
 
     let api = Router::new()
         .route("/users", get(list_ws_users_handler))
-        .route("/upload", post(upload_file_handler))
+        .route(
+            "/dnd",
+            get(get_dnd_handler)
+                .put(update_dnd_handler)
+                .delete(delete_dnd_handler),
+        )
+        .route(
+            "/upload",
+            post(upload_file_handler).layer(DefaultBodyLimit::max(UPLOAD_LIMIT)),
+        )
         .route("/files/*path", get(download_file_handler))
+        .route("/files-meta/*path", get(file_meta_handler))
+        .route("/uploads", post(create_upload_handler))
+        .route(
+            "/uploads/:id",
+            get(get_upload_handler).patch(patch_upload_handler),
+        )
+        .route("/uploads/:id/finish", post(finish_upload_handler))
+        .route("/bots", get(list_bots_handler).post(create_bot_handler))
+        .route(
+            "/bots/:id/keys",
+            get(list_api_keys_handler).post(create_api_key_handler),
+        )
+        .route("/bots/:id/keys/:key_id", delete(revoke_api_key_handler))
         .nest("", chat)
+        .layer(from_fn(ensure_api_scope))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>));
 
     let root = Router::new()
@@ -59,6 +105,22 @@ pub async fn get_router(state: AppState) -> anyhow::Result<Router> {
     Ok(set_global_layer(root))
 }
 
+/// Periodically remove expired resumable upload sessions
+pub fn setup_upload_cleaner(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(UPLOAD_CLEAN_INTERVAL));
+        loop {
+            interval.tick().await;
+            let base = Path::new(&state.config.base_dir);
+            match models::upload::cleanup_expired(&state.db, base).await {
+                Ok(0) => {}
+                Ok(n) => info!("removed {} expired uploads", n),
+                Err(e) => error!("failed to clean expired uploads: {:?}", e),
+            }
+        }
+    });
+}
+
 impl Deref for AppState {
     type Target = AppStateInner;
     fn deref(&self) -> &Self::Target {
@@ -66,9 +128,13 @@ impl Deref for AppState {
     }
 }
 
+#[async_trait]
 impl VerifyToken for AppState {
     type Error = anyhow::Error;
-    fn verify(&self, token: &str) -> Result<UserCliams, Self::Error> {
+    async fn verify(&self, token: &str) -> Result<UserCliams, Self::Error> {
+        if is_api_key(token) {
+            return verify_api_key(&self.db, token).await;
+        }
         self.dk.verify(token)
     }
 }
@@ -78,11 +144,13 @@ impl AppState {
         let dk = JwtDecodingKey::load(config.auth.pk.as_bytes())?;
         let ek = JwtEncodingKey::load(config.auth.sk.as_bytes())?;
         let pool = PgPool::connect(&config.server.db_url).await?;
+        let storage = storage::from_config(&config)?;
         let inner = Arc::new(AppStateInner {
             config,
             dk,
             ek,
             db: pool,
+            storage,
         });
         Ok(Self { inner })
     }
@@ -109,10 +177,12 @@ impl AppState {
                         pk: pk.to_string(),
                     },
                     base_dir: "./log".to_string(),
+                    storage: config::StorageConfig::Local,
                 },
                 dk,
                 ek,
                 db: pool,
+                storage: Box::new(storage::LocalStorage::new("./log")),
             }),
         }
     }
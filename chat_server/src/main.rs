@@ -1,5 +1,5 @@
 use anyhow::Context;
-use chat_server::{get_router, AppConfig, AppState};
+use chat_server::{get_router, setup_upload_cleaner, AppConfig, AppState};
 use tokio::net::TcpListener;
 use tracing::{info, level_filters::LevelFilter};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt as _, Layer};
@@ -16,6 +16,7 @@ async fn main() -> anyhow::Result<()> {
     let state = AppState::try_new(config)
         .await
         .context("new state failed")?;
+    setup_upload_cleaner(state.clone());
     let router = get_router(state).await?;
     info!("listening on {}", addr);
     axum::serve(listener, router).await?;
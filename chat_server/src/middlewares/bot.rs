@@ -0,0 +1,25 @@
+// Bots can only do what the scopes of their API key allow
+
+use axum::{extract::Request, http::Method, middleware::Next, response::Response, Extension};
+use chat_core::utils::{ApiScope, UserCliams};
+
+use crate::error::{AppError, AppResult};
+
+/// Reading needs the `read` scope, anything else `write`
+pub async fn ensure_api_scope(
+    Extension(user): Extension<UserCliams>,
+    req: Request,
+    next: Next,
+) -> AppResult<Response> {
+    let scope = match *req.method() {
+        Method::GET | Method::HEAD => ApiScope::Read,
+        _ => ApiScope::Write,
+    };
+    if !user.allows(scope) {
+        return Err(AppError::forbidden(format!(
+            "api key lacks the {} scope",
+            scope.as_str()
+        )));
+    }
+    Ok(next.run(req).await)
+}
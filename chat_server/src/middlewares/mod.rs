@@ -1,2 +1,5 @@
+mod bot;
 mod chat;
+
+pub use bot::ensure_api_scope;
 pub use chat::ensure_chat_member;
@@ -0,0 +1,251 @@
+use chat_core::{
+    utils::{generate_api_key, ApiScope, UserCliams},
+    RowID, User,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+
+/// Characters of a key kept to tell keys apart
+const KEY_PREFIX_LEN: usize = 12;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBot {
+    pub fullname: String,
+}
+
+/// An API key without the key itself
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: RowID,
+    pub user_id: RowID,
+    pub ws_id: RowID,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    pub created_by: RowID,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    /// Never expires if not given
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// The only time the key is returned
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewApiKey {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+}
+
+/// Bots are managed by the owner of the workspace
+pub async fn ensure_admin(pool: &PgPool, user: &UserCliams) -> AppResult<()> {
+    if !user.is_workspace_owner(pool).await? {
+        return Err(AppError::forbidden(
+            "only the workspace owner can manage bots",
+        ));
+    }
+    Ok(())
+}
+
+/// Bots have no password and can't sign in, their email is a placeholder
+pub async fn create(pool: &PgPool, ws_id: RowID, input: CreateBot) -> AppResult<User> {
+    let fullname = input.fullname.trim();
+    if fullname.is_empty() || fullname.len() > 64 {
+        return Err(AppError::invalid_input("invalid bot name"));
+    }
+
+    let email = format!("bot-{}@bots.invalid", Uuid::now_v7().simple());
+    let bot = sqlx::query_as(
+        r#"
+            INSERT INTO users (fullname, email, password_hash, ws_id, is_bot)
+            VALUES ($1, $2, '', $3, TRUE)
+            RETURNING id, fullname, email, password_hash, ws_id, is_bot, created_at
+        "#,
+    )
+    .bind(fullname)
+    .bind(email)
+    .bind(ws_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(bot)
+}
+
+pub async fn list(pool: &PgPool, ws_id: RowID) -> AppResult<Vec<User>> {
+    let bots = sqlx::query_as(
+        r#"
+            SELECT id, fullname, email, password_hash, ws_id, is_bot, created_at
+            FROM users WHERE ws_id = $1 AND is_bot ORDER BY id
+        "#,
+    )
+    .bind(ws_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(bots)
+}
+
+async fn ensure_bot(pool: &PgPool, ws_id: RowID, bot_id: RowID) -> AppResult<()> {
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM users WHERE id = $1 AND ws_id = $2 AND is_bot)",
+    )
+    .bind(bot_id)
+    .bind(ws_id)
+    .fetch_one(pool)
+    .await?;
+    if !exists {
+        return Err(AppError::not_found("bot not found"));
+    }
+    Ok(())
+}
+
+pub async fn create_key(
+    pool: &PgPool,
+    ws_id: RowID,
+    bot_id: RowID,
+    created_by: RowID,
+    mut input: CreateApiKey,
+) -> AppResult<NewApiKey> {
+    ensure_bot(pool, ws_id, bot_id).await?;
+    if input.name.is_empty() || input.name.len() > 64 {
+        return Err(AppError::invalid_input("invalid key name"));
+    }
+    input.scopes.sort_by_key(|scope| *scope as u8);
+    input.scopes.dedup();
+    if input.scopes.is_empty() {
+        return Err(AppError::invalid_input("api key must have a scope"));
+    }
+    if input.expires_at.is_some_and(|t| t <= Utc::now()) {
+        return Err(AppError::invalid_input("api key already expired"));
+    }
+
+    let (key, hash) = generate_api_key();
+    let api_key = sqlx::query_as(
+        r#"
+            INSERT INTO api_keys (user_id, ws_id, name, key_hash, prefix, scopes, created_by, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id, user_id, ws_id, name, prefix, scopes, created_by, created_at,
+                expires_at, last_used_at, revoked_at
+        "#,
+    )
+    .bind(bot_id)
+    .bind(ws_id)
+    .bind(&input.name)
+    .bind(hash)
+    .bind(&key[..KEY_PREFIX_LEN])
+    .bind(&input.scopes)
+    .bind(created_by)
+    .bind(input.expires_at)
+    .fetch_one(pool)
+    .await?;
+    Ok(NewApiKey { key, api_key })
+}
+
+pub async fn list_keys(pool: &PgPool, ws_id: RowID, bot_id: RowID) -> AppResult<Vec<ApiKey>> {
+    ensure_bot(pool, ws_id, bot_id).await?;
+    let keys = sqlx::query_as(
+        r#"
+            SELECT id, user_id, ws_id, name, prefix, scopes, created_by, created_at,
+                expires_at, last_used_at, revoked_at
+            FROM api_keys WHERE user_id = $1 ORDER BY id
+        "#,
+    )
+    .bind(bot_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(keys)
+}
+
+/// Revoked keys are rejected right away, they are kept for the record
+pub async fn revoke_key(
+    pool: &PgPool,
+    ws_id: RowID,
+    bot_id: RowID,
+    key_id: RowID,
+) -> AppResult<()> {
+    let ret = sqlx::query(
+        r#"
+            UPDATE api_keys SET revoked_at = COALESCE(revoked_at, NOW())
+            WHERE id = $1 AND user_id = $2 AND ws_id = $3
+        "#,
+    )
+    .bind(key_id)
+    .bind(bot_id)
+    .bind(ws_id)
+    .execute(pool)
+    .await?;
+    if ret.rows_affected() == 0 {
+        return Err(AppError::not_found("api key not found"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chat_core::utils::verify_api_key;
+
+    use super::*;
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_bot_api_keys(pool: PgPool) {
+        let owner = UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        };
+        ensure_admin(&pool, &owner).await.unwrap();
+        let member = UserCliams {
+            uid: 2,
+            ws_id: 1,
+            ..Default::default()
+        };
+        let ret = ensure_admin(&pool, &member).await;
+        assert!(matches!(ret, Err(AppError::Forbidden(_))));
+
+        let input = CreateBot {
+            fullname: "ci".to_string(),
+        };
+        let bot = create(&pool, 1, input).await.unwrap();
+        assert!(bot.is_bot);
+        assert_eq!(list(&pool, 1).await.unwrap().len(), 1);
+        assert!(list(&pool, 2).await.unwrap().is_empty());
+
+        let input = CreateApiKey {
+            name: "deploy".to_string(),
+            scopes: vec![ApiScope::Write, ApiScope::Read, ApiScope::Write],
+            expires_at: None,
+        };
+        let new = create_key(&pool, 1, bot.id, 1, input).await.unwrap();
+        assert_eq!(new.api_key.scopes, vec![ApiScope::Read, ApiScope::Write]);
+        assert!(new.key.starts_with(&new.api_key.prefix));
+
+        let user = verify_api_key(&pool, &new.key).await.unwrap();
+        assert_eq!((user.uid, user.ws_id), (bot.id, 1));
+        assert!(user.allows(ApiScope::Read) && !user.allows(ApiScope::Events));
+        let keys = list_keys(&pool, 1, bot.id).await.unwrap();
+        assert!(keys[0].last_used_at.is_some());
+
+        // keys of bots in other workspaces are out of reach
+        let ret = list_keys(&pool, 2, bot.id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let ret = revoke_key(&pool, 2, bot.id, new.api_key.id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        revoke_key(&pool, 1, bot.id, new.api_key.id).await.unwrap();
+        assert!(verify_api_key(&pool, &new.key).await.is_err());
+        assert!(verify_api_key(&pool, "chat_nothing").await.is_err());
+    }
+}
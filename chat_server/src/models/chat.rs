@@ -1,7 +1,8 @@
-use chat_core::{Chat, ChatType, RowID};
+use chat_core::{event::ChatEvent, Chat, ChatType, RowID};
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 
+use super::event;
 use crate::error::{AppError, AppResult};
 
 #[derive(Deserialize)]
@@ -11,7 +12,19 @@ pub struct CreateChat {
     pub public: bool,
 }
 
-pub async fn create(pool: &PgPool, ws_id: RowID, input: CreateChat) -> AppResult<Chat> {
+/// Fields not given are kept
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateChat {
+    pub name: Option<String>,
+    pub public: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMembers {
+    pub members: Vec<RowID>,
+}
+
+pub async fn create(pool: &PgPool, ws_id: RowID, uid: RowID, input: CreateChat) -> AppResult<Chat> {
     if input.members.len() < 2 {
         return Err(AppError::invalid_input("chat must have at least 2 members"));
     }
@@ -30,22 +43,13 @@ pub async fn create(pool: &PgPool, ws_id: RowID, input: CreateChat) -> AppResult
         return Err(AppError::invalid_input("some members don't exist"));
     }
 
-    let typ = match (&input.name, input.members.len()) {
-        (None, 2) => ChatType::Single,
-        (None, _) => ChatType::Group,
-        (Some(_), _) => {
-            if input.public {
-                ChatType::PublicChannel
-            } else {
-                ChatType::PrivateChannel
-            }
-        }
-    };
+    let typ = chat_type(input.name.as_deref(), input.members.len(), input.public);
 
-    let chat = sqlx::query_as(
+    let mut tx = pool.begin().await?;
+    let chat: Chat = sqlx::query_as(
         r#"
-            INSERT INTO chats (ws_id, name, type, members)
-            VALUES ($1, $2, $3, $4)
+            INSERT INTO chats (ws_id, name, type, members, created_by)
+            VALUES ($1, $2, $3, $4, $5)
             RETURNING id, ws_id, name, type, members, created_at
             "#,
     )
@@ -53,12 +57,100 @@ pub async fn create(pool: &PgPool, ws_id: RowID, input: CreateChat) -> AppResult
     .bind(input.name)
     .bind(typ)
     .bind(input.members)
-    .fetch_one(pool)
+    .bind(uid)
+    .fetch_one(&mut *tx)
     .await?;
+    let event = ChatEvent::ChatCreated {
+        actor: uid,
+        chat: chat.clone(),
+    };
+    event::append(&mut tx, ws_id, &event).await?;
+    tx.commit().await?;
 
     Ok(chat)
 }
 
+/// Rename the chat or change whether a channel is public
+pub async fn update(pool: &PgPool, id: RowID, uid: RowID, input: UpdateChat) -> AppResult<Chat> {
+    if let Some(name) = &input.name {
+        if name.is_empty() || name.len() > 64 {
+            return Err(AppError::invalid_input("invalid chat name"));
+        }
+    }
+    change(pool, id, uid, true, |chat| {
+        if input.name.is_some() {
+            chat.name = input.name;
+        }
+        if let Some(public) = input.public {
+            chat.typ = if public {
+                ChatType::PublicChannel
+            } else {
+                ChatType::PrivateChannel
+            };
+        }
+        Ok(())
+    })
+    .await
+}
+
+pub async fn add_members(
+    pool: &PgPool,
+    id: RowID,
+    uid: RowID,
+    input: UpdateMembers,
+) -> AppResult<Chat> {
+    change(pool, id, uid, true, |chat| {
+        for member in input.members {
+            if !chat.members.contains(&member) {
+                chat.members.push(member);
+            }
+        }
+        Ok(())
+    })
+    .await
+}
+
+/// Managers can remove anyone, other members can only leave the chat themselves
+pub async fn remove_members(
+    pool: &PgPool,
+    id: RowID,
+    uid: RowID,
+    input: UpdateMembers,
+) -> AppResult<Chat> {
+    let leaving = input.members.iter().all(|member| *member == uid);
+    change(pool, id, uid, !leaving, |chat| {
+        chat.members
+            .retain(|member| !input.members.contains(member));
+        Ok(())
+    })
+    .await
+}
+
+/// The messages and settings of the chat go with it
+pub async fn delete(pool: &PgPool, id: RowID, uid: RowID) -> AppResult<()> {
+    let mut tx = pool.begin().await?;
+    let chat: Chat = sqlx::query_as(
+        "SELECT id, ws_id, name, type, members, created_at FROM chats WHERE id = $1 FOR UPDATE",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::not_found("chat not found"))?;
+    ensure_manager(&mut tx, id, uid).await?;
+    for sql in [
+        "DELETE FROM messages WHERE chat_id = $1",
+        "DELETE FROM chat_settings WHERE chat_id = $1",
+        "DELETE FROM chats WHERE id = $1",
+    ] {
+        sqlx::query(sql).bind(id).execute(&mut *tx).await?;
+    }
+    let ws_id = chat.ws_id;
+    let event = ChatEvent::ChatDeleted { actor: uid, chat };
+    event::append(&mut tx, ws_id, &event).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
 pub async fn list(pool: &PgPool, ws_id: RowID, uid: RowID) -> AppResult<Vec<Chat>> {
     let chats = sqlx::query_as(
         r#"
@@ -91,6 +183,101 @@ pub async fn is_member(pool: &PgPool, chat_id: RowID, uid: RowID) -> AppResult<b
     Ok(ret.is_some())
 }
 
+fn chat_type(name: Option<&str>, members: usize, public: bool) -> ChatType {
+    match (name, members) {
+        (None, 2) => ChatType::Single,
+        (None, _) => ChatType::Group,
+        (Some(_), _) => {
+            if public {
+                ChatType::PublicChannel
+            } else {
+                ChatType::PrivateChannel
+            }
+        }
+    }
+}
+
+/// The creator of the chat and the owner of the workspace can rename, delete it and change
+/// its members
+async fn ensure_manager(tx: &mut PgConnection, id: RowID, uid: RowID) -> AppResult<()> {
+    let is_manager: bool = sqlx::query_scalar(
+        r#"
+            SELECT COALESCE(c.created_by = $2, FALSE) OR w.owner_id = $2
+            FROM chats c JOIN workspaces w ON w.id = c.ws_id
+            WHERE c.id = $1
+        "#,
+    )
+    .bind(id)
+    .bind(uid)
+    .fetch_one(tx)
+    .await?;
+    if !is_manager {
+        return Err(AppError::forbidden(
+            "only the creator of the chat or the workspace owner can change it",
+        ));
+    }
+    Ok(())
+}
+
+/// Apply `f` to the chat locked in a transaction, the change is appended with the chat
+/// before and after it. Only managers can make it if `manage` is set.
+async fn change<F>(pool: &PgPool, id: RowID, uid: RowID, manage: bool, f: F) -> AppResult<Chat>
+where
+    F: FnOnce(&mut Chat) -> AppResult<()>,
+{
+    let mut tx = pool.begin().await?;
+    let old: Chat = sqlx::query_as(
+        "SELECT id, ws_id, name, type, members, created_at FROM chats WHERE id = $1 FOR UPDATE",
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::not_found("chat not found"))?;
+    if manage {
+        ensure_manager(&mut tx, id, uid).await?;
+    }
+
+    let mut new = old.clone();
+    f(&mut new)?;
+    new.typ = chat_type(
+        new.name.as_deref(),
+        new.members.len(),
+        new.typ == ChatType::PublicChannel,
+    );
+    if new.members.len() < 2 {
+        return Err(AppError::invalid_input("chat must have at least 2 members"));
+    }
+    let n: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users WHERE id = ANY($1) AND ws_id = $2")
+        .bind(&new.members)
+        .bind(new.ws_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if n != new.members.len() as i64 {
+        return Err(AppError::invalid_input("some members don't exist"));
+    }
+
+    let chat: Chat = sqlx::query_as(
+        r#"
+            UPDATE chats SET name = $2, type = $3, members = $4 WHERE id = $1
+            RETURNING id, ws_id, name, type, members, created_at
+            "#,
+    )
+    .bind(id)
+    .bind(&new.name)
+    .bind(new.typ)
+    .bind(&new.members)
+    .fetch_one(&mut *tx)
+    .await?;
+    let event = ChatEvent::ChatUpdated {
+        actor: uid,
+        old,
+        new: chat.clone(),
+    };
+    event::append(&mut tx, chat.ws_id, &event).await?;
+    tx.commit().await?;
+    Ok(chat)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -105,7 +292,7 @@ mod test {
             name: None,
             public: false,
         };
-        let chat = create(&pool, 1, input).await.unwrap();
+        let chat = create(&pool, 1, 1, input).await.unwrap();
         assert_eq!(chat.members, vec![1, 2]);
         matches!(chat.typ, ChatType::Single);
         assert!(chat.name.is_none());
@@ -121,7 +308,7 @@ mod test {
             name: Some("test".to_string()),
             public: true,
         };
-        let chat = create(&pool, 1, input).await.unwrap();
+        let chat = create(&pool, 1, 1, input).await.unwrap();
         assert_eq!(chat.members, vec![1, 2]);
         matches!(chat.typ, ChatType::PublicChannel);
         assert!(chat.name.is_some());
@@ -136,4 +323,119 @@ mod test {
         let chats = list(&pool, 1, 1).await.unwrap();
         assert_eq!(chats.len(), 1);
     }
+
+    async fn last_event(pool: &PgPool) -> ChatEvent {
+        let sqlx::types::Json(event) =
+            sqlx::query_scalar("SELECT payload FROM events ORDER BY id DESC LIMIT 1")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        event
+    }
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_update_chat(pool: PgPool) {
+        let members = UpdateMembers { members: vec![3] };
+        let ret = add_members(&pool, 1, 1, members).await;
+        assert!(
+            matches!(ret, Err(AppError::InvalidInput(_))),
+            "user 3 is in ws 2"
+        );
+
+        // a third member turns the single chat into a group
+        let user = sqlx::query_scalar(
+            "INSERT INTO users (fullname, email, password_hash, ws_id) VALUES ('u', 'u@a.com', '', 1) RETURNING id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        let members = UpdateMembers {
+            members: vec![user, 2],
+        };
+        let ret = add_members(
+            &pool,
+            1,
+            2,
+            UpdateMembers {
+                members: vec![user],
+            },
+        )
+        .await;
+        assert!(
+            matches!(ret, Err(AppError::Forbidden(_))),
+            "user 2 is neither the creator nor the owner"
+        );
+        let chat = add_members(&pool, 1, 1, members).await.unwrap();
+        assert_eq!(chat.members, vec![1, 2, user]);
+        assert_eq!(chat.typ, ChatType::Group);
+        let ChatEvent::ChatUpdated { actor, old, new } = last_event(&pool).await else {
+            panic!("expect a chat update");
+        };
+        assert_eq!(actor, 1);
+        assert_eq!(old.members, vec![1, 2]);
+        assert_eq!(new.members, vec![1, 2, user]);
+
+        let input = UpdateChat {
+            name: Some("ops".to_string()),
+            public: Some(true),
+        };
+        let chat = update(&pool, 1, 1, input).await.unwrap();
+        assert_eq!(chat.typ, ChatType::PublicChannel);
+        assert_eq!(chat.name.as_deref(), Some("ops"));
+
+        // members can only leave by themselves
+        let ret = remove_members(&pool, 1, 2, UpdateMembers { members: vec![1] }).await;
+        assert!(matches!(ret, Err(AppError::Forbidden(_))));
+        let chat = remove_members(
+            &pool,
+            1,
+            user,
+            UpdateMembers {
+                members: vec![user],
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(chat.members, vec![1, 2]);
+        let members = UpdateMembers { members: vec![2] };
+        let ret = remove_members(&pool, 1, 1, members).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        assert!(matches!(
+            delete(&pool, 1, 2).await,
+            Err(AppError::Forbidden(_))
+        ));
+        delete(&pool, 1, 1).await.unwrap();
+        let ChatEvent::ChatDeleted { actor, chat } = last_event(&pool).await else {
+            panic!("expect a chat deletion");
+        };
+        assert_eq!((actor, chat.id), (1, 1));
+        assert!(matches!(
+            delete(&pool, 1, 1).await,
+            Err(AppError::NotFound(_))
+        ));
+
+        // the creator manages the chats they created
+        let input = CreateChat {
+            name: None,
+            members: vec![1, 2, user],
+            public: false,
+        };
+        let chat = create(&pool, 1, 2, input).await.unwrap();
+        let input = UpdateChat {
+            name: Some("team".to_string()),
+            public: None,
+        };
+        update(&pool, chat.id, 2, input).await.unwrap();
+        let input = UpdateChat {
+            name: Some("mine".to_string()),
+            public: None,
+        };
+        let ret = update(&pool, chat.id, user, input).await;
+        assert!(matches!(ret, Err(AppError::Forbidden(_))));
+        delete(&pool, chat.id, 2).await.unwrap();
+    }
 }
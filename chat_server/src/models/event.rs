@@ -0,0 +1,92 @@
+use chat_core::{
+    event::{ChatEvent, EVENTS_CHANNEL},
+    RowID,
+};
+use sqlx::{types::Json, PgConnection};
+
+use crate::error::AppResult;
+
+/// Held until the transaction ends, so events become visible in id order
+/// and consumers reading past the last id they have seen never skip one
+const EVENTS_LOCK: i64 = 0x6576_656e_7473;
+
+/// Append an event in the transaction of the change it describes
+pub async fn append(tx: &mut PgConnection, ws_id: RowID, event: &ChatEvent) -> AppResult<RowID> {
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(EVENTS_LOCK)
+        .execute(&mut *tx)
+        .await?;
+    let id: RowID =
+        sqlx::query_scalar("INSERT INTO events (ws_id, payload) VALUES ($1, $2) RETURNING id")
+            .bind(ws_id)
+            .bind(Json(event))
+            .fetch_one(&mut *tx)
+            .await?;
+    // delivered when the transaction commits
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(EVENTS_CHANNEL)
+        .bind(id.to_string())
+        .execute(&mut *tx)
+        .await?;
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        models::{
+            chat::{self, CreateChat},
+            message::{self, CreateMessage},
+        },
+        storage::LocalStorage,
+    };
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_chat_events(pool: PgPool) {
+        let input = CreateChat {
+            members: vec![1, 2],
+            name: None,
+            public: false,
+        };
+        let chat = chat::create(&pool, 1, 1, input).await.unwrap();
+
+        let events: Vec<(RowID, Json<ChatEvent>)> =
+            sqlx::query_as("SELECT ws_id, payload FROM events ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, 1);
+        assert!(
+            matches!(&events[0].1 .0, ChatEvent::ChatCreated { actor: 1, chat: c } if c.id == chat.id)
+        );
+
+        let input = CreateMessage {
+            chat_id: chat.id,
+            content: "hello".to_string(),
+            files: vec![],
+        };
+        let storage = LocalStorage::new("/tmp/chat_server");
+        let message = message::create(&pool, input, 1, &storage).await.unwrap();
+        let event: Json<ChatEvent> =
+            sqlx::query_scalar("SELECT payload FROM events ORDER BY id DESC LIMIT 1")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        let ChatEvent::MessageCreated {
+            message: m,
+            members,
+        } = event.0
+        else {
+            panic!("unexpected event: {:?}", event.0);
+        };
+        assert_eq!(m.id, message.id);
+        assert_eq!(members, vec![1, 2]);
+    }
+}
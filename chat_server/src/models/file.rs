@@ -1,9 +1,21 @@
 use std::path::{Path, PathBuf};
 
+use anyhow::Context;
 use chat_core::RowID;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
+use sqlx::{prelude::FromRow, PgPool};
+use tokio::{fs, io::AsyncWriteExt};
+use uuid::Uuid;
 
-use crate::error::{AppError, AppResult};
+use super::thumbnail::{self, ImageInfo, ThumbnailSize};
+use crate::{
+    error::{AppError, AppResult},
+    storage::Storage,
+};
+
+pub(crate) const TMP_DIR: &str = "tmp";
 
 pub struct ChatFile {
     pub ws_id: RowID,
@@ -11,22 +23,76 @@ pub struct ChatFile {
     pub hash: String,
 }
 
+/// Metadata recorded for every uploader of a file
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub id: RowID,
+    pub ws_id: RowID,
+    pub uploader_id: RowID,
+    pub filename: String,
+    pub size: i64,
+    pub mime: String,
+    pub hash: String,
+    pub url: String,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Default, Deserialize)]
+pub struct DownloadFile {
+    /// Download a thumbnail instead of the original image
+    pub size: Option<ThumbnailSize>,
+}
+
+/// A file being streamed to disk, hashed chunk by chunk.
+/// It's written to `base_dir/tmp` first and moved to its hashed path on `persist`.
+/// If it's dropped before that, e.g. on a write error, the temporary file is removed.
+pub struct FileUpload {
+    tmp_path: PathBuf,
+    file: fs::File,
+    hasher: Sha1,
+    size: u64,
+}
+
 impl ChatFile {
-    pub fn new(ws_id: RowID, filename: &str, data: &[u8]) -> Self {
-        let hash = Sha1::digest(data);
+    pub fn new(ws_id: RowID, filename: &str, digest: &[u8]) -> Self {
         Self {
             ws_id,
-            ext: filename.split('.').last().unwrap_or("txt").to_string(),
-            hash: hex::encode(hash),
+            ext: filename.rsplit('.').next().unwrap_or("txt").to_string(),
+            hash: hex::encode(digest),
         }
     }
 
     pub fn url(&self) -> String {
-        format!("/files/{}", self.hash_to_path())
+        format!("/files/{}", self.key())
     }
 
-    pub fn path(&self, base_dir: &Path) -> PathBuf {
-        base_dir.join(self.hash_to_path())
+    /// Location of the file in the storage
+    pub fn key(&self) -> String {
+        self.hash_to_path()
+    }
+
+    /// File name without directories, used in Content-Disposition
+    pub fn filename(&self) -> String {
+        format!("{}.{}", self.hash, self.ext)
+    }
+
+    pub fn content_type(&self) -> mime_guess::Mime {
+        mime_guess::from_ext(&self.ext).first_or_octet_stream()
+    }
+
+    /// Move a fully written local file `src` into the storage, with thumbnails for images.
+    /// If the same content was uploaded before, `src` is removed and the existing file is kept.
+    pub async fn store(&self, storage: &dyn Storage, src: &Path) -> AppResult<Option<ImageInfo>> {
+        let image = thumbnail::generate(storage, self, src).await?;
+        let key = self.key();
+        if storage.size(&key).await?.is_some() {
+            fs::remove_file(src).await?;
+        } else {
+            storage.put_file(&key, src).await?;
+        }
+        Ok(image)
     }
 
     fn hash_to_path(&self) -> String {
@@ -59,3 +125,168 @@ impl ChatFile {
         })
     }
 }
+
+impl FileUpload {
+    pub async fn create(base_dir: &Path) -> AppResult<Self> {
+        let dir = base_dir.join(TMP_DIR);
+        fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("create dir: {:?}", &dir))?;
+        let tmp_path = dir.join(Uuid::now_v7().to_string());
+        let file = fs::File::create(&tmp_path)
+            .await
+            .with_context(|| format!("create file: {:?}", &tmp_path))?;
+        Ok(Self {
+            tmp_path,
+            file,
+            hasher: Sha1::new(),
+            size: 0,
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> AppResult<()> {
+        self.hasher.update(chunk);
+        self.size += chunk.len() as u64;
+        self.file
+            .write_all(chunk)
+            .await
+            .with_context(|| format!("write file: {:?}", &self.tmp_path))?;
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Move the uploaded data to its hashed location
+    pub async fn persist(
+        mut self,
+        storage: &dyn Storage,
+        ws_id: RowID,
+        filename: &str,
+    ) -> AppResult<(ChatFile, Option<ImageInfo>)> {
+        self.file.flush().await?;
+
+        let chat_file = ChatFile::new(ws_id, filename, &self.hasher.finalize_reset());
+        let image = chat_file.store(storage, &self.tmp_path).await?;
+        Ok((chat_file, image))
+    }
+}
+
+impl Drop for FileUpload {
+    fn drop(&mut self) {
+        // already moved away if it was persisted
+        let _ = std::fs::remove_file(&self.tmp_path);
+    }
+}
+
+/// Record that `uploader_id` uploaded the file as `filename`
+pub async fn create_meta(
+    pool: &PgPool,
+    file: &ChatFile,
+    uploader_id: RowID,
+    filename: &str,
+    size: u64,
+    image: Option<ImageInfo>,
+) -> AppResult<()> {
+    sqlx::query(
+        r#"
+            INSERT INTO files (ws_id, uploader_id, filename, size, mime, hash, url, width, height)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (url, uploader_id) DO NOTHING
+        "#,
+    )
+    .bind(file.ws_id)
+    .bind(uploader_id)
+    .bind(filename)
+    .bind(size as i64)
+    .bind(file.content_type().to_string())
+    .bind(&file.hash)
+    .bind(file.url())
+    .bind(image.map(|i| i.width as i32))
+    .bind(image.map(|i| i.height as i32))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Metadata of the user's own upload of the file, or of its first upload by anyone else
+pub async fn find_meta(pool: &PgPool, url: &str, uid: RowID) -> AppResult<Option<FileMeta>> {
+    let meta = sqlx::query_as(
+        "SELECT * FROM files WHERE url = $1 ORDER BY uploader_id = $2 DESC, id LIMIT 1",
+    )
+    .bind(url)
+    .bind(uid)
+    .fetch_optional(pool)
+    .await?;
+    Ok(meta)
+}
+
+/// The user can access a file if they uploaded it, or it's referenced by a
+/// message in one of their chats
+pub async fn can_access(pool: &PgPool, url: &str, uid: RowID) -> AppResult<bool> {
+    let (ret,): (bool,) = sqlx::query_as(
+        r#"
+            SELECT EXISTS (
+                SELECT 1 FROM files WHERE url = $1 AND uploader_id = $2
+            ) OR EXISTS (
+                SELECT 1 FROM messages m JOIN chats c ON c.id = m.chat_id
+                WHERE m.files @> ARRAY[$1] AND $2 = ANY(c.members)
+            )
+        "#,
+    )
+    .bind(url)
+    .bind(uid)
+    .fetch_one(pool)
+    .await?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::LocalStorage;
+
+    use super::*;
+
+    #[test]
+    fn t_chat_file_url() {
+        let file = ChatFile::new(1, "a.png", &Sha1::digest(b"hello"));
+        let url = file.url();
+        assert_eq!(
+            url,
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.png"
+        );
+        let parsed = ChatFile::from_url(url.trim_start_matches("/files/")).unwrap();
+        assert_eq!(parsed.hash, file.hash);
+        assert_eq!(parsed.ext, "png");
+        assert_eq!(parsed.content_type(), mime_guess::mime::IMAGE_PNG);
+    }
+
+    #[tokio::test]
+    async fn t_file_upload() {
+        let base = std::env::temp_dir().join(Uuid::now_v7().to_string());
+        let storage = LocalStorage::new(&base);
+        let mut upload = FileUpload::create(&base).await.unwrap();
+        upload.write(b"hel").await.unwrap();
+        upload.write(b"lo").await.unwrap();
+        assert_eq!(upload.size(), 5);
+        let (file, image) = upload.persist(&storage, 1, "a.txt").await.unwrap();
+        assert!(image.is_none());
+        assert_eq!(file.hash, hex::encode(Sha1::digest(b"hello")));
+        let data = fs::read(base.join(file.key())).await.unwrap();
+        assert_eq!(data, b"hello");
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn t_file_upload_dropped() {
+        let base = std::env::temp_dir().join(Uuid::now_v7().to_string());
+        let mut upload = FileUpload::create(&base).await.unwrap();
+        upload.write(b"hel").await.unwrap();
+        let tmp_path = upload.tmp_path.clone();
+        assert!(tmp_path.exists());
+        drop(upload);
+        assert!(!tmp_path.exists());
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+}
@@ -1,10 +1,15 @@
-use std::path::Path;
-
-use chat_core::{Message, RowID};
+use chat_core::{event::ChatEvent, Message, RowID};
 use serde::Deserialize;
 use sqlx::PgPool;
 
-use crate::error::{AppError, AppResult};
+use super::{
+    event,
+    file::{self, ChatFile},
+};
+use crate::{
+    error::{AppError, AppResult},
+    storage::Storage,
+};
 
 #[derive(Deserialize)]
 pub struct CreateMessage {
@@ -42,13 +47,20 @@ pub async fn list(pool: &PgPool, chat_id: RowID, input: ListMessage) -> AppResul
 
 pub async fn create(
     pool: &PgPool,
-    input: CreateMessage,
+    mut input: CreateMessage,
     uid: RowID,
-    base_dir: &Path,
+    storage: &dyn Storage,
 ) -> AppResult<Message> {
-    input.verify(base_dir)?;
+    input.verify(pool, storage, uid).await?;
 
-    let message = sqlx::query_as(
+    let mut tx = pool.begin().await?;
+    // lock the chat, so the members of the event are the ones at the time of the message
+    let (ws_id, members): (RowID, Vec<RowID>) =
+        sqlx::query_as("SELECT ws_id, members FROM chats WHERE id = $1 FOR SHARE")
+            .bind(input.chat_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    let message: Message = sqlx::query_as(
         r#"
             INSERT INTO messages (chat_id, sender_id, content, files)
             VALUES ($1, $2, $3, $4)
@@ -59,23 +71,39 @@ pub async fn create(
     .bind(uid)
     .bind(input.content)
     .bind(input.files)
-    .fetch_one(pool)
+    .fetch_one(&mut *tx)
     .await?;
+    let event = ChatEvent::MessageCreated {
+        message: message.clone(),
+        members,
+    };
+    event::append(&mut tx, ws_id, &event).await?;
+    tx.commit().await?;
 
     Ok(message)
 }
 
 impl CreateMessage {
-    pub fn verify(&self, base_dir: &Path) -> AppResult<()> {
+    /// Files are normalized to their url, and must be accessible to the sender
+    pub async fn verify(
+        &mut self,
+        pool: &PgPool,
+        storage: &dyn Storage,
+        uid: RowID,
+    ) -> AppResult<()> {
         // content should not be empty
         if self.content.is_empty() {
             return Err(AppError::invalid_input("empty content"));
         }
 
         // files should be exist
-        for file in &self.files {
-            let file_path = base_dir.join(file);
-            if !file_path.exists() {
+        for url in self.files.iter_mut() {
+            let file = ChatFile::from_url(url.trim_start_matches("/files/"))?;
+            *url = file.url();
+            if !file::can_access(pool, url, uid).await? {
+                return Err(AppError::invalid_input("some files not exist"));
+            }
+            if storage.size(&file.key()).await?.is_none() {
                 return Err(AppError::invalid_input("some files not exist"));
             }
         }
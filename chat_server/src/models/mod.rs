@@ -1,5 +1,10 @@
+pub mod bot;
 pub mod chat;
+pub mod event;
 pub mod file;
 pub mod message;
+pub mod preference;
+pub mod thumbnail;
+pub mod upload;
 pub mod user;
 pub mod workspace;
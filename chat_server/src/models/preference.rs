@@ -0,0 +1,219 @@
+use chat_core::RowID;
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, PgPool};
+
+use crate::error::{AppError, AppResult};
+
+/// Messages not matching the level are delivered silently
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "notify_level", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum NotifyLevel {
+    #[default]
+    All,
+    Mentions,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct ChatSettings {
+    pub chat_id: RowID,
+    pub level: NotifyLevel,
+    /// Every message is silent until then
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateChatSettings {
+    #[serde(default)]
+    pub level: NotifyLevel,
+    pub muted_until: Option<DateTime<Utc>>,
+}
+
+/// Daily window in the local time of `timezone`, it wraps around midnight if `end_time` is earlier
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct DndSchedule {
+    pub ws_id: RowID,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub timezone: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateDndSchedule {
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+/// Settings of the user in the chat, the defaults if never changed
+pub async fn get_chat_settings(
+    pool: &PgPool,
+    chat_id: RowID,
+    uid: RowID,
+) -> AppResult<ChatSettings> {
+    let settings = sqlx::query_as(
+        "SELECT chat_id, level, muted_until FROM chat_settings WHERE chat_id = $1 AND user_id = $2",
+    )
+    .bind(chat_id)
+    .bind(uid)
+    .fetch_optional(pool)
+    .await?;
+    Ok(settings.unwrap_or(ChatSettings {
+        chat_id,
+        level: NotifyLevel::All,
+        muted_until: None,
+    }))
+}
+
+pub async fn update_chat_settings(
+    pool: &PgPool,
+    chat_id: RowID,
+    uid: RowID,
+    input: UpdateChatSettings,
+) -> AppResult<ChatSettings> {
+    let settings = sqlx::query_as(
+        r#"
+            INSERT INTO chat_settings (user_id, chat_id, level, muted_until)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (user_id, chat_id) DO UPDATE SET level = $3, muted_until = $4
+            RETURNING chat_id, level, muted_until
+        "#,
+    )
+    .bind(uid)
+    .bind(chat_id)
+    .bind(input.level)
+    .bind(input.muted_until)
+    .fetch_one(pool)
+    .await?;
+    Ok(settings)
+}
+
+pub async fn get_dnd(pool: &PgPool, ws_id: RowID, uid: RowID) -> AppResult<DndSchedule> {
+    let schedule = sqlx::query_as(
+        r#"
+            SELECT ws_id, start_time, end_time, timezone FROM dnd_schedules
+            WHERE ws_id = $1 AND user_id = $2
+        "#,
+    )
+    .bind(ws_id)
+    .bind(uid)
+    .fetch_optional(pool)
+    .await?;
+    schedule.ok_or_else(|| AppError::not_found("no do-not-disturb schedule"))
+}
+
+pub async fn update_dnd(
+    pool: &PgPool,
+    ws_id: RowID,
+    uid: RowID,
+    input: UpdateDndSchedule,
+) -> AppResult<DndSchedule> {
+    let valid: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1)")
+            .bind(&input.timezone)
+            .fetch_one(pool)
+            .await?;
+    if !valid {
+        return Err(AppError::invalid_input("invalid timezone"));
+    }
+    if input.start_time == input.end_time {
+        return Err(AppError::invalid_input("empty do-not-disturb window"));
+    }
+
+    let schedule = sqlx::query_as(
+        r#"
+            INSERT INTO dnd_schedules (user_id, ws_id, start_time, end_time, timezone)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id, ws_id) DO UPDATE
+                SET start_time = $3, end_time = $4, timezone = $5
+            RETURNING ws_id, start_time, end_time, timezone
+        "#,
+    )
+    .bind(uid)
+    .bind(ws_id)
+    .bind(input.start_time)
+    .bind(input.end_time)
+    .bind(input.timezone)
+    .fetch_one(pool)
+    .await?;
+    Ok(schedule)
+}
+
+pub async fn delete_dnd(pool: &PgPool, ws_id: RowID, uid: RowID) -> AppResult<()> {
+    sqlx::query("DELETE FROM dnd_schedules WHERE ws_id = $1 AND user_id = $2")
+        .bind(ws_id)
+        .bind(uid)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_chat_settings(pool: PgPool) {
+        let settings = get_chat_settings(&pool, 1, 1).await.unwrap();
+        assert_eq!(settings.level, NotifyLevel::All);
+        assert!(settings.muted_until.is_none());
+
+        let until = Utc::now() + Duration::hours(1);
+        let input = UpdateChatSettings {
+            level: NotifyLevel::Mentions,
+            muted_until: Some(until),
+        };
+        update_chat_settings(&pool, 1, 1, input).await.unwrap();
+        let settings = get_chat_settings(&pool, 1, 1).await.unwrap();
+        assert_eq!(settings.level, NotifyLevel::Mentions);
+        assert_eq!(
+            settings.muted_until.map(|t| t.timestamp()),
+            Some(until.timestamp())
+        );
+
+        // other users keep the defaults
+        let settings = get_chat_settings(&pool, 1, 2).await.unwrap();
+        assert_eq!(settings.level, NotifyLevel::All);
+    }
+
+    #[sqlx::test(
+        migrator = "crate::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_dnd_schedule(pool: PgPool) {
+        assert!(get_dnd(&pool, 1, 1).await.is_err());
+
+        let time = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        let input = UpdateDndSchedule {
+            start_time: time("22:00"),
+            end_time: time("07:00"),
+            timezone: "Nope/Nowhere".to_string(),
+        };
+        let ret = update_dnd(&pool, 1, 1, input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        let input = UpdateDndSchedule {
+            start_time: time("22:00"),
+            end_time: time("07:00"),
+            timezone: "Asia/Shanghai".to_string(),
+        };
+        update_dnd(&pool, 1, 1, input).await.unwrap();
+        let schedule = get_dnd(&pool, 1, 1).await.unwrap();
+        assert_eq!(schedule.start_time, time("22:00"));
+        assert_eq!(schedule.timezone, "Asia/Shanghai");
+
+        delete_dnd(&pool, 1, 1).await.unwrap();
+        assert!(get_dnd(&pool, 1, 1).await.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use image::{DynamicImage, ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use tracing::warn;
+
+use super::file::ChatFile;
+use crate::{error::AppResult, storage::Storage};
+
+/// Thumbnails are scaled to fit in a square of the size, keeping the aspect ratio
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThumbnailSize {
+    Small,
+    Medium,
+    Large,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ThumbnailSize {
+    pub const ALL: [ThumbnailSize; 3] = [Self::Small, Self::Medium, Self::Large];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Small => "small",
+            Self::Medium => "medium",
+            Self::Large => "large",
+        }
+    }
+
+    fn max_side(&self) -> u32 {
+        match self {
+            Self::Small => 128,
+            Self::Medium => 512,
+            Self::Large => 1024,
+        }
+    }
+}
+
+impl ChatFile {
+    pub fn is_image(&self) -> bool {
+        ImageFormat::from_extension(&self.ext).is_some_and(|f| f.reading_enabled())
+    }
+
+    /// Thumbnails are stored beside the original, jpeg stays jpeg and others become png
+    pub fn thumbnail_key(&self, size: ThumbnailSize) -> String {
+        let key = self.key();
+        let stem = key.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(&key);
+        format!("{}_{}.{}", stem, size.as_str(), self.thumbnail_ext())
+    }
+
+    pub fn thumbnail_content_type(&self) -> &'static str {
+        match self.thumbnail_ext() {
+            "jpg" => "image/jpeg",
+            _ => "image/png",
+        }
+    }
+
+    fn thumbnail_ext(&self) -> &'static str {
+        match ImageFormat::from_extension(&self.ext) {
+            Some(ImageFormat::Jpeg) => "jpg",
+            _ => "png",
+        }
+    }
+}
+
+/// Decode the image at `src` and store its thumbnails, `src` is kept.
+/// Return `None` if `src` can't be decoded as an image.
+pub async fn generate(
+    storage: &dyn Storage,
+    file: &ChatFile,
+    src: &Path,
+) -> AppResult<Option<ImageInfo>> {
+    if !file.is_image() {
+        return Ok(None);
+    }
+
+    let exists = storage
+        .size(&file.thumbnail_key(ThumbnailSize::Small))
+        .await?
+        .is_some();
+    let jpeg = file.thumbnail_ext() == "jpg";
+    let path = src.to_owned();
+    let ret = task::spawn_blocking(move || render(&path, jpeg, exists)).await?;
+    let (info, thumbnails) = match ret {
+        Ok(ret) => ret,
+        Err(e) => {
+            warn!("failed to decode image {:?}: {:#}", src, e);
+            return Ok(None);
+        }
+    };
+
+    for (size, path) in thumbnails {
+        storage.put_file(&file.thumbnail_key(size), &path).await?;
+    }
+    Ok(Some(info))
+}
+
+/// Write every thumbnail beside `src`, skipped if they already exist in the storage
+fn render(
+    src: &Path,
+    jpeg: bool,
+    exists: bool,
+) -> anyhow::Result<(ImageInfo, Vec<(ThumbnailSize, PathBuf)>)> {
+    // `src` has no extension, the format is guessed from its content
+    let reader = ImageReader::open(src)?.with_guessed_format()?;
+    if exists {
+        let (width, height) = reader.into_dimensions()?;
+        return Ok((ImageInfo { width, height }, vec![]));
+    }
+
+    let img = reader.decode()?;
+    let info = ImageInfo {
+        width: img.width(),
+        height: img.height(),
+    };
+
+    let mut thumbnails = Vec::with_capacity(ThumbnailSize::ALL.len());
+    for size in ThumbnailSize::ALL {
+        let side = size.max_side();
+        let thumb = if img.width() > side || img.height() > side {
+            img.thumbnail(side, side)
+        } else {
+            img.clone()
+        };
+        let mut path = src.as_os_str().to_owned();
+        path.push(format!("_{}", size.as_str()));
+        let path = PathBuf::from(path);
+        if jpeg {
+            DynamicImage::ImageRgb8(thumb.to_rgb8()).save_with_format(&path, ImageFormat::Jpeg)
+        } else {
+            thumb.save_with_format(&path, ImageFormat::Png)
+        }
+        .with_context(|| format!("write thumbnail: {:?}", &path))?;
+        thumbnails.push((size, path));
+    }
+    Ok((info, thumbnails))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+    use tokio::fs;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    #[tokio::test]
+    async fn t_generate_thumbnails() {
+        let base = std::env::temp_dir().join(Uuid::now_v7().to_string());
+        fs::create_dir_all(&base).await.unwrap();
+        let storage = LocalStorage::new(&base);
+
+        let src = base.join("src");
+        RgbImage::new(1000, 600)
+            .save_with_format(&src, ImageFormat::Png)
+            .unwrap();
+        let file = ChatFile::new(1, "a.png", b"0123456789");
+        let info = generate(&storage, &file, &src).await.unwrap();
+        assert_eq!(
+            info,
+            Some(ImageInfo {
+                width: 1000,
+                height: 600
+            })
+        );
+
+        let small = base.join(file.thumbnail_key(ThumbnailSize::Small));
+        assert!(small.to_string_lossy().ends_with("_small.png"));
+        assert_eq!(image::image_dimensions(&small).unwrap(), (128, 77));
+        let large = base.join(file.thumbnail_key(ThumbnailSize::Large));
+        assert_eq!(image::image_dimensions(&large).unwrap(), (1000, 600));
+
+        // thumbnails exist, only the dimensions are read
+        let info = generate(&storage, &file, &src).await.unwrap();
+        assert_eq!(info.map(|i| (i.width, i.height)), Some((1000, 600)));
+
+        // not an image
+        let file = ChatFile::new(1, "a.txt", b"0123456789");
+        assert_eq!(generate(&storage, &file, &src).await.unwrap(), None);
+
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+}
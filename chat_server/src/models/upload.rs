@@ -0,0 +1,452 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use axum::body::Bytes;
+use chat_core::RowID;
+use chrono::{DateTime, Duration, Utc};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use sqlx::{prelude::FromRow, PgPool};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    time,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{
+    file::{ChatFile, TMP_DIR},
+    thumbnail::ImageInfo,
+};
+use crate::{
+    error::{AppError, AppResult},
+    storage::Storage,
+};
+
+/// Incomplete sessions are removed after this many hours
+pub const UPLOAD_TTL_HOURS: i64 = 24;
+pub const MAX_UPLOAD_SIZE: i64 = 4 * 1024 * 1024 * 1024;
+const UPLOAD_DIR: &str = "uploads";
+/// A chunk holds its session for this long, the lease is renewed while the body comes in
+const LEASE_SECS: f64 = 30.0;
+const LEASE_RENEWAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct UploadSession {
+    pub id: String,
+    pub ws_id: RowID,
+    pub user_id: RowID,
+    pub filename: String,
+    pub size: i64,
+    pub received: i64,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateUpload {
+    pub filename: String,
+    pub size: i64,
+}
+
+pub async fn create(
+    pool: &PgPool,
+    base_dir: &Path,
+    ws_id: RowID,
+    uid: RowID,
+    input: CreateUpload,
+) -> AppResult<UploadSession> {
+    if input.filename.is_empty() || input.filename.contains('/') {
+        return Err(AppError::invalid_input("invalid filename"));
+    }
+    if input.size <= 0 || input.size > MAX_UPLOAD_SIZE {
+        return Err(AppError::invalid_input("invalid upload size"));
+    }
+
+    let id = Uuid::now_v7().to_string();
+    let path = data_path(base_dir, &id);
+    fs::create_dir_all(path.parent().unwrap_or(base_dir))
+        .await
+        .with_context(|| format!("create dir: {:?}", &path))?;
+    fs::File::create(&path)
+        .await
+        .with_context(|| format!("create file: {:?}", &path))?;
+
+    let session = sqlx::query_as(
+        r#"
+            INSERT INTO uploads (id, ws_id, user_id, filename, size, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *
+        "#,
+    )
+    .bind(id)
+    .bind(ws_id)
+    .bind(uid)
+    .bind(input.filename)
+    .bind(input.size)
+    .bind(Utc::now() + Duration::hours(UPLOAD_TTL_HOURS))
+    .fetch_one(pool)
+    .await?;
+
+    Ok(session)
+}
+
+/// Find an unexpired session owned by the user
+pub async fn get(pool: &PgPool, id: &str, uid: RowID) -> AppResult<UploadSession> {
+    let session = sqlx::query_as(
+        "SELECT * FROM uploads WHERE id = $1 AND user_id = $2 AND expires_at > NOW()",
+    )
+    .bind(id)
+    .bind(uid)
+    .fetch_optional(pool)
+    .await?;
+    session.ok_or_else(|| AppError::not_found("upload not found"))
+}
+
+/// Append a chunk starting at `offset`, which must equal the bytes received so far.
+/// The chunk leases the session while its body is streamed, so concurrent requests are
+/// rejected without holding a connection. If the body fails, the bytes written so far are
+/// recorded and the client can resume from them; if the request is dropped, nothing past the
+/// last recorded offset is kept.
+pub async fn append<S, E>(
+    pool: &PgPool,
+    base_dir: &Path,
+    session: &UploadSession,
+    offset: i64,
+    mut chunks: S,
+) -> AppResult<UploadSession>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<anyhow::Error>,
+{
+    let lease = Lease::acquire(pool, &session.id, offset).await?;
+
+    let path = data_path(base_dir, &session.id);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .await
+        .with_context(|| format!("open file: {:?}", &path))?;
+    // drop anything written after the last recorded offset
+    file.set_len(offset as u64).await?;
+    file.seek(io::SeekFrom::Start(offset as u64)).await?;
+
+    let mut renew = time::interval(LEASE_RENEWAL);
+    renew.tick().await;
+    let mut received = offset;
+    let mut ret = Ok(());
+    loop {
+        let chunk = tokio::select! {
+            chunk = chunks.next() => chunk,
+            _ = renew.tick() => {
+                if let Err(e) = lease.renew().await {
+                    ret = Err(e);
+                    break;
+                }
+                continue;
+            }
+        };
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => {
+                ret = Err(AppError::any(e));
+                break;
+            }
+            None => break,
+        };
+        if received + chunk.len() as i64 > session.size {
+            ret = Err(AppError::invalid_input("chunk exceeds upload size"));
+            break;
+        }
+        if let Err(e) = file.write_all(&chunk).await {
+            ret = Err(AppError::any(e));
+            break;
+        }
+        received += chunk.len() as i64;
+    }
+    file.flush().await?;
+    file.set_len(received as u64).await?;
+
+    let updated = lease.release(received).await?;
+    ret.map(|_| updated)
+}
+
+/// The right of a request to write the data of a session, it is given up when the request
+/// is dropped
+struct Lease {
+    pool: PgPool,
+    session_id: String,
+    id: String,
+    offset: i64,
+    released: bool,
+}
+
+impl Lease {
+    /// Take the session if it is at `offset` and nobody else holds it
+    async fn acquire(pool: &PgPool, session_id: &str, offset: i64) -> AppResult<Self> {
+        let id = Uuid::now_v7().to_string();
+        let ret = sqlx::query(
+            r#"
+            UPDATE uploads SET lease_id = $3, lease_until = NOW() + make_interval(secs => $4)
+            WHERE id = $1 AND received = $2 AND (lease_until IS NULL OR lease_until <= NOW())
+            "#,
+        )
+        .bind(session_id)
+        .bind(offset)
+        .bind(&id)
+        .bind(LEASE_SECS)
+        .execute(pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            let received: Option<i64> =
+                sqlx::query_scalar("SELECT received FROM uploads WHERE id = $1")
+                    .bind(session_id)
+                    .fetch_optional(pool)
+                    .await?;
+            return Err(match received {
+                None => AppError::not_found("upload not found"),
+                Some(expected) if expected != offset => {
+                    AppError::conflict(format!("offset mismatch, expect {}", expected), expected)
+                }
+                Some(expected) => AppError::conflict("upload in progress", expected),
+            });
+        }
+        Ok(Self {
+            pool: pool.clone(),
+            session_id: session_id.to_string(),
+            id,
+            offset,
+            released: false,
+        })
+    }
+
+    async fn renew(&self) -> AppResult<()> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE uploads SET lease_until = NOW() + make_interval(secs => $3)
+            WHERE id = $1 AND lease_id = $2
+            "#,
+        )
+        .bind(&self.session_id)
+        .bind(&self.id)
+        .bind(LEASE_SECS)
+        .execute(&self.pool)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::conflict("upload lease lost", self.offset));
+        }
+        Ok(())
+    }
+
+    /// Record the bytes received, only if the session is still where the lease found it
+    async fn release(mut self, received: i64) -> AppResult<UploadSession> {
+        self.released = true;
+        let updated = sqlx::query_as(
+            r#"
+            UPDATE uploads SET received = $4, lease_id = NULL, lease_until = NULL
+            WHERE id = $1 AND lease_id = $2 AND received = $3
+            RETURNING *
+            "#,
+        )
+        .bind(&self.session_id)
+        .bind(&self.id)
+        .bind(self.offset)
+        .bind(received)
+        .fetch_optional(&self.pool)
+        .await?;
+        updated.ok_or_else(|| AppError::conflict("upload lease lost", self.offset))
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        let (pool, session_id, id) = (self.pool.clone(), self.session_id.clone(), self.id.clone());
+        tokio::spawn(async move {
+            let ret = sqlx::query(
+                "UPDATE uploads SET lease_id = NULL, lease_until = NULL WHERE id = $1 AND lease_id = $2",
+            )
+            .bind(&session_id)
+            .bind(&id)
+            .execute(&pool)
+            .await;
+            if let Err(e) = ret {
+                warn!("failed to release upload {}: {}", session_id, e);
+            }
+        });
+    }
+}
+
+/// Move a completed upload into the hashed file layout and drop the session
+pub async fn finish(
+    pool: &PgPool,
+    base_dir: &Path,
+    storage: &dyn Storage,
+    session: UploadSession,
+) -> AppResult<(ChatFile, Option<ImageInfo>)> {
+    if session.received != session.size {
+        return Err(AppError::invalid_input("upload is incomplete"));
+    }
+
+    let path = data_path(base_dir, &session.id);
+    let digest = hash_file(&path).await?;
+    let chat_file = ChatFile::new(session.ws_id, &session.filename, &digest);
+    let image = chat_file.store(storage, &path).await?;
+
+    sqlx::query("DELETE FROM uploads WHERE id = $1")
+        .bind(&session.id)
+        .execute(pool)
+        .await?;
+
+    Ok((chat_file, image))
+}
+
+/// Remove expired sessions and their data, return the number of removed sessions
+pub async fn cleanup_expired(pool: &PgPool, base_dir: &Path) -> AppResult<usize> {
+    let ids: Vec<(String,)> =
+        sqlx::query_as("DELETE FROM uploads WHERE expires_at <= NOW() RETURNING id")
+            .fetch_all(pool)
+            .await?;
+
+    for (id,) in &ids {
+        let path = data_path(base_dir, id);
+        match fs::remove_file(&path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(AppError::any(e));
+            }
+            _ => {}
+        }
+    }
+    Ok(ids.len())
+}
+
+fn data_path(base_dir: &Path, id: &str) -> PathBuf {
+    base_dir.join(TMP_DIR).join(UPLOAD_DIR).join(id)
+}
+
+async fn hash_file(path: &Path) -> AppResult<Vec<u8>> {
+    let mut file = fs::File::open(path)
+        .await
+        .with_context(|| format!("open file: {:?}", path))?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::stream;
+
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    fn chunks(data: &[&'static [u8]]) -> impl Stream<Item = Result<Bytes, io::Error>> + Unpin {
+        stream::iter(
+            data.iter()
+                .map(|d| Ok(Bytes::from_static(d)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[sqlx::test(migrator = "crate::tests::MIGRATOR")]
+    async fn t_resumable_upload(pool: PgPool) {
+        let base = std::env::temp_dir().join(Uuid::now_v7().to_string());
+        let storage = LocalStorage::new(&base);
+        let input = CreateUpload {
+            filename: "a.txt".to_string(),
+            size: 11,
+        };
+        let session = create(&pool, &base, 1, 1, input).await.unwrap();
+        assert_eq!(session.received, 0);
+
+        // other users can't see the session
+        assert!(get(&pool, &session.id, 2).await.is_err());
+
+        // first chunk
+        let session = append(&pool, &base, &session, 0, chunks(&[b"hello"]))
+            .await
+            .unwrap();
+        assert_eq!(session.received, 5);
+
+        // wrong offset is rejected with the expected one
+        let ret = append(&pool, &base, &session, 0, chunks(&[b"hello"])).await;
+        assert!(matches!(ret, Err(AppError::Conflict { offset: 5, .. })));
+
+        // incomplete upload can't be finished
+        let session = get(&pool, &session.id, 1).await.unwrap();
+        assert!(finish(&pool, &base, &storage, session.clone())
+            .await
+            .is_err());
+
+        // a dropped request gives the session back
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(Ok::<_, io::Error>(Bytes::from_static(b" WOR")))
+            .unwrap();
+        let dropped = tokio::spawn({
+            let (pool, base, session) = (pool.clone(), base.clone(), session.clone());
+            async move { append(&pool, &base, &session, 5, rx).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        dropped.abort();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // resume while a concurrent request is rejected right away
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        tx.unbounded_send(Ok::<_, io::Error>(Bytes::from_static(b" wor")))
+            .unwrap();
+        let first = tokio::spawn({
+            let (pool, base, session) = (pool.clone(), base.clone(), session.clone());
+            async move { append(&pool, &base, &session, 5, rx).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let ret = append(&pool, &base, &session, 5, chunks(&[b"WORLD!"])).await;
+        assert!(matches!(ret, Err(AppError::Conflict { offset: 5, .. })));
+        tx.unbounded_send(Ok(Bytes::from_static(b"ld"))).unwrap();
+        drop(tx);
+        let session = first.await.unwrap().unwrap();
+        assert_eq!(session.received, 11);
+        let (file, _) = finish(&pool, &base, &storage, session.clone())
+            .await
+            .unwrap();
+        assert_eq!(file.hash, hex::encode(Sha1::digest(b"hello world")));
+        assert!(base.join(file.key()).exists());
+        assert!(get(&pool, &session.id, 1).await.is_err());
+
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+
+    #[sqlx::test(migrator = "crate::tests::MIGRATOR")]
+    async fn t_cleanup_expired(pool: PgPool) {
+        let base = std::env::temp_dir().join(Uuid::now_v7().to_string());
+        let input = CreateUpload {
+            filename: "a.txt".to_string(),
+            size: 10,
+        };
+        let session = create(&pool, &base, 1, 1, input).await.unwrap();
+        assert_eq!(cleanup_expired(&pool, &base).await.unwrap(), 0);
+
+        sqlx::query("UPDATE uploads SET expires_at = NOW() WHERE id = $1")
+            .bind(&session.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(cleanup_expired(&pool, &base).await.unwrap(), 1);
+        assert!(!data_path(&base, &session.id).exists());
+
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+}
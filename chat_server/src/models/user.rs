@@ -11,9 +11,22 @@ use serde::Deserialize;
 use crate::error::AppError;
 
 pub async fn create(pool: &sqlx::PgPool, input: &CreateUser) -> Result<User, AppError> {
+    // the address ends up in mail commands and headers
+    let valid = input
+        .email
+        .split_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+        && !input
+            .email
+            .chars()
+            .any(|c| c.is_control() || c.is_whitespace() || matches!(c, '<' | '>'));
+    if !valid {
+        return Err(AppError::invalid_input("invalid email"));
+    }
+
     let user: Option<User> = sqlx::query_as(
         r#"
-        SELECT id, fullname, email, password_hash, ws_id, created_at
+        SELECT id, fullname, email, password_hash, ws_id, is_bot, created_at
         FROM users WHERE email = $1
         "#,
     )
@@ -31,7 +44,7 @@ pub async fn create(pool: &sqlx::PgPool, input: &CreateUser) -> Result<User, App
     let user = sqlx::query_as(
         r#"
         INSERT INTO users (fullname, email, password_hash, ws_id) VALUES ($1, $2, $3, 0)
-        RETURNING id, fullname, email, password_hash, ws_id, created_at"#,
+        RETURNING id, fullname, email, password_hash, ws_id, is_bot, created_at"#,
     )
     .bind(&input.fullname)
     .bind(&input.email)
@@ -47,7 +60,8 @@ pub async fn create(pool: &sqlx::PgPool, input: &CreateUser) -> Result<User, App
 pub async fn signin(pool: &sqlx::PgPool, input: &SigninInput) -> Result<Option<User>, AppError> {
     let user: Option<User> = sqlx::query_as(
         r#"
-        SELECT id, fullname, email, password_hash, ws_id, created_at FROM users WHERE email = $1
+        SELECT id, fullname, email, password_hash, ws_id, is_bot, created_at FROM users
+        WHERE email = $1 AND NOT is_bot
         "#,
     )
     .bind(&input.email)
@@ -120,5 +134,15 @@ mod tests {
         assert_eq!(user.fullname, input.fullname);
         assert_eq!(user.email, input.email);
         assert!(verify_password(&input.password, &user.password_hash).unwrap());
+
+        for email in ["b.com", "c@a.com\r\nBcc: d@a.com", "<c@a.com>"] {
+            let input = CreateUser {
+                fullname: "test".to_string(),
+                email: email.to_string(),
+                password: "123456".to_string(),
+            };
+            let ret = models::user::create(&pool, &input).await;
+            assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+        }
     }
 }
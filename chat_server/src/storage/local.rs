@@ -0,0 +1,93 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use super::{ByteStream, Storage};
+
+/// Files are kept under `base_dir`, the key is the relative path
+pub struct LocalStorage {
+    base_dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            base_dir: base_dir.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn size(&self, key: &str) -> anyhow::Result<Option<u64>> {
+        match fs::metadata(self.base_dir.join(key)).await {
+            Ok(meta) => Ok(Some(meta.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put_file(&self, key: &str, src: &Path) -> anyhow::Result<()> {
+        let path = self.base_dir.join(key);
+        fs::create_dir_all(path.parent().unwrap_or(&self.base_dir))
+            .await
+            .with_context(|| format!("create dir: {:?}", &path))?;
+        fs::rename(src, &path)
+            .await
+            .with_context(|| format!("move file: {:?}", &path))?;
+        Ok(())
+    }
+
+    async fn read(&self, key: &str, offset: u64, len: u64) -> anyhow::Result<ByteStream> {
+        let path = self.base_dir.join(key);
+        let mut file = fs::File::open(&path)
+            .await
+            .with_context(|| format!("open file: {:?}", &path))?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(ReaderStream::new(file.take(len)).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn t_local_storage() {
+        let base = std::env::temp_dir().join(Uuid::now_v7().to_string());
+        let storage = LocalStorage::new(&base);
+        assert_eq!(storage.size("1/a.txt").await.unwrap(), None);
+
+        fs::create_dir_all(&base).await.unwrap();
+        let src = base.join("src");
+        fs::write(&src, b"hello world").await.unwrap();
+        storage.put_file("1/a.txt", &src).await.unwrap();
+        assert!(!src.exists());
+        assert_eq!(storage.size("1/a.txt").await.unwrap(), Some(11));
+
+        let data = storage
+            .read("1/a.txt", 6, 5)
+            .await
+            .unwrap()
+            .map_ok(|b| b.to_vec())
+            .try_concat()
+            .await
+            .unwrap();
+        assert_eq!(data, b"world");
+
+        fs::remove_dir_all(&base).await.unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+mod local;
+mod s3;
+
+use std::{io, path::Path};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+
+use crate::config::{AppConfig, StorageConfig};
+
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+/// Where uploaded files are kept, addressed by `ChatFile::key`
+#[async_trait]
+pub trait Storage: Send + Sync + 'static {
+    /// Size of the object, `None` if it doesn't exist
+    async fn size(&self, key: &str) -> anyhow::Result<Option<u64>>;
+
+    /// Move the local file `src` into the storage, `src` is consumed
+    async fn put_file(&self, key: &str, src: &Path) -> anyhow::Result<()>;
+
+    /// Read `len` bytes of the object starting at `offset`
+    async fn read(&self, key: &str, offset: u64, len: u64) -> anyhow::Result<ByteStream>;
+
+    /// A time limited url the client can download from directly, bypassing chat_server.
+    /// The response carries `content_disposition` instead of the one stored with the object.
+    fn presign(&self, _key: &str, _content_disposition: &str) -> Option<String> {
+        None
+    }
+}
+
+pub fn from_config(config: &AppConfig) -> anyhow::Result<Box<dyn Storage>> {
+    let storage: Box<dyn Storage> = match &config.storage {
+        StorageConfig::Local => Box::new(LocalStorage::new(&config.base_dir)),
+        StorageConfig::S3(s3) => Box::new(S3Storage::try_new(s3)?),
+    };
+    Ok(storage)
+}
@@ -1,199 +1,300 @@
-use core::panic;
+use std::time::Duration;
 
-use chat_core::{Chat, ChatType, Message};
-use chat_server::AppState;
+use bytes::Bytes;
+use chat_client::{
+    ChatClient, ClientError, ClientResult, CreateApiKey, CreateChat, CreateMessage, CreateUpload,
+    EventOptions, ListMessages, UploadFile,
+};
+use chat_core::{
+    notify::{EventEnvelope, NotifyEvent},
+    utils::ApiScope,
+    ChatType,
+};
 use futures::StreamExt;
 use notify_server::{NotifyConfig, NotifyState};
-use reqwest_eventsource::{Event, EventSource};
-use serde_json::json;
-use tokio::net::TcpListener;
+use reqwest::StatusCode;
+use serde_json::Value;
+use sqlx::PgPool;
+use tokio::{net::TcpListener, sync::mpsc, time};
 
 const PUBLIC_KEY: &str = include_str!("../../fixtures/public.pem");
-const CHAT_SERVER_PORT: u16 = 8080;
-const NOTIFY_SERVER_PORT: u16 = 8081;
-
-struct ChatServer {
-    token: String,
-    http_client: reqwest::Client,
-}
-
-#[allow(unused)]
-struct NotifyServer;
 
 #[sqlx::test(
     migrator = "chat_server::tests::MIGRATOR",
     fixtures("../../fixtures/test.sql")
 )]
-async fn t_chat_server(pool: sqlx::PgPool) {
-    let state = chat_server::AppState::new_for_test(pool);
-    let mut chat = ChatServer::try_new(state).await.unwrap();
-    chat.signin().await.unwrap();
+async fn t_chat_server(pool: PgPool) {
+    let chat_url = start_chat_server(pool.clone()).await;
+    let notify_url = start_notify_server(pool).await;
+    let client = ChatClient::new(chat_url, &notify_url);
+    client.signin("user-1@a.com", "123456").await.unwrap();
+
+    let options = EventOptions {
+        types: vec!["NewChat".to_string(), "NewMessage".to_string()],
+        ..Default::default()
+    };
+    let mut events = spawn_events(&client, options);
+    wait_connected(&client, &notify_url).await;
 
-    let notify_config = NotifyServer::new_config();
-    let notify_state = NotifyState::try_new(notify_config).unwrap();
-    let _notify = NotifyServer::try_new(notify_state, &chat.token)
+    let input = CreateChat {
+        name: Some("ig-chat".to_string()),
+        members: vec![1, 2, 3],
+        public: true,
+    };
+    let chat = client.create_chat(&input).await.unwrap();
+    let NotifyEvent::NewChat(event) = next_event(&mut events).await.event else {
+        panic!("expect NewChat");
+    };
+    assert_eq!(event.id, chat.id);
+    assert_eq!(event.name.as_deref(), Some("ig-chat"));
+    assert_eq!(event.members, vec![1, 2, 3]);
+    assert_eq!(event.typ, ChatType::PublicChannel);
+
+    let input = CreateMessage {
+        content: "hello".to_string(),
+        ..Default::default()
+    };
+    client.send_message(1, &input).await.unwrap();
+    let NotifyEvent::NewMessage(message) = next_event(&mut events).await.event else {
+        panic!("expect NewMessage");
+    };
+    assert_eq!(message.content, "hello");
+
+    let messages = client
+        .list_messages(
+            1,
+            &ListMessages {
+                last_id: None,
+                limit: 1,
+            },
+        )
         .await
         .unwrap();
+    assert_eq!(messages[0].id, message.id);
 
-    chat.create_chat().await;
-    chat.create_message().await;
+    let ret = client.get_chat(1000).await;
+    assert!(matches!(ret, Err(e) if e.status() == Some(StatusCode::FORBIDDEN)));
 }
 
-impl ChatServer {
-    async fn try_new(state: AppState) -> anyhow::Result<Self> {
-        let router = chat_server::get_router(state)
-            .await
-            .expect("get router failed");
+#[sqlx::test(
+    migrator = "chat_server::tests::MIGRATOR",
+    fixtures("../../fixtures/test.sql")
+)]
+async fn t_client_refresh_token(pool: PgPool) {
+    let chat_url = start_chat_server(pool).await;
+    let client = ChatClient::new(chat_url, "http://127.0.0.1:1");
+    let ret = client.list_users().await;
+    assert!(matches!(ret, Err(ClientError::Unauthenticated)));
 
-        let addr = format!("0.0.0.0:{}", CHAT_SERVER_PORT);
-        let ls = TcpListener::bind(addr)
-            .await
-            .expect("bind tcp listener failed");
+    client.signin("user-1@a.com", "123456").await.unwrap();
+    // a rejected token is replaced by signing in again
+    client.set_token("expired").await;
+    let users = client.list_users().await.unwrap();
+    assert_eq!(users.len(), 2);
+    assert_ne!(client.token().await.unwrap(), "expired");
 
-        let server = axum::serve(ls, router);
-        tokio::spawn(async move { server.await.expect("chat server exist with error") });
+    // multipart bodies are rebuilt for the retry
+    client.set_token("expired").await;
+    let file = UploadFile {
+        filename: "a.txt".to_string(),
+        data: Bytes::from_static(b"hello"),
+    };
+    let urls = client.upload(&[file]).await.unwrap();
+    let content = client.download(&urls[0], None).await.unwrap();
+    assert_eq!(content.as_ref(), b"hello");
+    let meta = client.file_meta(&urls[0]).await.unwrap();
+    assert_eq!(meta.filename, "a.txt");
 
-        let http_client = reqwest::Client::new();
+    let input = CreateUpload {
+        filename: "b.txt".to_string(),
+        size: 6,
+    };
+    let session = client.create_upload(&input).await.unwrap();
+    let session = client
+        .append_upload(&session.id, 0, Bytes::from_static(b"abc"))
+        .await
+        .unwrap();
+    let session = client
+        .append_upload(&session.id, session.received, Bytes::from_static(b"def"))
+        .await
+        .unwrap();
+    let url = client.finish_upload(&session.id).await.unwrap();
+    let content = client.download(&url, None).await.unwrap();
+    assert_eq!(content.as_ref(), b"abcdef");
+}
 
-        let chat = Self {
-            token: Default::default(),
-            http_client,
-        };
+#[sqlx::test(
+    migrator = "chat_server::tests::MIGRATOR",
+    fixtures("../../fixtures/test.sql")
+)]
+async fn t_chat_members(pool: PgPool) {
+    let uid: i64 = sqlx::query_scalar(
+        r#"
+        INSERT INTO users (fullname, email, password_hash, ws_id)
+        SELECT 'user-6', 'user-6@a.com', password_hash, 1 FROM users WHERE id = 1
+        RETURNING id
+        "#,
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    let chat_url = start_chat_server(pool.clone()).await;
+    let notify_url = start_notify_server(pool).await;
+    let owner = ChatClient::new(&chat_url, &notify_url);
+    owner.signin("user-1@a.com", "123456").await.unwrap();
+    let member = ChatClient::new(&chat_url, &notify_url);
+    member.signin("user-6@a.com", "123456").await.unwrap();
 
-        Ok(chat)
-    }
+    let options = EventOptions {
+        types: vec!["MemberAdded".to_string(), "RemovedFromChat".to_string()],
+        ..Default::default()
+    };
+    let mut events = spawn_events(&member, options);
+    wait_connected(&owner, &notify_url).await;
 
-    async fn signin(&mut self) -> anyhow::Result<()> {
-        let url = format!("http://localhost:{CHAT_SERVER_PORT}/signin");
-        let body = json!(
-            {
-                "email": "user-1@a.com",
-                "password": "123456"
-            }
-        );
-        let resp = self.http_client.post(url).json(&body).send().await?;
-        assert!(resp.status().is_success());
-        let body = resp.json::<serde_json::Value>().await.unwrap();
-        let token = body
-            .as_object()
-            .unwrap()
-            .get("token")
-            .unwrap()
-            .as_str()
-            .unwrap();
-        self.token = token.to_string();
-        Ok(())
-    }
+    let input = CreateChat {
+        name: Some("team".to_string()),
+        members: vec![1, 2],
+        public: false,
+    };
+    let chat = owner.create_chat(&input).await.unwrap();
+    owner.add_members(chat.id, &[uid]).await.unwrap();
+    let NotifyEvent::MemberAdded(change) = next_event(&mut events).await.event else {
+        panic!("expect MemberAdded");
+    };
+    assert_eq!((change.actor, change.chat.id), (1, chat.id));
+    assert_eq!(change.members, vec![uid]);
+    assert_eq!(change.chat.members, vec![1, 2, uid]);
+    member.get_chat(chat.id).await.unwrap();
 
-    async fn create_chat(&self) {
-        let url = format!("http://localhost:{CHAT_SERVER_PORT}/api/chat");
-        let body = json!(
-            {
-                "name": "ig-chat",
-                "members": [1, 2, 3],
-                "public": true,
-            }
-        );
-        let resp = self
-            .http_client
-            .post(url)
-            .bearer_auth(&self.token)
-            .json(&body)
-            .send()
-            .await
-            .unwrap();
-        assert!(resp.status().is_success());
-    }
+    owner.remove_members(chat.id, &[uid]).await.unwrap();
+    let NotifyEvent::RemovedFromChat(change) = next_event(&mut events).await.event else {
+        panic!("expect RemovedFromChat");
+    };
+    assert_eq!((change.actor, change.chat.id), (1, chat.id));
+    assert_eq!(change.members, vec![uid]);
+    let ret = member.get_chat(chat.id).await;
+    assert!(matches!(ret, Err(e) if e.status() == Some(StatusCode::FORBIDDEN)));
+}
 
-    async fn create_message(&self) {
-        let url = format!("http://localhost:{CHAT_SERVER_PORT}/api/chat/1/message");
-        let body = json!(
-            {
-                "content": "hello"
+/// The stream connects when polled, it is driven before anything is sent
+fn spawn_events(
+    client: &ChatClient,
+    options: EventOptions,
+) -> mpsc::UnboundedReceiver<ClientResult<EventEnvelope>> {
+    let (tx, events) = mpsc::unbounded_channel();
+    let stream = client.events(options);
+    tokio::spawn(async move {
+        let mut stream = Box::pin(stream);
+        while let Some(event) = stream.next().await {
+            if tx.send(event).is_err() {
+                break;
             }
-        );
-        let resp = self
-            .http_client
-            .put(url)
-            .bearer_auth(&self.token)
-            .json(&body)
-            .send()
-            .await
-            .unwrap();
-        assert!(resp.status().is_success());
-    }
+        }
+    });
+    events
 }
 
-impl NotifyServer {
-    async fn try_new(state: NotifyState, token: &str) -> anyhow::Result<Self> {
-        let router = notify_server::get_router(state)
-            .await
-            .expect("get router failed");
+#[sqlx::test(
+    migrator = "chat_server::tests::MIGRATOR",
+    fixtures("../../fixtures/test.sql")
+)]
+async fn t_bot_client(pool: PgPool) {
+    let chat_url = start_chat_server(pool).await;
+    let owner = ChatClient::new(&chat_url, "http://127.0.0.1:1");
+    owner.signin("user-1@a.com", "123456").await.unwrap();
 
-        let addr = format!("0.0.0.0:{NOTIFY_SERVER_PORT}");
-        let ls = TcpListener::bind(addr)
-            .await
-            .expect("bind tcp listener failed");
-
-        let server = axum::serve(ls, router);
-        tokio::spawn(async move { server.await.expect("notify server exist with error") });
-
-        let token = token.to_string();
-        tokio::spawn(async move {
-            let mut es = EventSource::get(format!(
-                "http://localhost:{NOTIFY_SERVER_PORT}/events?access_token={token}"
-            ));
-
-            let client = reqwest::Client::new();
-
-            let resp = client
-                .get(format!(
-                    "http://localhost:{NOTIFY_SERVER_PORT}/events?access_token={token}"
-                ))
-                .send()
-                .await
-                .unwrap();
-            assert!(resp.status().is_success());
-
-            while let Some(event) = es.next().await {
-                match event {
-                    Ok(Event::Open) => println!("event source connection open!"),
-                    Ok(Event::Message(msg)) => match msg.event.as_str() {
-                        "NewChat" => {
-                            let chat = serde_json::from_str::<Chat>(&msg.data).unwrap();
-                            assert_eq!(chat.name.as_ref().unwrap(), "ig-test");
-                            assert_eq!(chat.members, vec![1, 2, 3]);
-                            matches!(chat.typ, ChatType::PublicChannel);
-                        }
-                        "NewMessage" => {
-                            let message = serde_json::from_str::<Message>(&msg.data).unwrap();
-                            assert_eq!(message.content.as_str(), "hello");
-                        }
-                        _ => {
-                            panic!("unknown event: {}", msg.event.as_str());
-                        }
-                    },
-                    Err(e) => {
-                        es.close();
-                        println!("event source error: {}", e);
-                    }
-                }
-            }
-        });
+    let bot = owner.create_bot("ci").await.unwrap();
+    assert!(bot.is_bot);
+    assert_eq!(owner.list_bots().await.unwrap().len(), 1);
+    owner.add_members(1, &[bot.id]).await.unwrap();
+    let input = CreateApiKey {
+        name: "deploy".to_string(),
+        scopes: vec![ApiScope::Read, ApiScope::Write],
+        expires_at: None,
+    };
+    let key = owner.create_api_key(bot.id, &input).await.unwrap();
+    assert_eq!(key.api_key.scopes, input.scopes);
 
-        Ok(Self)
-    }
+    // the bot uses the key as its token
+    let client = ChatClient::new(&chat_url, "http://127.0.0.1:1");
+    client.set_token(&key.key).await;
+    let input = CreateMessage {
+        content: "deployed".to_string(),
+        ..Default::default()
+    };
+    let message = client.send_message(1, &input).await.unwrap();
+    assert_eq!(message.sender_id, bot.id);
 
-    fn new_config() -> NotifyConfig {
-        NotifyConfig {
-            server: notify_server::config::ServerConfig {
-                db_url: Default::default(),
-                host: "0.0.0.0".to_string(),
-                port: NOTIFY_SERVER_PORT,
-            },
-            auth: notify_server::config::AuthConfig {
-                pk: PUBLIC_KEY.to_string(),
-            },
+    owner.revoke_api_key(bot.id, key.api_key.id).await.unwrap();
+    let keys = owner.list_api_keys(bot.id).await.unwrap();
+    assert!(keys[0].revoked_at.is_some());
+    let ret = client.get_chat(1).await;
+    assert!(matches!(ret, Err(e) if e.is_token_rejected()));
+}
+
+async fn next_event(
+    events: &mut mpsc::UnboundedReceiver<ClientResult<EventEnvelope>>,
+) -> EventEnvelope {
+    time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("no event in time")
+        .unwrap()
+        .unwrap()
+}
+
+/// The stats are only for the workspace owner
+async fn wait_connected(owner: &ChatClient, notify_url: &str) {
+    let token = owner.token().await.unwrap();
+    for _ in 0..50 {
+        let stats: Value = reqwest::Client::new()
+            .get(format!("{}/stats", notify_url))
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        if stats["connections"] == 1 {
+            return;
         }
+        time::sleep(Duration::from_millis(100)).await;
     }
+    panic!("the event stream didn't connect");
+}
+
+async fn start_chat_server(pool: PgPool) -> String {
+    let state = chat_server::AppState::new_for_test(pool);
+    let router = chat_server::get_router(state).await.unwrap();
+    let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = ls.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(ls, router).await.unwrap() });
+    format!("http://{}", addr)
+}
+
+async fn start_notify_server(pool: PgPool) -> String {
+    let config = NotifyConfig {
+        server: notify_server::config::ServerConfig {
+            db_url: Default::default(),
+            host: "127.0.0.1".to_string(),
+            port: 0,
+        },
+        auth: notify_server::config::AuthConfig {
+            pk: PUBLIC_KEY.to_string(),
+        },
+        push: None,
+        digest: None,
+        stream: Default::default(),
+        webhook: Default::default(),
+    };
+    let state = NotifyState::new(config, pool).unwrap();
+    notify_server::setup_pg_listener(state.clone())
+        .await
+        .unwrap();
+    let router = notify_server::get_router(state).await.unwrap();
+    let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = ls.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(ls, router).await.unwrap() });
+    format!("http://{}", addr)
 }
@@ -0,0 +1,224 @@
+use chat_client::{ChatClient, CreateChat, CreateMessage};
+use chat_core::{
+    notify::{EventEnvelope, NotifyEvent},
+    utils::{JwtEncodingKey, UserCliams},
+};
+use futures::{SinkExt, StreamExt};
+use notify_server::{NotifyConfig, NotifyState};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+const PUBLIC_KEY: &str = include_str!("../../fixtures/public.pem");
+const PRIVATE_KEY: &str = include_str!("../../fixtures/private.pem");
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+struct Instance {
+    state: NotifyState,
+    addr: String,
+}
+
+#[sqlx::test(
+    migrator = "chat_server::tests::MIGRATOR",
+    fixtures("../../fixtures/test.sql")
+)]
+async fn t_notify_cluster(pool: PgPool) {
+    let a = Instance::start(pool.clone()).await;
+    let b = Instance::start(pool.clone()).await;
+    let token1 = sign(1);
+    let token2 = sign(2);
+    let chat = start_chat_server(pool.clone(), &token1).await;
+
+    let mut client1 = a.connect(&token1).await;
+    let mut client2 = b.connect(&token2).await;
+
+    // presence and typing are relayed between instances
+    let NotifyEvent::PresenceChanged(presence) = next_event(&mut client1, "PresenceChanged").await
+    else {
+        unreachable!()
+    };
+    assert_eq!(presence.user_id, 2);
+    let typing = json!({ "type": "typing", "chat_id": 1 }).to_string();
+    client1.send(Message::Text(typing)).await.unwrap();
+    let NotifyEvent::Typing(typing) = next_event(&mut client2, "Typing").await else {
+        unreachable!()
+    };
+    assert_eq!(typing.user_id, 1);
+
+    // every instance delivers the events of chat_server to its own users
+    send_message(&chat, "hi").await;
+    for client in [&mut client1, &mut client2] {
+        let NotifyEvent::NewMessage(message) = next_event(client, "NewMessage").await else {
+            unreachable!()
+        };
+        assert_eq!(message.content, "hi");
+    }
+
+    notify_server::sync_registry(&a.state).await.unwrap();
+    notify_server::sync_registry(&b.state).await.unwrap();
+    let cluster = a.cluster(&token1).await;
+    assert_eq!(cluster.len(), 2);
+    assert_eq!(cluster[0]["users"], json!([1]));
+    assert_eq!(cluster[1]["users"], json!([2]));
+    // only the owner of the workspace can see the cluster
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}/cluster", a.addr))
+        .bearer_auth(&token2)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::FORBIDDEN);
+
+    // a drained instance closes its connections and leaves the registry
+    a.state.drain().await.unwrap();
+    loop {
+        match client1.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => {
+                assert_eq!(u16::from(frame.code), 1012);
+                break;
+            }
+            Some(Ok(_)) => continue,
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+    let cluster = b.cluster(&token1).await;
+    assert_eq!(cluster.len(), 1);
+    assert_eq!(cluster[0]["users"], json!([2]));
+}
+
+#[sqlx::test(
+    migrator = "chat_server::tests::MIGRATOR",
+    fixtures("../../fixtures/test.sql")
+)]
+async fn t_large_notifications(pool: PgPool) {
+    let a = Instance::start(pool.clone()).await;
+    let token = sign(1);
+    let chat = start_chat_server(pool.clone(), &token).await;
+    let mut client = a.connect(&token).await;
+
+    // pg_notify payloads are limited to 8000 bytes, notifications only carry event ids
+    let content = "a".repeat(10000);
+    send_message(&chat, &content).await;
+    let NotifyEvent::NewMessage(message) = next_event(&mut client, "NewMessage").await else {
+        unreachable!()
+    };
+    assert_eq!(message.content, content);
+
+    let input = CreateChat {
+        name: Some("large".to_string()),
+        members: vec![1, 2],
+        public: false,
+    };
+    chat.create_chat(&input).await.unwrap();
+    let NotifyEvent::NewChat(new) = next_event(&mut client, "NewChat").await else {
+        unreachable!()
+    };
+    assert_eq!(new.name.as_deref(), Some("large"));
+
+    // updates carry the acting user and the change
+    let uid: i64 = sqlx::query_scalar(
+        "INSERT INTO users (fullname, email, password_hash, ws_id) VALUES ('u', 'u@a.com', '', 1) RETURNING id",
+    )
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    chat.add_members(1, &[uid]).await.unwrap();
+    let NotifyEvent::MemberAdded(change) = next_event(&mut client, "MemberAdded").await else {
+        unreachable!()
+    };
+    assert_eq!(change.chat.id, 1);
+    assert_eq!(change.actor, 1);
+    assert_eq!(change.members, vec![uid]);
+}
+
+/// chat_server appends the events the instances deliver
+async fn start_chat_server(pool: PgPool, token: &str) -> ChatClient {
+    let state = chat_server::AppState::new_for_test(pool);
+    let router = chat_server::get_router(state).await.unwrap();
+    let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = ls.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(ls, router).await.unwrap() });
+    let client = ChatClient::new(format!("http://{}", addr), "");
+    client.set_token(token).await;
+    client
+}
+
+async fn send_message(chat: &ChatClient, content: &str) {
+    let input = CreateMessage {
+        content: content.to_string(),
+        ..Default::default()
+    };
+    chat.send_message(1, &input).await.unwrap();
+}
+
+fn sign(uid: i64) -> String {
+    let ek = JwtEncodingKey::load(PRIVATE_KEY.as_bytes()).unwrap();
+    ek.sign(&UserCliams {
+        uid,
+        ws_id: 1,
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+/// Presence changes arrive whenever the relay catches up, they are skipped unless expected
+async fn next_event(client: &mut Client, name: &str) -> NotifyEvent {
+    loop {
+        let Message::Text(text) = client.next().await.unwrap().unwrap() else {
+            continue;
+        };
+        let envelope = EventEnvelope::decode(&text).unwrap();
+        if envelope.event.name() == name {
+            return envelope.event;
+        }
+        assert_eq!(envelope.event.name(), "PresenceChanged", "unexpected frame");
+    }
+}
+
+impl Instance {
+    async fn start(pool: PgPool) -> Self {
+        let config = NotifyConfig {
+            server: notify_server::config::ServerConfig {
+                db_url: Default::default(),
+                host: "127.0.0.1".to_string(),
+                port: 0,
+            },
+            auth: notify_server::config::AuthConfig {
+                pk: PUBLIC_KEY.to_string(),
+            },
+            push: None,
+            digest: None,
+            stream: Default::default(),
+            webhook: Default::default(),
+        };
+        let state = NotifyState::new(config, pool).unwrap();
+        notify_server::setup_pg_listener(state.clone())
+            .await
+            .unwrap();
+
+        let router = notify_server::get_router(state.clone()).await.unwrap();
+        let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = ls.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(ls, router).await.unwrap() });
+        Self { state, addr }
+    }
+
+    async fn connect(&self, token: &str) -> Client {
+        let url = format!("ws://{}/ws?access_token={}", self.addr, token);
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    async fn cluster(&self, token: &str) -> Vec<Value> {
+        reqwest::Client::new()
+            .get(format!("http://{}/cluster", self.addr))
+            .bearer_auth(token)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+}
@@ -0,0 +1,17 @@
+-- uploaded files, a row per uploader of the same content
+CREATE TABLE IF NOT EXISTS files (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL,
+    uploader_id BIGINT NOT NULL,
+    filename VARCHAR(255) NOT NULL,
+    size BIGINT NOT NULL,
+    mime VARCHAR(128) NOT NULL,
+    hash CHAR(40) NOT NULL,
+    url TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS files_url_uploader_index ON files(url, uploader_id);
+
+-- find messages referencing a file
+CREATE INDEX IF NOT EXISTS messages_files_index ON messages USING GIN(files);
@@ -0,0 +1,3 @@
+-- image dimensions, null for other files
+ALTER TABLE files ADD COLUMN width INT;
+ALTER TABLE files ADD COLUMN height INT;
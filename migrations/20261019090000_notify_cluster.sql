@@ -0,0 +1,18 @@
+-- registry of running notify_server instances and the users connected to them
+CREATE TABLE IF NOT EXISTS notify_instances (
+    id VARCHAR(36) PRIMARY KEY,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    heartbeat_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS notify_connections (
+    instance_id VARCHAR(36) NOT NULL REFERENCES notify_instances(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL,
+    ws_id BIGINT NOT NULL,
+    connections INT NOT NULL,
+    away BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (instance_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS notify_connections_ws_id_index ON notify_connections(ws_id);
+CREATE INDEX IF NOT EXISTS notify_connections_user_id_index ON notify_connections(user_id);
@@ -0,0 +1,56 @@
+-- pg_notify payloads are limited to 8000 bytes, notifications only carry the id of an outbox row
+-- and notify_server loads the rows it refers to
+CREATE TABLE IF NOT EXISTS notify_outbox (
+    id BIGSERIAL PRIMARY KEY,
+    channel VARCHAR(64) NOT NULL,
+    op VARCHAR(16) NOT NULL,
+    row_id BIGINT NOT NULL,
+    -- the row before an update or delete
+    old JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS notify_outbox_created_at_index ON notify_outbox(created_at);
+
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  OUTBOX_ID bigint;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO notify_outbox (channel, op, row_id)
+      VALUES ('chat_updated', TG_OP, NEW.id)
+    RETURNING
+      id INTO OUTBOX_ID;
+  ELSE
+    INSERT INTO notify_outbox (channel, op, row_id, old)
+      VALUES ('chat_updated', TG_OP, OLD.id, to_jsonb(OLD))
+    RETURNING
+      id INTO OUTBOX_ID;
+  END IF;
+  PERFORM
+    pg_notify('chat_updated', OUTBOX_ID::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  OUTBOX_ID bigint;
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    INSERT INTO notify_outbox (channel, op, row_id)
+      VALUES ('chat_message_created', TG_OP, NEW.id)
+    RETURNING
+      id INTO OUTBOX_ID;
+    PERFORM
+      pg_notify('chat_message_created', OUTBOX_ID::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,18 @@
+-- domain events are appended by chat_server in the transaction of the change, replacing the triggers
+DROP TRIGGER IF EXISTS add_to_chat_trigger ON chats;
+DROP TRIGGER IF EXISTS add_to_message_trigger ON messages;
+DROP FUNCTION IF EXISTS add_to_chat();
+DROP FUNCTION IF EXISTS add_to_message();
+DROP TABLE IF EXISTS notify_outbox;
+
+CREATE TABLE IF NOT EXISTS events (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS events_created_at_index ON events(created_at);
+
+-- the last event consumed by the instance
+ALTER TABLE notify_instances ADD COLUMN IF NOT EXISTS last_event_id BIGINT NOT NULL DEFAULT 0;
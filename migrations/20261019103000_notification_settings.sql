@@ -0,0 +1,23 @@
+-- per chat notification settings, messages are still delivered but silent
+CREATE TYPE notify_level AS ENUM (
+    'all',
+    'mentions'
+);
+
+CREATE TABLE IF NOT EXISTS chat_settings (
+    user_id BIGINT NOT NULL,
+    chat_id BIGINT NOT NULL,
+    level notify_level NOT NULL DEFAULT 'all',
+    muted_until TIMESTAMPTZ,
+    PRIMARY KEY (user_id, chat_id)
+);
+
+-- daily do-not-disturb window of a user in the workspace, in local time of the timezone
+CREATE TABLE IF NOT EXISTS dnd_schedules (
+    user_id BIGINT NOT NULL,
+    ws_id BIGINT NOT NULL,
+    start_time TIME NOT NULL,
+    end_time TIME NOT NULL,
+    timezone VARCHAR(64) NOT NULL DEFAULT 'UTC',
+    PRIMARY KEY (user_id, ws_id)
+);
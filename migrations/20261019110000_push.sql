@@ -0,0 +1,25 @@
+-- web push subscriptions of the devices of a user
+CREATE TABLE IF NOT EXISTS push_subscriptions (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    endpoint TEXT NOT NULL,
+    p256dh VARCHAR(128) NOT NULL,
+    auth VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS push_subscriptions_endpoint_index ON push_subscriptions(endpoint);
+CREATE INDEX IF NOT EXISTS push_subscriptions_user_id_index ON push_subscriptions(user_id);
+
+-- a row per event pushed to a subscription, claimed by the instance sending it
+CREATE TABLE IF NOT EXISTS push_deliveries (
+    event_id BIGINT NOT NULL,
+    subscription_id BIGINT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    -- status code of the last response
+    status INT,
+    error TEXT,
+    delivered_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (event_id, subscription_id)
+);
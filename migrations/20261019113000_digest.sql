@@ -0,0 +1,18 @@
+-- when the user was last connected to a notify instance
+CREATE TABLE IF NOT EXISTS user_activity (
+    user_id BIGINT PRIMARY KEY,
+    ws_id BIGINT NOT NULL,
+    last_seen_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- digest emails sent, with the messages they included
+CREATE TABLE IF NOT EXISTS digests (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    ws_id BIGINT NOT NULL,
+    message_ids BIGINT[] NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS digests_user_id_index ON digests(user_id);
+CREATE INDEX IF NOT EXISTS digests_message_ids_index ON digests USING GIN (message_ids);
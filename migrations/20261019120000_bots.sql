@@ -0,0 +1,28 @@
+-- bots belong to a workspace and authenticate with API keys instead of passwords
+ALTER TABLE users ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TYPE api_scope AS ENUM (
+    'read',
+    'write',
+    'events'
+);
+
+-- only the sha256 of a key is stored, the key is shown once on creation
+CREATE TABLE IF NOT EXISTS api_keys (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    ws_id BIGINT NOT NULL,
+    name VARCHAR(64) NOT NULL,
+    key_hash CHAR(64) NOT NULL,
+    -- the beginning of the key, to tell keys apart
+    prefix VARCHAR(16) NOT NULL,
+    scopes api_scope[] NOT NULL,
+    created_by BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMPTZ,
+    last_used_at TIMESTAMPTZ,
+    revoked_at TIMESTAMPTZ
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS api_keys_key_hash_index ON api_keys(key_hash);
+CREATE INDEX IF NOT EXISTS api_keys_user_id_index ON api_keys(user_id);
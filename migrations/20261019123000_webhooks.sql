@@ -0,0 +1,35 @@
+-- endpoints of a workspace receiving chat events, managed by the workspace owner
+CREATE TABLE IF NOT EXISTS webhooks (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL,
+    url TEXT NOT NULL,
+    -- key of the HMAC signature of the deliveries
+    secret VARCHAR(64) NOT NULL,
+    -- names of the events sent, e.g. NewMessage
+    events TEXT[] NOT NULL,
+    -- only events of these chats, all chats if empty
+    chat_ids BIGINT[] NOT NULL DEFAULT '{}',
+    created_by BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS webhooks_ws_id_index ON webhooks(ws_id);
+
+-- a row per event sent to a webhook, claimed by the instance sending it
+CREATE TABLE IF NOT EXISTS webhook_deliveries (
+    id BIGSERIAL PRIMARY KEY,
+    webhook_id BIGINT NOT NULL,
+    event_id BIGINT NOT NULL,
+    -- an event of the outbox can turn into several events, e.g. MemberAdded and ChatUpdated
+    event VARCHAR(32) NOT NULL,
+    -- the body as sent, replays send it again
+    body TEXT NOT NULL,
+    attempts INT NOT NULL DEFAULT 0,
+    -- status code of the last response
+    status INT,
+    error TEXT,
+    delivered_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS webhook_deliveries_event_index ON webhook_deliveries(webhook_id, event_id, event);
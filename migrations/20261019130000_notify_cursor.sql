@@ -0,0 +1,7 @@
+-- progress of notify_server through the events, shared by the instances. Registry rows go away
+-- with their instances, instances starting after an outage resume from here.
+CREATE TABLE IF NOT EXISTS notify_cursor (
+    id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+    last_event_id BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,4 @@
+-- deliveries claimed by an instance that died before sending them are claimed again after a while
+ALTER TABLE webhook_deliveries ADD COLUMN IF NOT EXISTS claimed_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_unsent_index ON webhook_deliveries(claimed_at) WHERE attempts = 0;
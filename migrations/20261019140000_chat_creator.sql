@@ -0,0 +1,3 @@
+-- the creator manages the chat with the owner of the workspace, chats created before are
+-- managed by the owner only
+ALTER TABLE chats ADD COLUMN IF NOT EXISTS created_by BIGINT;
@@ -0,0 +1,3 @@
+-- a chunk holds a short lease on its session while the body is streamed, instead of a row lock
+ALTER TABLE uploads ADD COLUMN IF NOT EXISTS lease_id VARCHAR(36);
+ALTER TABLE uploads ADD COLUMN IF NOT EXISTS lease_until TIMESTAMPTZ;
@@ -0,0 +1,2 @@
+-- finished deliveries are removed after the retention
+CREATE INDEX IF NOT EXISTS webhook_deliveries_created_at_index ON webhook_deliveries(created_at);
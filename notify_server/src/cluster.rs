@@ -0,0 +1,239 @@
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use axum::{extract::State, Extension, Json};
+use chat_core::{notify::NotifyEvent, utils::UserCliams, RowID};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{
+    error::{AppError, AppResult},
+    notify_event::deliver_ephemeral,
+    NotifyState,
+};
+
+/// The registry rows of an instance are refreshed this often
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+/// Instances without a heartbeat for this many seconds are considered gone
+pub const INSTANCE_TTL_SECS: f64 = 30.0;
+/// Ephemeral events are relayed to the other instances through this channel
+pub(crate) const EPHEMERAL_CHANNEL: &str = "notify_ephemeral";
+
+/// Receivers of an ephemeral event, every instance resolves it against its own connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Audience {
+    Users(Vec<RowID>),
+    /// Users of the workspace except the given one
+    Workspace {
+        ws_id: RowID,
+        except: RowID,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EphemeralNotification {
+    instance_id: String,
+    audience: Audience,
+    event: NotifyEvent,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub struct InstanceInfo {
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+    pub heartbeat_at: DateTime<Utc>,
+    pub last_event_id: RowID,
+    pub users: Vec<RowID>,
+}
+
+// GET /cluster, live instances and the users of the workspace connected to them
+pub(crate) async fn cluster_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+) -> AppResult<Json<Vec<InstanceInfo>>> {
+    if !user.is_workspace_owner(&state.db).await? {
+        return Err(AppError::forbidden(
+            "only the workspace owner can see the cluster",
+        ));
+    }
+    let instances = sqlx::query_as(
+        r#"
+        SELECT i.id, i.started_at, i.heartbeat_at, i.last_event_id,
+            COALESCE(array_agg(c.user_id ORDER BY c.user_id) FILTER (WHERE c.user_id IS NOT NULL), '{}') AS users
+        FROM notify_instances i
+        LEFT JOIN notify_connections c ON c.instance_id = i.id AND c.ws_id = $1
+        WHERE i.heartbeat_at > NOW() - make_interval(secs => $2)
+        GROUP BY i.id
+        ORDER BY i.started_at
+        "#,
+    )
+    .bind(user.ws_id)
+    .bind(INSTANCE_TTL_SECS)
+    .fetch_all(&state.db)
+    .await?;
+    Ok(Json(instances))
+}
+
+/// Keep the registry rows of the instance up to date until it is drained
+pub fn setup_registry(state: NotifyState) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.shutdown.cancelled() => break,
+            }
+            if let Err(e) = sync_registry(&state).await {
+                warn!("failed to sync registry: {:#}", e);
+            }
+        }
+    });
+}
+
+/// Register the instance with its progress through the events and replace its connections in the registry
+pub async fn sync_registry(state: &NotifyState) -> anyhow::Result<()> {
+    let mut uids = vec![];
+    let mut ws_ids = vec![];
+    let mut connections = vec![];
+    let mut aways = vec![];
+    for kv in state.presence.iter().filter(|kv| kv.connections > 0) {
+        uids.push(*kv.key());
+        ws_ids.push(kv.ws_id);
+        connections.push(kv.connections as i32);
+        aways.push(kv.away);
+    }
+
+    let mut tx = state.db.begin().await?;
+    sqlx::query(
+        r#"
+        INSERT INTO notify_instances (id, last_event_id) VALUES ($1, $2)
+        ON CONFLICT (id) DO UPDATE SET heartbeat_at = NOW(), last_event_id = $2
+        "#,
+    )
+    .bind(&state.instance_id)
+    .bind(state.event_cursor.load(Ordering::Relaxed))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM notify_connections WHERE instance_id = $1")
+        .bind(&state.instance_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"
+        INSERT INTO notify_connections (instance_id, user_id, ws_id, connections, away)
+        SELECT $1, * FROM UNNEST($2::BIGINT[], $3::BIGINT[], $4::INT[], $5::BOOLEAN[])
+        "#,
+    )
+    .bind(&state.instance_id)
+    .bind(&uids)
+    .bind(&ws_ids)
+    .bind(connections)
+    .bind(aways)
+    .execute(&mut *tx)
+    .await?;
+    // instances that died without deregistering
+    sqlx::query(
+        "DELETE FROM notify_instances WHERE heartbeat_at < NOW() - make_interval(secs => $1)",
+    )
+    .bind(INSTANCE_TTL_SECS)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Whether the user is connected to another live instance
+pub(crate) async fn connected_elsewhere(state: &NotifyState, uid: RowID) -> anyhow::Result<bool> {
+    let ret = sqlx::query_scalar(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM notify_connections c
+            JOIN notify_instances i ON i.id = c.instance_id
+            WHERE c.user_id = $1 AND c.instance_id <> $2
+                AND i.heartbeat_at > NOW() - make_interval(secs => $3)
+        )
+        "#,
+    )
+    .bind(uid)
+    .bind(&state.instance_id)
+    .bind(INSTANCE_TTL_SECS)
+    .fetch_one(&state.db)
+    .await?;
+    Ok(ret)
+}
+
+/// Relay an ephemeral event to the other instances
+pub(crate) fn broadcast_ephemeral(state: &NotifyState, audience: Audience, event: NotifyEvent) {
+    let nf = EphemeralNotification {
+        instance_id: state.instance_id.clone(),
+        audience,
+        event,
+    };
+    let payload = match serde_json::to_string(&nf) {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("failed to serialize ephemeral event: {}", e);
+            return;
+        }
+    };
+
+    let db = state.db.clone();
+    tokio::spawn(async move {
+        let ret = sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(EPHEMERAL_CHANNEL)
+            .bind(payload)
+            .execute(&db)
+            .await;
+        if let Err(e) = ret {
+            warn!("failed to relay ephemeral event: {}", e);
+        }
+    });
+}
+
+/// Deliver an ephemeral event relayed by another instance
+pub(crate) fn receive_ephemeral(state: &NotifyState, payload: &str) {
+    let nf: EphemeralNotification = match serde_json::from_str(payload) {
+        Ok(nf) => nf,
+        Err(e) => {
+            warn!("invalid ephemeral payload: {}: {}", e, payload);
+            return;
+        }
+    };
+    // delivered locally when published
+    if nf.instance_id != state.instance_id {
+        deliver_ephemeral(state, &nf.audience, Arc::new(nf.event));
+    }
+}
+
+impl Audience {
+    pub(crate) fn resolve(&self, state: &NotifyState) -> Vec<RowID> {
+        match self {
+            Audience::Users(users) => users.clone(),
+            Audience::Workspace { ws_id, except } => state
+                .presence
+                .iter()
+                .filter(|kv| kv.ws_id == *ws_id && kv.key() != except)
+                .map(|kv| *kv.key())
+                .collect(),
+        }
+    }
+}
+
+impl NotifyState {
+    /// Close every stream so clients reconnect to other instances, and leave the registry
+    pub async fn drain(&self) -> anyhow::Result<()> {
+        info!("draining instance {}", self.instance_id);
+        self.shutdown.cancel();
+        sqlx::query("DELETE FROM notify_instances WHERE id = $1")
+            .bind(&self.instance_id)
+            .execute(&self.db)
+            .await?;
+        Ok(())
+    }
+}
@@ -7,6 +7,16 @@ use serde::{Deserialize, Serialize};
 pub struct NotifyConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    /// Web Push is disabled if missing
+    #[serde(default)]
+    pub push: Option<PushConfig>,
+    /// Digest emails are disabled if missing
+    #[serde(default)]
+    pub digest: Option<DigestConfig>,
+    #[serde(default)]
+    pub stream: StreamConfig,
+    #[serde(default)]
+    pub webhook: WebhookConfig,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -21,6 +31,97 @@ pub struct AuthConfig {
     pub pk: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct PushConfig {
+    /// PKCS#8 PEM of the P-256 key push services verify the requests with
+    pub vapid_key: String,
+    /// Contact of the operator for the push services, a mailto: or https: url
+    pub subject: String,
+    /// Seconds push services keep a message for an offline device
+    #[serde(default = "default_push_ttl")]
+    pub ttl: u32,
+    #[serde(default = "default_push_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every following one
+    #[serde(default = "default_push_backoff")]
+    pub retry_backoff_ms: u64,
+}
+
+/// Tuning of the event streams of the clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamConfig {
+    /// Seconds between SSE keep-alive comments
+    pub keep_alive_secs: u64,
+    /// Events buffered per user before a slow consumer lags behind
+    pub channel_capacity: usize,
+    pub lag_policy: LagPolicy,
+}
+
+/// What a stream lagging behind its buffer gets instead of the dropped events
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LagPolicy {
+    /// A resync event, the client reloads its state
+    #[default]
+    Resync,
+    /// The stream is closed with a reason, the client reconnects and replays the event log
+    Disconnect,
+}
+
+/// Delivery of events to the webhooks of workspaces
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every following one
+    pub retry_backoff_ms: u64,
+    /// Seconds to wait for the response of an endpoint
+    pub timeout_secs: u64,
+    /// Accept `http://` endpoints, for development only
+    pub allow_http: bool,
+    /// Days delivered or failed deliveries are kept for
+    pub retention_days: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DigestConfig {
+    pub smtp: SmtpConfig,
+    /// Sender address of the digests
+    pub from: String,
+    /// Users offline for this long get a digest of what they missed
+    #[serde(default = "default_offline_after")]
+    pub offline_after_mins: u64,
+    /// How often offline users are checked
+    #[serde(default = "default_digest_interval")]
+    pub interval_secs: u64,
+    /// Messages included in a digest at most, the rest goes into the next one
+    #[serde(default = "default_digest_messages")]
+    pub max_messages: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+}
+
+/// Credentials are only sent once the connection is encrypted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrade with STARTTLS when the server offers it, usually on port 587
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465
+    Implicit,
+}
+
 impl NotifyConfig {
     pub fn load() -> anyhow::Result<Self> {
         let ret = match (
@@ -48,3 +149,53 @@ impl NotifyConfig {
         Ok(ret)
     }
 }
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self {
+            keep_alive_secs: 15,
+            channel_capacity: 256,
+            lag_policy: LagPolicy::Resync,
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            retry_backoff_ms: 1000,
+            timeout_secs: 10,
+            allow_http: false,
+            retention_days: 7,
+        }
+    }
+}
+
+fn default_push_ttl() -> u32 {
+    24 * 3600
+}
+
+fn default_push_attempts() -> u32 {
+    3
+}
+
+fn default_push_backoff() -> u64 {
+    1000
+}
+
+fn default_offline_after() -> u64 {
+    60
+}
+
+fn default_digest_interval() -> u64 {
+    600
+}
+
+fn default_digest_messages() -> i64 {
+    50
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
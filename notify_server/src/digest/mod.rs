@@ -0,0 +1,571 @@
+mod smtp;
+mod template;
+
+use std::time::Duration;
+
+use chat_core::RowID;
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sqlx::prelude::FromRow;
+use tokio::time;
+use tracing::{info, warn};
+
+pub use smtp::{Mail, MailTransport, SmtpTransport};
+pub use template::Template;
+
+use crate::{
+    cluster::{HEARTBEAT_INTERVAL, INSTANCE_TTL_SECS},
+    config::DigestConfig,
+    NotifyState,
+};
+
+/// Held by the instance sending the digests, the others skip the run
+const DIGEST_LOCK: i64 = 0x6469_6765_7374;
+/// Characters of the message content included in the digest
+const PREVIEW_CHARS: usize = 200;
+
+const SUBJECT_TEMPLATE: &str = include_str!("../../templates/digest_subject.txt");
+const TEXT_TEMPLATE: &str = include_str!("../../templates/digest.txt");
+const HTML_TEMPLATE: &str = include_str!("../../templates/digest.html");
+
+/// Emails users who have been offline for a while what they missed
+pub struct DigestService {
+    from: String,
+    offline_after: Duration,
+    interval: Duration,
+    max_messages: i64,
+    transport: Box<dyn MailTransport>,
+    subject: Template,
+    text: Template,
+    html: Template,
+}
+
+#[derive(Debug, FromRow)]
+struct Recipient {
+    id: RowID,
+    ws_id: RowID,
+    email: String,
+    fullname: String,
+    workspace: String,
+    since: DateTime<Utc>,
+}
+
+#[derive(Debug, FromRow)]
+struct DigestMessage {
+    id: RowID,
+    chat_id: RowID,
+    chat: String,
+    sender: String,
+    content: String,
+    mention: bool,
+}
+
+impl DigestService {
+    pub fn new(config: &DigestConfig, transport: Box<dyn MailTransport>) -> anyhow::Result<Self> {
+        Ok(Self {
+            from: config.from.clone(),
+            offline_after: Duration::from_secs(config.offline_after_mins * 60),
+            interval: Duration::from_secs(config.interval_secs.max(1)),
+            max_messages: config.max_messages.max(1),
+            transport,
+            subject: Template::parse(SUBJECT_TEMPLATE)?,
+            text: Template::parse(TEXT_TEMPLATE)?,
+            html: Template::parse(HTML_TEMPLATE)?,
+        })
+    }
+
+    fn render(&self, recipient: &Recipient, messages: &[DigestMessage]) -> Mail {
+        let data = digest_data(recipient, messages);
+        Mail {
+            from: self.from.clone(),
+            to: recipient.email.clone(),
+            // names come from users, line breaks would end the header
+            subject: self
+                .subject
+                .render(&data, false)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" "),
+            text: self.text.render(&data, false),
+            html: self.html.render(&data, true),
+        }
+    }
+}
+
+/// Send the digests periodically until the instance is drained
+pub fn setup_digest(state: NotifyState) {
+    let Some(digest) = &state.digest else {
+        return;
+    };
+    let mut interval = time::interval(digest.interval);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.shutdown.cancelled() => break,
+            }
+            match send_digests(&state).await {
+                Ok(0) => {}
+                Ok(n) => info!("sent {} digests", n),
+                Err(e) => warn!("failed to send digests: {:#}", e),
+            }
+        }
+    });
+}
+
+/// Record the connected users as seen until the instance is drained. Every instance does it,
+/// whether it sends digests or not.
+pub fn setup_activity(state: NotifyState) {
+    let mut interval = time::interval(HEARTBEAT_INTERVAL);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.shutdown.cancelled() => break,
+            }
+            if let Err(e) = record_activity(&state).await {
+                warn!("failed to record user activity: {:#}", e);
+            }
+        }
+    });
+}
+
+/// Digests go to users who haven't been seen for a while
+pub async fn record_activity(state: &NotifyState) -> anyhow::Result<()> {
+    let (uids, ws_ids): (Vec<_>, Vec<_>) = state
+        .presence
+        .iter()
+        .filter(|kv| kv.connections > 0)
+        .map(|kv| (*kv.key(), kv.ws_id))
+        .unzip();
+    sqlx::query(
+        r#"
+        INSERT INTO user_activity (user_id, ws_id, last_seen_at)
+        SELECT *, NOW() FROM UNNEST($1::BIGINT[], $2::BIGINT[])
+        ON CONFLICT (user_id) DO UPDATE SET ws_id = EXCLUDED.ws_id, last_seen_at = NOW()
+        "#,
+    )
+    .bind(&uids)
+    .bind(&ws_ids)
+    .execute(&state.db)
+    .await?;
+    Ok(())
+}
+
+/// Send a digest to every user offline for long enough with unread messages,
+/// return the number of digests sent. Only one instance sends them at a time.
+pub async fn send_digests(state: &NotifyState) -> anyhow::Result<usize> {
+    let Some(digest) = &state.digest else {
+        return Ok(0);
+    };
+    let mut lock = state.db.begin().await?;
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+        .bind(DIGEST_LOCK)
+        .fetch_one(&mut *lock)
+        .await?;
+    if !locked {
+        return Ok(0);
+    }
+
+    // users in their do-not-disturb window get theirs once it is over
+    let recipients: Vec<Recipient> = sqlx::query_as(
+        r#"
+        SELECT u.id, u.ws_id, u.email, u.fullname, w.name AS workspace,
+            COALESCE(a.last_seen_at, u.created_at) AS since
+        FROM users u
+        JOIN workspaces w ON w.id = u.ws_id
+        LEFT JOIN user_activity a ON a.user_id = u.id
+        WHERE NOT u.is_bot
+            AND COALESCE(a.last_seen_at, u.created_at) < NOW() - make_interval(secs => $1)
+            AND NOT EXISTS (
+                SELECT 1 FROM digests d
+                WHERE d.user_id = u.id AND d.created_at > NOW() - make_interval(secs => $1)
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM notify_connections c
+                JOIN notify_instances i ON i.id = c.instance_id
+                WHERE c.user_id = u.id AND i.heartbeat_at > NOW() - make_interval(secs => $2)
+            )
+            AND NOT EXISTS (
+                SELECT 1 FROM dnd_schedules d,
+                    LATERAL (SELECT (NOW() AT TIME ZONE d.timezone)::TIME AS now) t
+                WHERE d.user_id = u.id AND d.ws_id = u.ws_id
+                    AND CASE WHEN d.start_time <= d.end_time
+                        THEN t.now >= d.start_time AND t.now < d.end_time
+                        ELSE t.now >= d.start_time OR t.now < d.end_time
+                    END
+            )
+        ORDER BY u.id
+        "#,
+    )
+    .bind(digest.offline_after.as_secs_f64())
+    .bind(INSTANCE_TTL_SECS)
+    .fetch_all(&state.db)
+    .await?;
+
+    let mut sent = 0;
+    for recipient in recipients {
+        match send_digest(state, digest, &recipient).await {
+            Ok(true) => sent += 1,
+            Ok(false) => {}
+            Err(e) => warn!("failed to send digest to {}: {:#}", recipient.id, e),
+        }
+    }
+    lock.commit().await?;
+    Ok(sent)
+}
+
+/// Messages already included in a digest are skipped, a digest is recorded only if it was sent
+async fn send_digest(
+    state: &NotifyState,
+    digest: &DigestService,
+    recipient: &Recipient,
+) -> anyhow::Result<bool> {
+    let messages: Vec<DigestMessage> = sqlx::query_as(
+        r#"
+        SELECT m.id, m.chat_id, COALESCE(c.name, s.fullname) AS chat, s.fullname AS sender,
+            m.content, m.content LIKE '%<@' || $1 || '>%' AS mention
+        FROM messages m
+        JOIN chats c ON c.id = m.chat_id
+        JOIN users s ON s.id = m.sender_id
+        LEFT JOIN chat_settings cs ON cs.user_id = $1 AND cs.chat_id = m.chat_id
+        WHERE c.ws_id = $2 AND $1 = ANY(c.members) AND m.sender_id <> $1 AND m.created_at > $3
+            AND (cs.muted_until IS NULL OR cs.muted_until <= NOW())
+            AND (cs.level IS DISTINCT FROM 'mentions' OR m.content LIKE '%<@' || $1 || '>%')
+            AND NOT EXISTS (
+                SELECT 1 FROM digests d WHERE d.user_id = $1 AND d.message_ids @> ARRAY[m.id]
+            )
+        ORDER BY m.id
+        LIMIT $4
+        "#,
+    )
+    .bind(recipient.id)
+    .bind(recipient.ws_id)
+    .bind(recipient.since)
+    .bind(digest.max_messages)
+    .fetch_all(&state.db)
+    .await?;
+    if messages.is_empty() {
+        return Ok(false);
+    }
+
+    let mail = digest.render(recipient, &messages);
+    let ids: Vec<RowID> = messages.iter().map(|m| m.id).collect();
+    let mut tx = state.db.begin().await?;
+    sqlx::query("INSERT INTO digests (user_id, ws_id, message_ids) VALUES ($1, $2, $3)")
+        .bind(recipient.id)
+        .bind(recipient.ws_id)
+        .bind(ids)
+        .execute(&mut *tx)
+        .await?;
+    digest.transport.send(&mail).await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// Mentions are listed first, then the messages grouped by chat
+fn digest_data(recipient: &Recipient, messages: &[DigestMessage]) -> Value {
+    let preview = |m: &DigestMessage| -> String { m.content.chars().take(PREVIEW_CHARS).collect() };
+
+    let mentions: Vec<Value> = messages
+        .iter()
+        .filter(|m| m.mention)
+        .map(|m| json!({ "chat": m.chat, "sender": m.sender, "content": preview(m) }))
+        .collect();
+
+    let mut chats: Vec<(RowID, &str, Vec<Value>)> = vec![];
+    for m in messages {
+        let message = json!({ "sender": m.sender, "content": preview(m) });
+        match chats.iter_mut().find(|(id, _, _)| *id == m.chat_id) {
+            Some((_, _, items)) => items.push(message),
+            None => chats.push((m.chat_id, &m.chat, vec![message])),
+        }
+    }
+    let chats: Vec<Value> = chats
+        .into_iter()
+        .map(|(_, chat, items)| json!({ "chat": chat, "count": items.len(), "messages": items }))
+        .collect();
+
+    json!({
+        "fullname": recipient.fullname,
+        "workspace": recipient.workspace,
+        "total": messages.len(),
+        "mentions": if mentions.is_empty() { Value::Null } else { json!({ "items": mentions }) },
+        "chats": chats,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use chat_core::utils::UserCliams;
+
+    use super::*;
+    use crate::{
+        config::{AuthConfig, NotifyConfig, ServerConfig, SmtpConfig},
+        presence,
+    };
+
+    #[derive(Debug)]
+    struct Received {
+        to: String,
+        data: String,
+    }
+
+    /// Accept every mail and hand it to the test
+    async fn smtp_sink() -> (u16, mpsc::UnboundedReceiver<Received>) {
+        async fn session(
+            stream: tokio::net::TcpStream,
+            tx: mpsc::UnboundedSender<Received>,
+        ) -> std::io::Result<()> {
+            let mut stream = BufReader::new(stream);
+            stream.get_mut().write_all(b"220 sink\r\n").await?;
+            let mut to = String::new();
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if stream.read_line(&mut line).await? == 0 {
+                    return Ok(());
+                }
+                let reply: &[u8] = match line.trim_end() {
+                    l if l.starts_with("EHLO") => b"250-sink\r\n250 AUTH PLAIN\r\n",
+                    l if l.starts_with("AUTH PLAIN") => b"235 authenticated\r\n",
+                    l if l.starts_with("RCPT TO:") => {
+                        to = l["RCPT TO:".len()..].trim_matches(['<', '>']).to_string();
+                        b"250 ok\r\n"
+                    }
+                    "DATA" => {
+                        stream.get_mut().write_all(b"354 go on\r\n").await?;
+                        let mut data = String::new();
+                        loop {
+                            line.clear();
+                            stream.read_line(&mut line).await?;
+                            if line == ".\r\n" {
+                                break;
+                            }
+                            data.push_str(&line);
+                        }
+                        let _ = tx.send(Received {
+                            to: to.clone(),
+                            data,
+                        });
+                        b"250 queued\r\n"
+                    }
+                    "QUIT" => {
+                        stream.get_mut().write_all(b"221 bye\r\n").await?;
+                        return Ok(());
+                    }
+                    _ => b"250 ok\r\n",
+                };
+                stream.get_mut().write_all(reply).await?;
+            }
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = ls.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = ls.accept().await {
+                tokio::spawn(session(stream, tx.clone()));
+            }
+        });
+        (port, rx)
+    }
+
+    /// The decoded part of a mail with the content type
+    fn part(data: &str, content_type: &str) -> String {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let part = data
+            .split(&format!(
+                "Content-Type: {}; charset=utf-8\r\n",
+                content_type
+            ))
+            .nth(1)
+            .unwrap();
+        let body = part.split("\r\n\r\n").nth(1).unwrap();
+        let encoded: String = body.lines().take_while(|l| !l.starts_with("--")).collect();
+        String::from_utf8(STANDARD.decode(encoded).unwrap()).unwrap()
+    }
+
+    fn config(port: u16) -> NotifyConfig {
+        NotifyConfig {
+            server: ServerConfig {
+                host: "127.0.0.1".to_string(),
+                port: 0,
+                db_url: "".to_string(),
+            },
+            auth: AuthConfig {
+                pk: include_str!("../../../fixtures/public.pem").to_string(),
+            },
+            push: None,
+            digest: Some(DigestConfig {
+                smtp: SmtpConfig {
+                    host: "127.0.0.1".to_string(),
+                    port,
+                    username: None,
+                    password: None,
+                    tls: Default::default(),
+                },
+                from: "digest@example.com".to_string(),
+                offline_after_mins: 60,
+                interval_secs: 600,
+                max_messages: 50,
+            }),
+            stream: Default::default(),
+            webhook: Default::default(),
+        }
+    }
+
+    async fn seen(pool: &sqlx::PgPool, uid: RowID, hours: i32) {
+        sqlx::query(
+            r#"
+            INSERT INTO user_activity (user_id, ws_id, last_seen_at)
+            VALUES ($1, 1, NOW() - make_interval(hours => $2))
+            "#,
+        )
+        .bind(uid)
+        .bind(hours)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_record_activity(pool: sqlx::PgPool) {
+        let state = NotifyState::new_for_test(pool.clone());
+        seen(&pool, 1, 2).await;
+        let user = UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        };
+        let _guard = presence::connect(&state, &user);
+        record_activity(&state).await.unwrap();
+
+        let recent: bool = sqlx::query_scalar(
+            "SELECT last_seen_at > NOW() - INTERVAL '1 minute' FROM user_activity WHERE user_id = 1",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(recent);
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_send_digests(pool: sqlx::PgPool) {
+        let (port, mut rx) = smtp_sink().await;
+        let state = NotifyState::new(config(port), pool.clone()).unwrap();
+
+        // nobody has been offline long enough
+        assert_eq!(send_digests(&state).await.unwrap(), 0);
+
+        seen(&pool, 1, 2).await;
+        sqlx::query(
+            "INSERT INTO messages (chat_id, sender_id, content) VALUES (1, 2, 'ping <@1> & bye')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(send_digests(&state).await.unwrap(), 1);
+        let mail = rx.recv().await.unwrap();
+        assert_eq!(mail.to, "user-1@a.com");
+        assert!(mail.data.contains("Subject: 2 unread messages in ws-1\r\n"));
+        let text = part(&mail.data, "text/plain");
+        assert!(text.contains("You were mentioned:\n  user-2 in user-2: ping <@1> & bye\n"));
+        assert!(text.contains("user-2 (2)\n  user-2: nice\n  user-2: ping <@1> & bye\n"));
+        let html = part(&mail.data, "text/html");
+        assert!(html.contains("ping &lt;@1&gt; &amp; bye"));
+
+        // included messages are not sent again
+        sqlx::query("UPDATE digests SET created_at = NOW() - INTERVAL '2 hours'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(send_digests(&state).await.unwrap(), 0);
+
+        // only mentions are wanted
+        sqlx::query("DELETE FROM digests")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO chat_settings (user_id, chat_id, level) VALUES (1, 1, 'mentions')",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(send_digests(&state).await.unwrap(), 1);
+        let text = part(&rx.recv().await.unwrap().data, "text/plain");
+        assert!(text.contains("You have 1 unread messages"));
+        assert!(!text.contains("nice"));
+
+        // muted chats are left out
+        sqlx::query("DELETE FROM digests")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE chat_settings SET muted_until = NOW() + INTERVAL '1 hour'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(send_digests(&state).await.unwrap(), 0);
+
+        // a workspace name can't add headers
+        sqlx::query("DELETE FROM digests")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM chat_settings")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE workspaces SET name = E'ws-1\r\nBcc: evil@a.com' WHERE id = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(send_digests(&state).await.unwrap(), 1);
+        let mail = rx.recv().await.unwrap();
+        assert!(mail
+            .data
+            .contains("Subject: 2 unread messages in ws-1 Bcc: evil@a.com\r\n"));
+        assert!(!mail.data.contains("\r\nBcc:"));
+    }
+
+    #[tokio::test]
+    async fn t_refuse_plain_auth() {
+        let (port, mut rx) = smtp_sink().await;
+        let mut smtp = config(port).digest.unwrap().smtp;
+        smtp.username = Some("user".to_string());
+        smtp.password = Some("pass".to_string());
+        let mail = Mail {
+            from: "digest@example.com".to_string(),
+            to: "user-1@a.com".to_string(),
+            subject: "subject".to_string(),
+            text: "text".to_string(),
+            html: "html".to_string(),
+        };
+
+        // the sink offers no STARTTLS
+        let transport = SmtpTransport::new(&smtp).unwrap();
+        let err = transport.send(&mail).await.unwrap_err();
+        assert!(err.to_string().contains("without tls"));
+        assert!(rx.try_recv().is_err());
+
+        // without credentials the mail goes through
+        let transport = SmtpTransport::new(&config(port).digest.unwrap().smtp).unwrap();
+        transport.send(&mail).await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().to, "user-1@a.com");
+    }
+}
@@ -0,0 +1,310 @@
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Utc;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{crypto::ring, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use uuid::Uuid;
+
+use crate::config::{SmtpConfig, SmtpTls};
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    async fn send(&self, mail: &Mail) -> anyhow::Result<()>;
+}
+
+/// SMTP with STARTTLS or implicit TLS, the credentials are never sent in clear text
+pub struct SmtpTransport {
+    host: String,
+    port: u16,
+    tls: SmtpTls,
+    credentials: Option<(String, String)>,
+    connector: TlsConnector,
+}
+
+impl SmtpTransport {
+    pub fn new(config: &SmtpConfig) -> anyhow::Result<Self> {
+        let credentials = match (&config.username, &config.password) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            _ => None,
+        };
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let tls = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Self {
+            host: config.host.clone(),
+            port: config.port,
+            tls: config.tls,
+            credentials,
+            connector: TlsConnector::from(Arc::new(tls)),
+        })
+    }
+
+    async fn encrypt(&self, stream: Box<dyn Stream>) -> anyhow::Result<Box<dyn Stream>> {
+        let name = ServerName::try_from(self.host.clone())?;
+        let stream = self
+            .connector
+            .connect(name, stream)
+            .await
+            .with_context(|| format!("tls handshake with smtp server {}", self.host))?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[async_trait]
+impl MailTransport for SmtpTransport {
+    async fn send(&self, mail: &Mail) -> anyhow::Result<()> {
+        // fail before talking to the server
+        let data = mail.to_mime()?;
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("connect smtp server {}:{}", self.host, self.port))?;
+        let mut stream: Box<dyn Stream> = Box::new(stream);
+        let mut encrypted = self.tls == SmtpTls::Implicit;
+        if encrypted {
+            stream = self.encrypt(stream).await?;
+        }
+        let mut conn = Connection {
+            stream: BufReader::new(stream),
+        };
+
+        conn.expect(220).await?;
+        let mut extensions = conn.ehlo().await?;
+        if !encrypted && extensions.iter().any(|ext| ext == "STARTTLS") {
+            conn.command("STARTTLS", 220).await?;
+            // nothing is buffered, the server waits for the handshake
+            conn.stream = BufReader::new(self.encrypt(conn.stream.into_inner()).await?);
+            encrypted = true;
+            extensions = conn.ehlo().await?;
+        }
+        if let Some((username, password)) = &self.credentials {
+            if !encrypted {
+                bail!("refuse to authenticate to {} without tls", self.host);
+            }
+            if !extensions.iter().any(|ext| ext.starts_with("AUTH")) {
+                bail!("smtp server {} doesn't support authentication", self.host);
+            }
+            let token = STANDARD.encode(format!("\0{}\0{}", username, password));
+            conn.command(&format!("AUTH PLAIN {}", token), 235).await?;
+        }
+        conn.command(&format!("MAIL FROM:<{}>", mail.from), 250)
+            .await?;
+        conn.command(&format!("RCPT TO:<{}>", mail.to), 250).await?;
+        conn.command("DATA", 354).await?;
+        conn.write(&format!("{}\r\n.", dot_stuff(&data))).await?;
+        conn.expect(250).await?;
+        conn.command("QUIT", 221).await?;
+        Ok(())
+    }
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+struct Connection {
+    stream: BufReader<Box<dyn Stream>>,
+}
+
+impl Connection {
+    async fn command(&mut self, line: &str, code: u16) -> anyhow::Result<()> {
+        self.write(line).await?;
+        self.expect(code).await.map(|_| ())
+    }
+
+    /// The extensions supported by the server, in upper case
+    async fn ehlo(&mut self) -> anyhow::Result<Vec<String>> {
+        self.write("EHLO localhost").await?;
+        let lines = self.expect(250).await?;
+        Ok(lines
+            .iter()
+            .skip(1)
+            .map(|line| line.to_ascii_uppercase())
+            .collect())
+    }
+
+    async fn write(&mut self, line: &str) -> anyhow::Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(line.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Read a possibly multiline reply, check its code and return the text of the lines
+    async fn expect(&mut self, code: u16) -> anyhow::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                bail!("smtp connection closed");
+            }
+            let reply: u16 = line
+                .get(..3)
+                .and_then(|c| c.parse().ok())
+                .with_context(|| format!("invalid smtp reply: {}", line.trim_end()))?;
+            if reply != code {
+                bail!("unexpected smtp reply: {}", line.trim_end());
+            }
+            lines.push(line.get(4..).unwrap_or_default().trim_end().to_string());
+            // `250-` is followed by more lines, `250 ` is the last one
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(lines);
+            }
+        }
+    }
+}
+
+impl Mail {
+    /// A multipart/alternative message with the text and html bodies
+    pub fn to_mime(&self) -> anyhow::Result<String> {
+        check_address(&self.from)?;
+        check_address(&self.to)?;
+        if self.subject.contains(['\r', '\n']) {
+            bail!("line break in the mail subject");
+        }
+        let boundary = Uuid::now_v7().simple().to_string();
+        let mut out = String::new();
+        out.push_str(&format!("From: <{}>\r\n", self.from));
+        out.push_str(&format!("To: <{}>\r\n", self.to));
+        out.push_str(&format!("Subject: {}\r\n", encode_header(&self.subject)));
+        out.push_str(&format!("Date: {}\r\n", Utc::now().to_rfc2822()));
+        out.push_str("MIME-Version: 1.0\r\n");
+        out.push_str(&format!(
+            "Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n",
+            boundary
+        ));
+        for (content_type, body) in [("text/plain", &self.text), ("text/html", &self.html)] {
+            out.push_str(&format!("--{}\r\n", boundary));
+            out.push_str(&format!(
+                "Content-Type: {}; charset=utf-8\r\nContent-Transfer-Encoding: base64\r\n\r\n",
+                content_type
+            ));
+            let encoded = STANDARD.encode(body);
+            // lines are limited to 998 characters
+            for chunk in encoded.as_bytes().chunks(76) {
+                out.push_str(std::str::from_utf8(chunk).unwrap_or_default());
+                out.push_str("\r\n");
+            }
+        }
+        out.push_str(&format!("--{}--", boundary));
+        Ok(out)
+    }
+}
+
+/// Addresses go into SMTP commands and headers as they are
+fn check_address(address: &str) -> anyhow::Result<()> {
+    let valid = address
+        .split_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+        && !address
+            .chars()
+            .any(|c| c.is_ascii_control() || c.is_whitespace() || matches!(c, '<' | '>'));
+    if !valid {
+        bail!("invalid mail address: {:?}", address);
+    }
+    Ok(())
+}
+
+/// Printable ascii headers are sent as they are if they fit on a line, others as base64
+/// encoded words folded on several lines
+fn encode_header(value: &str) -> String {
+    if value.len() <= 78 && value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+        return value.to_string();
+    }
+    // an encoded word is at most 75 characters, which leaves 45 bytes to encode
+    let mut words = Vec::new();
+    let mut start = 0;
+    while start < value.len() {
+        let mut end = (start + 45).min(value.len());
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        words.push(format!(
+            "=?utf-8?B?{}?=",
+            STANDARD.encode(&value[start..end])
+        ));
+        start = end;
+    }
+    words.join("\r\n ")
+}
+
+/// A line starting with a dot would end the data, it is escaped by another dot
+fn dot_stuff(data: &str) -> String {
+    data.split("\r\n")
+        .map(|line| {
+            if line.starts_with('.') {
+                format!(".{}", line)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(to: &str, subject: &str) -> Mail {
+        Mail {
+            from: "digest@example.com".to_string(),
+            to: to.to_string(),
+            subject: subject.to_string(),
+            text: "text".to_string(),
+            html: "html".to_string(),
+        }
+    }
+
+    #[test]
+    fn t_mail_headers() {
+        let data = mail("a@a.com", "2 unread messages in ws")
+            .to_mime()
+            .unwrap();
+        assert!(data.contains("\r\nSubject: 2 unread messages in ws\r\n"));
+
+        let subject = "未读消息 ".repeat(10);
+        let data = mail("a@a.com", &subject).to_mime().unwrap();
+        let header = data.split("Subject: ").nth(1).unwrap();
+        let header = &header[..header.find("\r\nDate:").unwrap()];
+        let mut decoded = Vec::new();
+        for word in header.split("\r\n ") {
+            assert!(word.len() <= 75);
+            let encoded = word.strip_prefix("=?utf-8?B?").unwrap();
+            decoded.extend(
+                STANDARD
+                    .decode(encoded.strip_suffix("?=").unwrap())
+                    .unwrap(),
+            );
+        }
+        assert_eq!(String::from_utf8(decoded).unwrap(), subject);
+
+        assert!(mail("a@a.com", "ws\r\nBcc: b@b.com").to_mime().is_err());
+        assert!(mail("a@a.com>\r\nRCPT TO:<b@b.com", "ws")
+            .to_mime()
+            .is_err());
+        assert!(mail("a.com", "ws").to_mime().is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use anyhow::{anyhow, bail};
+use serde_json::Value;
+
+/// A small subset of mustache: `{{name}}` is replaced by the value of the innermost context
+/// containing it, `{{#list}}...{{/list}}` repeats the block for every item of an array,
+/// or renders it once if the value is truthy.
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Var(String),
+    Section(String, Vec<Node>),
+}
+
+impl Template {
+    pub fn parse(src: &str) -> anyhow::Result<Self> {
+        let src = strip_standalone(src);
+        let mut rest = src.as_str();
+        let nodes = parse_nodes(&mut rest, None)?;
+        Ok(Self { nodes })
+    }
+
+    /// Render with the data, values are html escaped if `html` is set
+    pub fn render(&self, data: &Value, html: bool) -> String {
+        let mut out = String::new();
+        let mut stack = vec![data];
+        render_nodes(&self.nodes, &mut stack, html, &mut out);
+        out
+    }
+}
+
+/// Lines with nothing but a section tag don't leave an empty line in the output
+fn strip_standalone(src: &str) -> String {
+    let mut out = String::with_capacity(src.len());
+    for line in src.split_inclusive('\n') {
+        let tag = line.trim();
+        let standalone = (tag.starts_with("{{#") || tag.starts_with("{{/"))
+            && tag.ends_with("}}")
+            && tag.matches("{{").count() == 1;
+        if standalone {
+            out.push_str(tag);
+        } else {
+            out.push_str(line);
+        }
+    }
+    out
+}
+
+fn parse_nodes(rest: &mut &str, section: Option<&str>) -> anyhow::Result<Vec<Node>> {
+    let mut nodes = vec![];
+    loop {
+        let Some(start) = rest.find("{{") else {
+            if let Some(name) = section {
+                bail!("unclosed section: {}", name);
+            }
+            if !rest.is_empty() {
+                nodes.push(Node::Text(rest.to_string()));
+            }
+            *rest = "";
+            return Ok(nodes);
+        };
+        if start > 0 {
+            nodes.push(Node::Text(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find("}}")
+            .ok_or_else(|| anyhow!("unclosed tag"))?
+            + start;
+        let tag = rest[start + 2..end].trim().to_string();
+        *rest = &rest[end + 2..];
+
+        if let Some(name) = tag.strip_prefix('#') {
+            let children = parse_nodes(rest, Some(name))?;
+            nodes.push(Node::Section(name.to_string(), children));
+        } else if let Some(name) = tag.strip_prefix('/') {
+            if section != Some(name) {
+                bail!("unexpected closing tag: {}", name);
+            }
+            return Ok(nodes);
+        } else {
+            nodes.push(Node::Var(tag));
+        }
+    }
+}
+
+fn lookup<'a>(stack: &[&'a Value], name: &str) -> Option<&'a Value> {
+    stack.iter().rev().find_map(|ctx| ctx.get(name))
+}
+
+fn render_nodes<'a>(nodes: &'a [Node], stack: &mut Vec<&'a Value>, html: bool, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var(name) => {
+                let value = match lookup(stack, name) {
+                    Some(Value::String(s)) => s.clone(),
+                    Some(Value::Null) | None => String::new(),
+                    Some(v) => v.to_string(),
+                };
+                if html {
+                    escape_html(&value, out);
+                } else {
+                    out.push_str(&value);
+                }
+            }
+            Node::Section(name, children) => match lookup(stack, name) {
+                Some(Value::Array(items)) => {
+                    for item in items {
+                        stack.push(item);
+                        render_nodes(children, stack, html, out);
+                        stack.pop();
+                    }
+                }
+                Some(Value::Null | Value::Bool(false)) | None => {}
+                Some(value) => {
+                    stack.push(value);
+                    render_nodes(children, stack, html, out);
+                    stack.pop();
+                }
+            },
+        }
+    }
+}
+
+fn escape_html(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn t_render_template() {
+        let tpl = Template::parse(
+            "Hi {{name}}!{{#items}} [{{name}}: {{n}}]{{/items}}{{#none}}x{{/none}}",
+        )
+        .unwrap();
+        let data = json!({
+            "name": "<a>",
+            "items": [{ "name": "b", "n": 1 }, { "n": 2 }],
+        });
+        assert_eq!(tpl.render(&data, false), "Hi <a>! [b: 1] [<a>: 2]");
+        assert_eq!(
+            tpl.render(&data, true),
+            "Hi &lt;a&gt;! [b: 1] [&lt;a&gt;: 2]"
+        );
+
+        let tpl = Template::parse("a\n  {{#items}}\n- {{n}}\n  {{/items}}\nb\n").unwrap();
+        assert_eq!(tpl.render(&data, false), "a\n- 1\n- 2\nb\n");
+
+        assert!(Template::parse("{{#a}}").is_err());
+        assert!(Template::parse("{{#a}}{{/b}}").is_err());
+        assert!(Template::parse("{{a").is_err());
+    }
+}
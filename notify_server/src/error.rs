@@ -1,6 +1,12 @@
 use std::fmt;
 
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
 use serde::Serialize;
+use serde_json::json;
 
 pub type AppResult<T> = Result<T, AppError>;
 
@@ -12,6 +18,9 @@ pub enum ImmutStr {
 
 #[derive(Debug)]
 pub enum AppError {
+    NotFound(ImmutStr),
+    InvalidInput(ImmutStr),
+    Forbidden(ImmutStr),
     Internal(anyhow::Error),
 }
 
@@ -24,20 +33,33 @@ where
     }
 }
 
-// impl IntoResponse for AppError {
-//     fn into_response(self) -> Response {
-//         let (code, err) = match self {
-//             Self::Internal(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string().into()),
-//         };
-//
-//         (code, Json(json!({ "error": err }))).into_response()
-//     }
-// }
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (code, err) = match self {
+            Self::NotFound(err) => (StatusCode::NOT_FOUND, err),
+            Self::InvalidInput(err) => (StatusCode::UNPROCESSABLE_ENTITY, err),
+            Self::Forbidden(err) => (StatusCode::FORBIDDEN, err),
+            Self::Internal(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string().into()),
+        };
+
+        (code, Json(json!({ "error": err }))).into_response()
+    }
+}
 
 impl AppError {
     #[inline]
-    pub fn any(e: impl Into<anyhow::Error>) -> Self {
-        AppError::Internal(e.into())
+    pub fn not_found(msg: impl Into<ImmutStr>) -> Self {
+        AppError::NotFound(msg.into())
+    }
+
+    #[inline]
+    pub fn invalid_input(msg: impl Into<ImmutStr>) -> Self {
+        AppError::InvalidInput(msg.into())
+    }
+
+    #[inline]
+    pub fn forbidden(msg: impl Into<ImmutStr>) -> Self {
+        AppError::Forbidden(msg.into())
     }
 }
 
@@ -55,6 +77,17 @@ impl From<String> for ImmutStr {
     }
 }
 
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::NotFound(err) => err.fmt(f),
+            AppError::InvalidInput(err) => err.fmt(f),
+            AppError::Forbidden(err) => err.fmt(f),
+            AppError::Internal(err) => err.fmt(f),
+        }
+    }
+}
+
 impl fmt::Display for ImmutStr {
     #[inline]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -0,0 +1,165 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use chat_core::{notify::NotifyEvent, RowID};
+use chrono::{DateTime, Utc};
+
+/// Number of recent events kept for replay
+pub const EVENT_LOG_CAPACITY: usize = 1024;
+
+/// Ids sent to clients, sequences are only meaningful on the instance that assigned them
+pub fn format_event_id(instance_id: &str, seq: u64) -> String {
+    format!("{}:{}", instance_id, seq)
+}
+
+/// The sequence of an id assigned by this instance
+pub fn parse_event_id(instance_id: &str, id: &str) -> Option<u64> {
+    match id.rsplit_once(':') {
+        Some((instance, seq)) if instance == instance_id => seq.parse().ok(),
+        _ => None,
+    }
+}
+
+#[derive(Debug)]
+pub struct EventRecord {
+    pub id: u64,
+    pub users: Vec<RowID>,
+    /// Users the event is delivered to without notifying them
+    pub silent: Vec<RowID>,
+    pub event: NotifyEvent,
+    pub ts: DateTime<Utc>,
+}
+
+/// Bounded log of recent events, ids are monotonically increasing from 1
+pub struct EventLog {
+    capacity: usize,
+    last_id: u64,
+    /// Events were lost right before this id, older ids can't be replayed
+    gap_id: u64,
+    events: VecDeque<Arc<EventRecord>>,
+}
+
+#[derive(Debug)]
+pub enum Replay {
+    Events(Vec<Arc<EventRecord>>),
+    /// Some events after the requested id are gone, the client has to reload its state
+    Resync {
+        last_id: u64,
+    },
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            last_id: 0,
+            gap_id: 0,
+            events: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        users: Vec<RowID>,
+        silent: Vec<RowID>,
+        event: NotifyEvent,
+    ) -> Arc<EventRecord> {
+        self.last_id += 1;
+        let record = Arc::new(EventRecord {
+            id: self.last_id,
+            users,
+            silent,
+            event,
+            ts: Utc::now(),
+        });
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(record.clone());
+        record
+    }
+
+    /// Record that events were lost, return the id to resume from after a resync
+    pub fn push_gap(&mut self) -> u64 {
+        self.last_id += 1;
+        self.gap_id = self.last_id;
+        self.last_id
+    }
+
+    /// Events of the user after `last_id`
+    pub fn replay(&self, uid: RowID, last_id: u64) -> Replay {
+        // ids from a previous run of the instance
+        if last_id > self.last_id {
+            return Replay::Resync {
+                last_id: self.last_id,
+            };
+        }
+        let first_id = self.events.front().map_or(self.last_id + 1, |ev| ev.id);
+        if first_id > last_id + 1 || last_id < self.gap_id {
+            return Replay::Resync {
+                last_id: self.last_id,
+            };
+        }
+
+        let events = self
+            .events
+            .iter()
+            .filter(|ev| ev.id > last_id && ev.users.contains(&uid))
+            .cloned()
+            .collect();
+        Replay::Events(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event() -> NotifyEvent {
+        let chat = serde_json::from_value(serde_json::json!({
+            "id": 1, "ws_id": 1, "name": null, "type": "single",
+            "members": [1, 2], "created_at": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap();
+        NotifyEvent::NewChat(chat)
+    }
+
+    fn ids(replay: Replay) -> Vec<u64> {
+        match replay {
+            Replay::Events(events) => events.iter().map(|ev| ev.id).collect(),
+            Replay::Resync { .. } => panic!("unexpected resync"),
+        }
+    }
+
+    #[test]
+    fn t_event_log_replay() {
+        let mut log = EventLog::new(3);
+        log.push(vec![1, 2], vec![], event());
+        log.push(vec![2], vec![], event());
+        log.push(vec![1], vec![], event());
+
+        assert_eq!(ids(log.replay(1, 0)), vec![1, 3]);
+        assert_eq!(ids(log.replay(1, 1)), vec![3]);
+        assert_eq!(ids(log.replay(2, 3)), Vec::<u64>::new());
+
+        // event 1 is evicted
+        log.push(vec![2], vec![], event());
+        assert_eq!(ids(log.replay(2, 1)), vec![2, 4]);
+        assert!(matches!(log.replay(2, 0), Replay::Resync { last_id: 4 }));
+        // unknown id from a previous run
+        assert!(matches!(log.replay(2, 10), Replay::Resync { last_id: 4 }));
+
+        // events lost before the gap
+        assert_eq!(log.push_gap(), 5);
+        log.push(vec![2], vec![], event());
+        assert!(matches!(log.replay(2, 4), Replay::Resync { last_id: 6 }));
+        assert_eq!(ids(log.replay(2, 5)), vec![6]);
+    }
+
+    #[test]
+    fn t_event_id() {
+        let id = format_event_id("a", 3);
+        assert_eq!(parse_event_id("a", &id), Some(3));
+        assert_eq!(parse_event_id("b", &id), None);
+        assert_eq!(parse_event_id("a", "3"), None);
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+
+use chat_core::notify::{EventEnvelope, MessagePreview, NotifyEvent};
+use chat_core::RowID;
+use serde::Deserialize;
+
+use crate::{
+    error::{AppError, AppResult},
+    notify_event::UserEvent,
+};
+
+/// Characters of the message content sent in lite mode
+const LITE_PREVIEW_CHARS: usize = 100;
+
+const EVENT_NAMES: [&str; 8] = [
+    "NewChat",
+    "MemberAdded",
+    "RemovedFromChat",
+    "ChatUpdated",
+    "NewMessage",
+    "ReadMarker",
+    "Typing",
+    "PresenceChanged",
+];
+
+/// Query of a stream, e.g. `?types=NewMessage,Typing&chats=1,2&lite=true`
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct FilterParams {
+    /// Comma separated event names
+    types: Option<String>,
+    /// Comma separated chat ids
+    chats: Option<String>,
+    #[serde(default)]
+    lite: bool,
+}
+
+/// Which events a stream receives and how `NewMessage` is sent
+#[derive(Debug, Default, Clone)]
+pub(crate) struct EventFilter {
+    types: Option<HashSet<String>>,
+    chats: Option<HashSet<RowID>>,
+    /// `NewMessage` carries ids and a preview instead of the full message
+    lite: bool,
+}
+
+impl FilterParams {
+    pub(crate) fn parse(self) -> AppResult<EventFilter> {
+        let types = match self.types {
+            Some(types) => {
+                let types: HashSet<String> = split(&types).map(str::to_string).collect();
+                if let Some(name) = types.iter().find(|t| !EVENT_NAMES.contains(&t.as_str())) {
+                    return Err(AppError::invalid_input(format!(
+                        "unknown event type: {}",
+                        name
+                    )));
+                }
+                Some(types)
+            }
+            None => None,
+        };
+        let chats = match self.chats {
+            Some(chats) => Some(
+                split(&chats)
+                    .map(|id| id.parse())
+                    .collect::<Result<HashSet<RowID>, _>>()
+                    .map_err(|_| AppError::invalid_input("invalid chat id"))?,
+            ),
+            None => None,
+        };
+        Ok(EventFilter {
+            types,
+            chats,
+            lite: self.lite,
+        })
+    }
+}
+
+impl EventFilter {
+    /// Resyncs always pass. With a chat filter, events without a chat pass only if
+    /// their type is asked for explicitly.
+    pub(crate) fn matches(&self, ev: &UserEvent) -> bool {
+        let Some(event) = ev.event() else {
+            return true;
+        };
+        let listed = self.types.as_ref().map(|t| t.contains(event.name()));
+        if listed == Some(false) {
+            return false;
+        }
+        match (&self.chats, event.chat_id()) {
+            (None, _) => true,
+            (Some(chats), Some(chat_id)) => chats.contains(&chat_id),
+            (Some(_), None) => listed == Some(true),
+        }
+    }
+
+    /// Replace `NewMessage` by a `MessagePreview` in lite mode
+    pub(crate) fn apply(&self, envelope: &mut EventEnvelope) {
+        let NotifyEvent::NewMessage(message) = &envelope.event else {
+            return;
+        };
+        if self.lite {
+            envelope.event = NotifyEvent::MessagePreview(MessagePreview {
+                id: message.id,
+                chat_id: message.chat_id,
+                sender_id: message.sender_id,
+                preview: message.content.chars().take(LITE_PREVIEW_CHARS).collect(),
+                file_count: message.files.len(),
+                created_at: message.created_at,
+            });
+        }
+    }
+}
+
+fn split(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|s| !s.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chat_core::{
+        notify::{PresenceChanged, PresenceStatus, Typing},
+        Message,
+    };
+    use chrono::Utc;
+
+    use super::*;
+
+    fn params(types: Option<&str>, chats: Option<&str>, lite: bool) -> FilterParams {
+        FilterParams {
+            types: types.map(str::to_string),
+            chats: chats.map(str::to_string),
+            lite,
+        }
+    }
+
+    fn ephemeral(event: NotifyEvent) -> UserEvent {
+        UserEvent::Ephemeral(Arc::new(event))
+    }
+
+    fn message(chat_id: RowID) -> NotifyEvent {
+        NotifyEvent::NewMessage(Message {
+            id: 1,
+            chat_id,
+            sender_id: 2,
+            content: "a".repeat(200),
+            files: vec!["/files/1/a.png".to_string()],
+            created_at: Utc::now(),
+        })
+    }
+
+    #[test]
+    fn t_event_filter() {
+        let typing = ephemeral(NotifyEvent::Typing(Typing {
+            chat_id: 2,
+            user_id: 2,
+            typing: true,
+        }));
+        let presence = ephemeral(NotifyEvent::PresenceChanged(PresenceChanged {
+            user_id: 2,
+            status: PresenceStatus::Online,
+        }));
+        let resync = UserEvent::Resync { last_id: None };
+
+        let all = params(None, None, false).parse().unwrap();
+        assert!(all.matches(&typing) && all.matches(&presence));
+
+        let chat = params(None, Some("1"), false).parse().unwrap();
+        assert!(chat.matches(&ephemeral(message(1))));
+        assert!(!chat.matches(&ephemeral(message(2))));
+        assert!(!chat.matches(&typing));
+        assert!(!chat.matches(&presence));
+        assert!(chat.matches(&resync));
+
+        let types = params(Some("NewMessage, PresenceChanged"), Some("1,2"), false)
+            .parse()
+            .unwrap();
+        assert!(types.matches(&ephemeral(message(2))));
+        assert!(!types.matches(&typing));
+        assert!(types.matches(&presence));
+
+        assert!(params(Some("Unknown"), None, false).parse().is_err());
+        assert!(params(None, Some("1,x"), false).parse().is_err());
+    }
+
+    #[test]
+    fn t_lite_mode() {
+        let full = params(None, None, false).parse().unwrap();
+        let mut envelope = EventEnvelope::new(message(1), None, Utc::now());
+        full.apply(&mut envelope);
+        assert!(matches!(&envelope.event, NotifyEvent::NewMessage(m) if m.content.len() == 200));
+
+        let lite = params(None, None, true).parse().unwrap();
+        lite.apply(&mut envelope);
+        let NotifyEvent::MessagePreview(preview) = &envelope.event else {
+            panic!("expect a preview");
+        };
+        assert_eq!(preview.preview.len(), LITE_PREVIEW_CHARS);
+        assert_eq!(preview.file_count, 1);
+    }
+}
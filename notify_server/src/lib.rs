@@ -1,49 +1,164 @@
+mod cluster;
 pub mod config;
+mod digest;
+mod error;
+mod event_log;
+mod filter;
 mod notify_event;
+mod outbox;
+mod preferences;
+mod presence;
+pub mod push;
 mod sse;
+mod subscriber;
+mod typing;
+mod webhook;
+mod ws;
 
-use std::{ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use anyhow::Context;
+use async_trait::async_trait;
 use axum::{
-    middleware::from_fn_with_state,
-    response::{Html, IntoResponse},
-    routing::get,
-    Router,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::{from_fn, from_fn_with_state, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, post},
+    Extension, Json, Router,
 };
 use chat_core::{
     middlewares::{verify_token, VerifyToken},
-    utils::{JwtDecodingKey, UserCliams},
+    utils::{is_api_key, verify_api_key, ApiScope, JwtDecodingKey, UserCliams},
     RowID,
 };
+use cluster::cluster_handler;
+pub use cluster::{setup_registry, sync_registry};
 pub use config::NotifyConfig;
 use dashmap::DashMap;
-use notify_event::{setup_pg_listener, NotifyEvent};
+pub use digest::{record_activity, send_digests, setup_activity, setup_digest};
+use digest::{DigestService, SmtpTransport};
+use error::{AppError, AppResult};
+use event_log::{EventLog, EVENT_LOG_CAPACITY};
+pub use notify_event::setup_pg_listener;
+use notify_event::UserEvent;
+pub use outbox::{prune_events, setup_event_pruning};
+use presence::{list_presence_handler, update_presence_handler, PresenceMap};
+use push::{
+    create_subscription_handler, delete_subscription_handler, push_key_handler, setup_push,
+    HttpTransport, PushService, PushTransport,
+};
+use serde::Serialize;
+use sqlx::PgPool;
 use sse::sse_handler;
-use tokio::{net::TcpListener, sync::broadcast};
-use tracing::info;
+use subscriber::{lagged_handler, stats_handler};
+use tokio::{net::TcpListener, signal, sync::broadcast};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+use typing::{typing_handler, TypingMap};
+use uuid::Uuid;
+use webhook::{
+    create_webhook_handler, delete_webhook_handler, list_deliveries_handler, list_webhooks_handler,
+    replay_delivery_handler, WebhookService,
+};
+pub use webhook::{retry_stale, setup_webhooks};
+use ws::ws_handler;
 
 #[derive(Clone)]
 pub struct NotifyState {
     inner: Arc<NotifyStateInner>,
 }
 
-pub type UserMap = Arc<DashMap<RowID, broadcast::Sender<NotifyEvent>>>;
+pub type UserMap = Arc<DashMap<RowID, broadcast::Sender<UserEvent>>>;
+/// Events dropped for slow consumers per connected user
+pub type LagMap = Arc<DashMap<RowID, u64>>;
 
 pub struct NotifyStateInner {
     config: NotifyConfig,
+    /// Identifies the instance in the registry and in event ids
+    instance_id: String,
     users: UserMap,
+    events: Mutex<EventLog>,
+    typing: TypingMap,
+    presence: PresenceMap,
+    /// Events dropped for slow consumers, per connected user
+    lagged: LagMap,
+    /// Events dropped for slow consumers since the start
+    lagged_total: AtomicU64,
     dk: JwtDecodingKey,
+    db: PgPool,
+    /// The last event consumed from the outbox
+    event_cursor: AtomicI64,
+    /// Web Push delivery, if configured
+    push: Option<PushService>,
+    /// Digest emails, if configured
+    digest: Option<DigestService>,
+    webhook: WebhookService,
+    /// Whether the pg listener is connected
+    listening: AtomicBool,
+    /// Cancelled when the instance is drained
+    shutdown: CancellationToken,
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    instance_id: String,
+    listening: bool,
+    draining: bool,
+}
+
+/// Bots need the `events` scope on their API key for anything here
+async fn ensure_events_scope(
+    Extension(user): Extension<UserCliams>,
+    req: Request,
+    next: Next,
+) -> AppResult<Response> {
+    if !user.allows(ApiScope::Events) {
+        return Err(AppError::forbidden("api key lacks the events scope"));
+    }
+    Ok(next.run(req).await)
 }
 
 pub async fn get_router(state: NotifyState) -> anyhow::Result<Router> {
     let router = Router::new()
         .route("/events", get(sse_handler))
+        .route("/ws", get(ws_handler))
+        .route("/chats/:id/typing", post(typing_handler))
+        .route("/cluster", get(cluster_handler))
+        .route("/stats", get(stats_handler))
+        .route("/stats/lagged", get(lagged_handler))
+        .route(
+            "/presence",
+            get(list_presence_handler).post(update_presence_handler),
+        )
+        .route("/push/key", get(push_key_handler))
+        .route(
+            "/push/subscriptions",
+            post(create_subscription_handler).delete(delete_subscription_handler),
+        )
+        .route(
+            "/webhooks",
+            get(list_webhooks_handler).post(create_webhook_handler),
+        )
+        .route("/webhooks/:id", delete(delete_webhook_handler))
+        .route("/webhooks/:id/deliveries", get(list_deliveries_handler))
+        .route(
+            "/webhooks/:id/deliveries/:delivery_id/replay",
+            post(replay_delivery_handler),
+        )
+        .layer(from_fn(ensure_events_scope))
         .layer(from_fn_with_state(
             state.clone(),
             verify_token::<NotifyState>,
         ))
         .route("/", get(index_handler))
+        .route("/health", get(health_handler))
         .with_state(state.clone());
     Ok(router)
 }
@@ -58,18 +173,63 @@ pub async fn run_server() -> anyhow::Result<()> {
     setup_pg_listener(state.clone())
         .await
         .context("setup pg listener")?;
+    setup_registry(state.clone());
+    setup_event_pruning(state.clone());
+    setup_activity(state.clone());
+    setup_digest(state.clone());
+    setup_push(state.clone());
+    setup_webhooks(state.clone());
 
     let router = get_router(state.clone()).await?;
     let listener = TcpListener::bind(addr).await?;
-    axum::serve(listener, router).await?;
+    axum::serve(listener, router)
+        .with_graceful_shutdown(shutdown_signal(state))
+        .await?;
     Ok(())
 }
 
+async fn shutdown_signal(state: NotifyState) {
+    let ctrl_c = async {
+        let _ = signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        if let Ok(mut sig) = signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            sig.recv().await;
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    if let Err(e) = state.drain().await {
+        warn!("failed to drain: {:#}", e);
+    }
+}
+
 const INDEX_HTML: &str = include_str!("../index.html");
 async fn index_handler() -> impl IntoResponse {
     Html(INDEX_HTML)
 }
 
+/// Unavailable while notifications can't be received or the instance is drained
+async fn health_handler(State(state): State<NotifyState>) -> impl IntoResponse {
+    let health = Health {
+        instance_id: state.instance_id.clone(),
+        listening: state.listening.load(Ordering::Relaxed),
+        draining: state.shutdown.is_cancelled(),
+    };
+    let code = if health.listening && !health.draining {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(health))
+}
+
 impl Deref for NotifyState {
     type Target = NotifyStateInner;
 
@@ -81,18 +241,215 @@ impl Deref for NotifyState {
 
 impl NotifyState {
     pub fn try_new(config: NotifyConfig) -> anyhow::Result<Self> {
+        let db = PgPool::connect_lazy(&config.server.db_url).context("invalid db_url")?;
+        Self::new(config, db)
+    }
+
+    pub fn new(config: NotifyConfig, db: PgPool) -> anyhow::Result<Self> {
+        Self::new_with_transport(config, db, Box::new(HttpTransport::new()?))
+    }
+
+    /// Push messages are sent through `transport` instead of HTTP
+    pub fn new_with_transport(
+        config: NotifyConfig,
+        db: PgPool,
+        transport: Box<dyn PushTransport>,
+    ) -> anyhow::Result<Self> {
+        let instance_id = Uuid::now_v7().to_string();
         let users = Arc::new(DashMap::new());
+        let events = Mutex::new(EventLog::new(EVENT_LOG_CAPACITY));
+        let typing = Arc::new(DashMap::new());
+        let presence = Arc::new(DashMap::new());
         let dk = JwtDecodingKey::load(config.auth.pk.as_bytes())?;
+        let push = match &config.push {
+            Some(push) => Some(PushService::new(push, transport)?),
+            None => None,
+        };
+        let digest = match &config.digest {
+            Some(digest) => Some(DigestService::new(
+                digest,
+                Box::new(SmtpTransport::new(&digest.smtp)?),
+            )?),
+            None => None,
+        };
+        let webhook = WebhookService::new(&config.webhook)?;
         Ok(Self {
-            inner: Arc::new(NotifyStateInner { config, users, dk }),
+            inner: Arc::new(NotifyStateInner {
+                config,
+                instance_id,
+                users,
+                events,
+                typing,
+                presence,
+                lagged: Default::default(),
+                lagged_total: AtomicU64::new(0),
+                dk,
+                db,
+                event_cursor: AtomicI64::new(0),
+                push,
+                digest,
+                webhook,
+                listening: AtomicBool::new(false),
+                shutdown: CancellationToken::new(),
+            }),
         })
     }
 }
 
+#[cfg(test)]
+impl NotifyState {
+    pub fn new_for_test(db: PgPool) -> Self {
+        Self::new(NotifyConfig::new_for_test(), db).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl NotifyConfig {
+    pub fn new_for_test() -> Self {
+        NotifyConfig {
+            server: config::ServerConfig {
+                host: "127.0.0.1".to_string(),
+                port: 0,
+                db_url: "".to_string(),
+            },
+            auth: config::AuthConfig {
+                pk: include_str!("../../fixtures/public.pem").to_string(),
+            },
+            push: None,
+            digest: None,
+            stream: Default::default(),
+            webhook: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
 impl VerifyToken for NotifyState {
     type Error = anyhow::Error;
 
-    fn verify(&self, token: &str) -> Result<UserCliams, Self::Error> {
+    async fn verify(&self, token: &str) -> Result<UserCliams, Self::Error> {
+        if is_api_key(token) {
+            return verify_api_key(&self.db, token).await;
+        }
         self.dk.verify(token)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use chat_core::utils::{generate_api_key, JwtEncodingKey};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn create_key(state: &NotifyState, bot_id: RowID, scopes: &[ApiScope]) -> String {
+        let (key, hash) = generate_api_key();
+        sqlx::query(
+            r#"
+                INSERT INTO api_keys (user_id, ws_id, name, key_hash, prefix, scopes, created_by)
+                VALUES ($1, 1, 'test', $2, 'chat_', $3, 1)
+            "#,
+        )
+        .bind(bot_id)
+        .bind(hash)
+        .bind(scopes)
+        .execute(&state.db)
+        .await
+        .unwrap();
+        key
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_api_key_routes(pool: PgPool) {
+        let state = NotifyState::new_for_test(pool);
+        let router = get_router(state.clone()).await.unwrap();
+        let status = |token: String| {
+            let req = Request::builder()
+                .uri("/presence")
+                .header("Authorization", format!("Bearer {token}"))
+                .body(Body::empty())
+                .unwrap();
+            let router = router.clone();
+            async move { router.oneshot(req).await.unwrap().status() }
+        };
+
+        let bot_id: RowID = sqlx::query_scalar(
+            r#"
+                INSERT INTO users (fullname, email, password_hash, ws_id, is_bot)
+                VALUES ('ci', 'ci@bots.invalid', '', 1, TRUE) RETURNING id
+            "#,
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        let events = create_key(&state, bot_id, &[ApiScope::Events]).await;
+        let write = create_key(&state, bot_id, &[ApiScope::Read, ApiScope::Write]).await;
+
+        // users are not limited by scopes
+        let ek = JwtEncodingKey::load(include_bytes!("../../fixtures/private.pem")).unwrap();
+        let token = ek
+            .sign(&UserCliams {
+                uid: 1,
+                ws_id: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(status(token).await, StatusCode::OK);
+
+        // bots need the events scope
+        assert_eq!(status(events.clone()).await, StatusCode::OK);
+        assert_eq!(status(write).await, StatusCode::FORBIDDEN);
+
+        // a revoked key is rejected right away
+        sqlx::query("UPDATE api_keys SET revoked_at = NOW() WHERE user_id = $1")
+            .bind(bot_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(status(events).await, StatusCode::FORBIDDEN);
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_stats_routes(pool: PgPool) {
+        let state = NotifyState::new_for_test(pool);
+        let router = get_router(state.clone()).await.unwrap();
+        let ek = JwtEncodingKey::load(include_bytes!("../../fixtures/private.pem")).unwrap();
+        let status = |uri: &'static str, uid: Option<RowID>| {
+            let mut req = Request::builder().uri(uri);
+            if let Some(uid) = uid {
+                let user = UserCliams {
+                    uid,
+                    ws_id: 1,
+                    ..Default::default()
+                };
+                req = req.header(
+                    "Authorization",
+                    format!("Bearer {}", ek.sign(&user).unwrap()),
+                );
+            }
+            let router = router.clone();
+            async move {
+                let req = req.body(Body::empty()).unwrap();
+                router.oneshot(req).await.unwrap().status()
+            }
+        };
+
+        // only the health check is open, unavailable as nothing listens in tests
+        assert_eq!(
+            status("/health", None).await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        for uri in ["/stats", "/stats/lagged", "/cluster"] {
+            assert_eq!(status(uri, None).await, StatusCode::UNAUTHORIZED, "{}", uri);
+            assert_eq!(status(uri, Some(2)).await, StatusCode::FORBIDDEN, "{}", uri);
+            assert_eq!(status(uri, Some(1)).await, StatusCode::OK, "{}", uri);
+        }
+    }
+}
@@ -1,83 +1,112 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
-use anyhow::{anyhow, bail, Context};
-use chat_core::{Chat, Message, RowID};
-use futures::StreamExt;
-use serde::{Deserialize, Serialize};
+use anyhow::Context;
+use chat_core::{
+    event::EVENTS_CHANNEL,
+    notify::{Disconnect, EventEnvelope, NotifyEvent, Resync},
+    RowID,
+};
+use chrono::Utc;
 use sqlx::postgres::{PgListener, PgNotification};
+use tokio::time;
 use tracing::{error, info, warn};
 
-use crate::NotifyState;
+use crate::{
+    cluster::{broadcast_ephemeral, receive_ephemeral, Audience, EPHEMERAL_CHANNEL},
+    event_log::{format_event_id, EventRecord},
+    outbox::{consume, init_cursor},
+    NotifyState,
+};
 
-#[derive(Debug, Clone, Serialize)]
-pub enum NotifyEvent {
-    NewChat(Chat),
-    AddToChat(Chat),
-    RemoveFromChat(Chat),
-    NewMessage(Message),
-}
-
-pub struct AppNotification {
-    users: HashSet<RowID>,
-    event: NotifyEvent,
-}
-
-#[allow(unused)]
-#[derive(Debug, Deserialize)]
+/// Channels the pg listener subscribes to
+const LISTEN_CHANNELS: [&str; 2] = [EVENTS_CHANNEL, EPHEMERAL_CHANNEL];
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
-struct ChatUpdatedNotification {
-    // delete/update/insert
-    op: String,
-    old: Option<Chat>,
-    new: Option<Chat>,
+/// What is delivered to the streams of a user
+#[derive(Debug, Clone)]
+pub enum UserEvent {
+    /// Recorded in the event log and can be replayed
+    Logged(Arc<EventRecord>),
+    /// Not recorded, lost if the user isn't connected
+    Ephemeral(Arc<NotifyEvent>),
+    /// Events were lost and the client has to reload its state.
+    /// `last_id` is the id to resume from afterwards, if known.
+    Resync { last_id: Option<u64> },
+    /// The stream is closed after this, e.g. the consumer is too slow
+    Disconnect { reason: &'static str },
 }
 
-#[derive(Debug, Deserialize)]
-struct NewMessageNotification {
-    message: Message,
-    members: Vec<RowID>,
+pub struct AppNotification {
+    pub users: HashSet<RowID>,
+    /// Recipients who don't want to be notified, the event still updates their UI
+    pub silent: HashSet<RowID>,
+    pub event: NotifyEvent,
 }
 
+/// Connect to the database and keep delivering notifications, reconnecting with backoff.
+/// Fails only if the first connection can't be established.
 pub async fn setup_pg_listener(state: NotifyState) -> anyhow::Result<()> {
-    let mut ls = PgListener::connect(&state.config.server.db_url)
-        .await
-        .context("failed to create pg listener")?;
-    ls.listen("chat_updated")
-        .await
-        .context("listen chat_updated")?;
-    ls.listen("chat_message_created")
-        .await
-        .context("listen chat_message_created")?;
-
-    let mut stream = ls.into_stream();
+    let ls = connect_listener(&state).await?;
+    init_cursor(&state).await?;
+    // events appended while no instance was running
+    consume(&state).await?;
+    state.listening.store(true, Ordering::Relaxed);
 
     tokio::spawn(async move {
-        while let Some(nf) = stream.next().await {
-            let nf = match nf {
-                Err(e) => {
-                    error!("failed to receive pg notification: {}", e);
-                    break;
-                }
-                Ok(nf) => nf,
-            };
-            info!("Receive notification: {:?}", nf);
-
-            let nf: AppNotification = match nf.try_into() {
-                Ok(nf) => nf,
-                Err(e) => {
-                    error!("failed to parse pg notification: {:#}", e);
-                    continue;
+        let mut ls = Some(ls);
+        let mut backoff = MIN_BACKOFF;
+        loop {
+            let mut listener = match ls.take() {
+                Some(ls) => ls,
+                None => {
+                    tokio::select! {
+                        _ = time::sleep(backoff) => {}
+                        _ = state.shutdown.cancelled() => break,
+                    }
+                    match connect_listener(&state).await {
+                        Ok(ls) => {
+                            info!("pg listener reconnected");
+                            backoff = MIN_BACKOFF;
+                            state.listening.store(true, Ordering::Relaxed);
+                            resync_all(&state);
+                            // events appended during the outage
+                            if let Err(e) = consume(&state).await {
+                                error!("failed to consume events: {:#}", e);
+                            }
+                            ls
+                        }
+                        Err(e) => {
+                            warn!("failed to reconnect pg listener: {:#}", e);
+                            backoff = (backoff * 2).min(MAX_BACKOFF);
+                            continue;
+                        }
+                    }
                 }
             };
 
-            nf.users
-                .iter()
-                .filter_map(|uid| state.users.get(uid))
-                .for_each(|kv| {
-                    if let Err(e) = kv.send(nf.event.clone()) {
-                        error!("failed to send notification to {}: {}", kv.key(), e);
+            loop {
+                let nf = tokio::select! {
+                    nf = listener.try_recv() => nf,
+                    _ = state.shutdown.cancelled() => return,
+                };
+                match nf {
+                    Ok(Some(nf)) => handle_notification(&state, nf).await,
+                    Ok(None) => {
+                        error!("pg listener connection lost");
+                        break;
                     }
-                });
+                    Err(e) => {
+                        error!("failed to receive pg notification: {}", e);
+                        break;
+                    }
+                }
+            }
+            state.listening.store(false, Ordering::Relaxed);
         }
 
         warn!("pg listener exit");
@@ -86,81 +115,203 @@ pub async fn setup_pg_listener(state: NotifyState) -> anyhow::Result<()> {
     Ok(())
 }
 
-impl TryFrom<PgNotification> for AppNotification {
-    type Error = anyhow::Error;
-
-    fn try_from(nf: PgNotification) -> Result<Self, Self::Error> {
-        let payload = nf.payload();
-        let channel = nf.channel();
-        match channel {
-            "chat_updated" => {
-                let payload = serde_json::from_str::<ChatUpdatedNotification>(payload)
-                    .with_context(|| format!("invalid chat_updated payload: {}", payload))?;
-                payload.try_into()
-            }
-            "chat_message_created" => {
-                let payload = serde_json::from_str::<NewMessageNotification>(payload)
-                    .with_context(|| {
-                        format!("invalid chat_message_created payload: {}", payload)
-                    })?;
-                payload.try_into()
-            }
-            _ => Err(anyhow!("invalid pg notification channel: {}", channel)),
-        }
+async fn connect_listener(state: &NotifyState) -> anyhow::Result<PgListener> {
+    let mut ls = PgListener::connect_with(&state.db)
+        .await
+        .context("failed to create pg listener")?;
+    ls.listen_all(LISTEN_CHANNELS)
+        .await
+        .with_context(|| format!("listen {:?}", LISTEN_CHANNELS))?;
+    Ok(ls)
+}
+
+async fn handle_notification(state: &NotifyState, nf: PgNotification) {
+    info!("Receive notification: {:?}", nf);
+
+    if nf.channel() == EPHEMERAL_CHANNEL {
+        receive_ephemeral(state, nf.payload());
+        return;
+    }
+
+    if let Err(e) = consume(state).await {
+        error!("failed to consume events: {:#}", e);
     }
 }
 
-impl TryFrom<NewMessageNotification> for AppNotification {
-    type Error = anyhow::Error;
-    fn try_from(value: NewMessageNotification) -> Result<Self, Self::Error> {
-        let users = value.members.iter().copied().collect();
-        Ok(AppNotification {
-            users,
-            event: NotifyEvent::NewMessage(value.message),
-        })
+/// Notifications sent while the listener was disconnected are lost,
+/// tell every connected user to reload its state
+fn resync_all(state: &NotifyState) {
+    let mut events = state.events.lock().unwrap();
+    let last_id = events.push_gap();
+    for kv in state.users.iter() {
+        // nobody is listening
+        let _ = kv.send(UserEvent::Resync {
+            last_id: Some(last_id),
+        });
     }
 }
 
-impl TryFrom<ChatUpdatedNotification> for AppNotification {
-    type Error = anyhow::Error;
-
-    fn try_from(value: ChatUpdatedNotification) -> Result<Self, Self::Error> {
-        match (value.old, value.new) {
-            // update
-            (Some(old), Some(new)) => {
-                let old_users: HashSet<_> = old.members.iter().copied().collect();
-                let new_users: HashSet<_> = new.members.iter().copied().collect();
-                if old_users == new_users {
-                    Ok(AppNotification {
-                        users: HashSet::default(),
-                        event: NotifyEvent::AddToChat(new),
-                    })
-                } else {
-                    let users = old_users.union(&new_users).copied().collect();
-
-                    Ok(AppNotification {
-                        users,
-                        event: NotifyEvent::AddToChat(new),
-                    })
-                }
+/// Record the event for replay and send it to the connected users
+pub(crate) fn publish(state: &NotifyState, nf: AppNotification) {
+    // send under the lock, so every user receives events in id order
+    let mut events = state.events.lock().unwrap();
+    let record = events.push(
+        nf.users.into_iter().collect(),
+        nf.silent.into_iter().collect(),
+        nf.event,
+    );
+    record
+        .users
+        .iter()
+        .filter_map(|uid| state.users.get(uid))
+        .for_each(|kv| {
+            if let Err(e) = kv.send(UserEvent::Logged(record.clone())) {
+                error!("failed to send notification to {}: {}", kv.key(), e);
             }
-            // insert
-            (None, Some(new)) => {
-                let users = new.members.iter().copied().collect();
-                Ok(AppNotification {
-                    users,
-                    event: NotifyEvent::NewChat(new),
-                })
+        });
+}
+
+/// Send an event that is not recorded to the connected users of every instance
+pub(crate) fn publish_ephemeral(state: &NotifyState, audience: Audience, event: NotifyEvent) {
+    deliver_ephemeral(state, &audience, Arc::new(event.clone()));
+    broadcast_ephemeral(state, audience, event);
+}
+
+/// Send an event that is not recorded to the connected users of this instance
+pub(crate) fn deliver_ephemeral(state: &NotifyState, audience: &Audience, event: Arc<NotifyEvent>) {
+    for uid in audience.resolve(state) {
+        if let Some(tx) = state.users.get(&uid) {
+            // nobody is listening
+            let _ = tx.send(UserEvent::Ephemeral(event.clone()));
+        }
+    }
+}
+
+impl UserEvent {
+    /// The event on the wire, as received by `uid`
+    pub fn envelope(&self, instance_id: &str, uid: RowID) -> EventEnvelope {
+        match self {
+            UserEvent::Logged(record) => {
+                let id = format_event_id(instance_id, record.id);
+                let mut envelope = EventEnvelope::new(record.event.clone(), Some(id), record.ts);
+                envelope.silent = record.silent.contains(&uid);
+                envelope
+            }
+            UserEvent::Ephemeral(event) => {
+                EventEnvelope::new(event.as_ref().clone(), None, Utc::now())
             }
-            (Some(old), None) => {
-                let users = old.members.iter().copied().collect();
-                Ok(AppNotification {
-                    users,
-                    event: NotifyEvent::RemoveFromChat(old),
-                })
+            UserEvent::Resync { last_id } => {
+                let last_id = last_id.map(|id| format_event_id(instance_id, id));
+                let event = NotifyEvent::Resync(Resync {
+                    last_id: last_id.clone(),
+                });
+                EventEnvelope::new(event, last_id, Utc::now())
+            }
+            UserEvent::Disconnect { reason } => {
+                let event = NotifyEvent::Disconnect(Disconnect {
+                    reason: reason.to_string(),
+                });
+                EventEnvelope::new(event, None, Utc::now())
             }
-            // no change
-            _ => bail!("chat_updated: (None, None)"),
         }
     }
+
+    pub fn id(&self) -> Option<u64> {
+        match self {
+            UserEvent::Logged(record) => Some(record.id),
+            UserEvent::Ephemeral(_) => None,
+            UserEvent::Resync { last_id } => *last_id,
+            UserEvent::Disconnect { .. } => None,
+        }
+    }
+
+    pub fn event(&self) -> Option<&NotifyEvent> {
+        match self {
+            UserEvent::Logged(record) => Some(&record.event),
+            UserEvent::Ephemeral(event) => Some(event),
+            UserEvent::Resync { .. } | UserEvent::Disconnect { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chat_core::utils::UserCliams;
+    use futures::StreamExt;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{outbox::tests::append_message, subscriber::user_events};
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_pg_listener_reconnect(pool: PgPool) {
+        let state = NotifyState::new_for_test(pool.clone());
+        setup_pg_listener(state.clone()).await.unwrap();
+        assert!(state.listening.load(Ordering::Relaxed));
+
+        let user = UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        };
+        let mut events = Box::pin(user_events(&state, &user, None));
+
+        // drop the listener connection
+        sqlx::query(
+            r#"
+            SELECT pg_terminate_backend(pid) FROM pg_stat_activity
+            WHERE datname = current_database() AND pid <> pg_backend_pid()
+                AND query LIKE 'LISTEN%'
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // clients are told to resync once it is back
+        let ev = events.next().await.unwrap();
+        assert!(matches!(ev, UserEvent::Resync { last_id: Some(1) }));
+        assert!(state.listening.load(Ordering::Relaxed));
+
+        append_message(&pool, 10, "hi").await;
+        let ev = events.next().await.unwrap();
+        assert!(matches!(ev.event(), Some(NotifyEvent::NewMessage(_))));
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_resume_after_outage(pool: PgPool) {
+        // events before the first instance ever started are skipped
+        append_message(&pool, 10, "history").await;
+        let a = NotifyState::new_for_test(pool.clone());
+        setup_pg_listener(a.clone()).await.unwrap();
+        append_message(&pool, 11, "consumed").await;
+        while a.event_cursor.load(Ordering::Relaxed) < 2 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        a.drain().await.unwrap();
+
+        // appended while no instance is running
+        append_message(&pool, 12, "missed").await;
+
+        let b = NotifyState::new_for_test(pool.clone());
+        let user = UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        };
+        let mut events = Box::pin(user_events(&b, &user, None));
+        setup_pg_listener(b.clone()).await.unwrap();
+        let ev = events.next().await.unwrap();
+        let Some(NotifyEvent::NewMessage(message)) = ev.event() else {
+            panic!("unexpected event: {:?}", ev);
+        };
+        assert_eq!(message.content, "missed");
+        assert_eq!(b.event_cursor.load(Ordering::Relaxed), 3);
+    }
 }
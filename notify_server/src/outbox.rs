@@ -0,0 +1,436 @@
+use std::{collections::HashSet, sync::atomic::Ordering, time::Duration};
+
+use chat_core::{
+    event::ChatEvent,
+    notify::{ChatChange, NotifyEvent},
+    Chat, RowID,
+};
+use sqlx::types::Json;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{
+    notify_event::{publish, AppNotification},
+    preferences::silent_users,
+    push::notify_offline,
+    webhook::notify_webhooks,
+    NotifyState,
+};
+
+/// Events loaded per query while catching up
+const BATCH_SIZE: i64 = 100;
+/// Events consumed by every instance are kept this long
+pub const EVENT_RETENTION_SECS: f64 = 3600.0;
+/// How often old events are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Resume after the last event consumed by any instance, so events appended while every
+/// instance was down are still delivered. The first instance ever starts after the last event.
+pub(crate) async fn init_cursor(state: &NotifyState) -> anyhow::Result<()> {
+    let last_id: RowID = sqlx::query_scalar(
+        r#"
+        INSERT INTO notify_cursor (last_event_id)
+        SELECT COALESCE(MAX(id), 0) FROM events
+        ON CONFLICT (id) DO UPDATE SET id = notify_cursor.id
+        RETURNING last_event_id
+        "#,
+    )
+    .fetch_one(&state.db)
+    .await?;
+    state.event_cursor.store(last_id, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Publish the events after the cursor in id order, the cursor is moved past each of them.
+/// Only called by the pg listener task, so events are never consumed twice.
+pub(crate) async fn consume(state: &NotifyState) -> anyhow::Result<()> {
+    loop {
+        let cursor = state.event_cursor.load(Ordering::Relaxed);
+        let events: Vec<(RowID, RowID, Json<ChatEvent>)> = sqlx::query_as(
+            "SELECT id, ws_id, payload FROM events WHERE id > $1 ORDER BY id LIMIT $2",
+        )
+        .bind(cursor)
+        .bind(BATCH_SIZE)
+        .fetch_all(&state.db)
+        .await?;
+        let n = events.len() as i64;
+        for (id, ws_id, Json(event)) in events {
+            for mut nf in notifications(event) {
+                if let NotifyEvent::NewMessage(message) = &nf.event {
+                    nf.silent = silent_users(&state.db, ws_id, message, &nf.users).await?;
+                }
+                notify_offline(state, id, &nf);
+                notify_webhooks(state, id, ws_id, &nf);
+                publish(state, nf);
+            }
+            state.event_cursor.store(id, Ordering::Relaxed);
+        }
+        if n > 0 {
+            save_cursor(state).await?;
+        }
+        if n < BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+/// Record the progress, instances consuming concurrently only move it forward
+async fn save_cursor(state: &NotifyState) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+        UPDATE notify_cursor SET last_event_id = GREATEST(last_event_id, $1), updated_at = NOW()
+        "#,
+    )
+    .bind(state.event_cursor.load(Ordering::Relaxed))
+    .execute(&state.db)
+    .await?;
+    Ok(())
+}
+
+/// Remove old events periodically until the instance is drained
+pub fn setup_event_pruning(state: NotifyState) {
+    let mut interval = time::interval(PRUNE_INTERVAL);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.shutdown.cancelled() => break,
+            }
+            match prune_events(&state).await {
+                Ok(0) => {}
+                Ok(n) => info!("pruned {} events", n),
+                Err(e) => warn!("failed to prune events: {:#}", e),
+            }
+        }
+    });
+}
+
+/// Remove events older than the retention that every live instance has consumed,
+/// return the number of removed events
+pub async fn prune_events(state: &NotifyState) -> anyhow::Result<u64> {
+    let ret = sqlx::query(
+        r#"
+        DELETE FROM events WHERE created_at < NOW() - make_interval(secs => $1)
+            AND id <= (SELECT MIN(last_event_id) FROM notify_instances)
+        "#,
+    )
+    .bind(EVENT_RETENTION_SECS)
+    .execute(&state.db)
+    .await?;
+    Ok(ret.rows_affected())
+}
+
+/// Events for the users affected by the change
+pub(crate) fn notifications(event: ChatEvent) -> Vec<AppNotification> {
+    match event {
+        ChatEvent::ChatCreated { chat, .. } => vec![AppNotification {
+            users: chat.members.iter().copied().collect(),
+            silent: HashSet::default(),
+            event: NotifyEvent::NewChat(chat),
+        }],
+        ChatEvent::ChatUpdated { actor, old, new } => chat_updated(actor, old, new),
+        ChatEvent::ChatDeleted { actor, chat } => {
+            let users = chat.members.iter().copied().collect();
+            let members = chat.members.clone();
+            vec![AppNotification {
+                users,
+                silent: HashSet::default(),
+                event: NotifyEvent::RemovedFromChat(ChatChange {
+                    actor,
+                    chat,
+                    members,
+                }),
+            }]
+        }
+        ChatEvent::MessageCreated { message, members } => vec![AppNotification {
+            users: members.into_iter().collect(),
+            silent: HashSet::default(),
+            event: NotifyEvent::NewMessage(message),
+        }],
+    }
+}
+
+fn chat_updated(actor: RowID, old: Chat, new: Chat) -> Vec<AppNotification> {
+    let old_members: HashSet<_> = old.members.iter().copied().collect();
+    let new_members: HashSet<_> = new.members.iter().copied().collect();
+    let added: Vec<_> = new
+        .members
+        .iter()
+        .copied()
+        .filter(|uid| !old_members.contains(uid))
+        .collect();
+    let removed: Vec<_> = old
+        .members
+        .iter()
+        .copied()
+        .filter(|uid| !new_members.contains(uid))
+        .collect();
+
+    let mut ret = vec![];
+    if !added.is_empty() {
+        ret.push(AppNotification {
+            users: new_members.clone(),
+            silent: HashSet::default(),
+            event: NotifyEvent::MemberAdded(ChatChange {
+                actor,
+                chat: new.clone(),
+                members: added,
+            }),
+        });
+    }
+    if !removed.is_empty() {
+        ret.push(AppNotification {
+            users: removed.iter().copied().collect(),
+            silent: HashSet::default(),
+            event: NotifyEvent::RemovedFromChat(ChatChange {
+                actor,
+                chat: new.clone(),
+                members: removed,
+            }),
+        });
+    }
+    if old.name != new.name || old.typ != new.typ {
+        ret.push(AppNotification {
+            users: new_members,
+            silent: HashSet::default(),
+            event: NotifyEvent::ChatUpdated(ChatChange {
+                actor,
+                chat: new,
+                members: vec![],
+            }),
+        });
+    }
+    ret
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use chat_core::{event::EVENTS_CHANNEL, utils::UserCliams, ChatType, Message};
+    use chrono::Utc;
+    use futures::StreamExt;
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{cluster::sync_registry, subscriber::user_events};
+
+    /// Append a message event the way chat_server does
+    pub(crate) async fn append_message(pool: &PgPool, id: RowID, content: &str) {
+        let message = Message {
+            id,
+            chat_id: 1,
+            sender_id: 2,
+            content: content.to_string(),
+            files: vec![],
+            created_at: Utc::now(),
+        };
+        let event = ChatEvent::MessageCreated {
+            message,
+            members: vec![1, 2],
+        };
+        let id: RowID =
+            sqlx::query_scalar("INSERT INTO events (ws_id, payload) VALUES (1, $1) RETURNING id")
+                .bind(Json(event))
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(EVENTS_CHANNEL)
+            .bind(id.to_string())
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_consume_events(pool: PgPool) {
+        let state = NotifyState::new_for_test(pool.clone());
+        // events before the instance started are skipped
+        append_message(&pool, 1, "old").await;
+        init_cursor(&state).await.unwrap();
+        let start = state.event_cursor.load(Ordering::Relaxed);
+
+        let user = UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        };
+        let mut events = Box::pin(user_events(&state, &user, None));
+        for i in 2..5 {
+            append_message(&pool, i, &i.to_string()).await;
+        }
+        consume(&state).await.unwrap();
+        assert_eq!(state.event_cursor.load(Ordering::Relaxed), start + 3);
+
+        for i in 2..5 {
+            let ev = events.next().await.unwrap();
+            let Some(NotifyEvent::NewMessage(message)) = ev.event() else {
+                panic!("unexpected event: {:?}", ev);
+            };
+            assert_eq!(message.id, i);
+        }
+
+        // nothing left
+        consume(&state).await.unwrap();
+        assert_eq!(state.event_cursor.load(Ordering::Relaxed), start + 3);
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_prune_events(pool: PgPool) {
+        let state = NotifyState::new_for_test(pool.clone());
+        append_message(&pool, 1, "a").await;
+        append_message(&pool, 2, "b").await;
+        sqlx::query("UPDATE events SET created_at = NOW() - INTERVAL '2 hours'")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // kept until an instance has consumed them
+        assert_eq!(prune_events(&state).await.unwrap(), 0);
+
+        let first: RowID = sqlx::query_scalar("SELECT MIN(id) FROM events")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        state.event_cursor.store(first, Ordering::Relaxed);
+        sync_registry(&state).await.unwrap();
+        assert_eq!(prune_events(&state).await.unwrap(), 1);
+    }
+
+    fn chat(name: Option<&str>, typ: ChatType, members: &[RowID]) -> Chat {
+        Chat {
+            id: 1,
+            ws_id: 1,
+            name: name.map(|n| n.to_string()),
+            typ,
+            members: members.to_vec(),
+            created_at: Utc::now(),
+        }
+    }
+
+    fn updated(old: Chat, new: Chat) -> Vec<AppNotification> {
+        notifications(ChatEvent::ChatUpdated { actor: 1, old, new })
+    }
+
+    fn change(nf: &AppNotification) -> &ChatChange {
+        match &nf.event {
+            NotifyEvent::MemberAdded(c)
+            | NotifyEvent::RemovedFromChat(c)
+            | NotifyEvent::ChatUpdated(c) => c,
+            ev => panic!("unexpected event: {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn t_chat_created_and_deleted() {
+        let event = ChatEvent::ChatCreated {
+            actor: 1,
+            chat: chat(None, ChatType::Single, &[1, 2]),
+        };
+        let nfs = notifications(event);
+        assert_eq!(nfs.len(), 1);
+        assert_eq!(nfs[0].users, HashSet::from([1, 2]));
+        assert!(matches!(nfs[0].event, NotifyEvent::NewChat(_)));
+
+        let event = ChatEvent::ChatDeleted {
+            actor: 2,
+            chat: chat(None, ChatType::Single, &[1, 2]),
+        };
+        let nfs = notifications(event);
+        assert_eq!(nfs.len(), 1);
+        assert_eq!(nfs[0].users, HashSet::from([1, 2]));
+        assert!(matches!(nfs[0].event, NotifyEvent::RemovedFromChat(_)));
+        assert_eq!(change(&nfs[0]).actor, 2);
+        assert_eq!(change(&nfs[0]).members, vec![1, 2]);
+    }
+
+    #[test]
+    fn t_members_added() {
+        let old = chat(None, ChatType::Group, &[1, 2, 3]);
+        let new = chat(None, ChatType::Group, &[1, 2, 3, 4, 5]);
+        let nfs = updated(old, new);
+        assert_eq!(nfs.len(), 1);
+        assert!(matches!(nfs[0].event, NotifyEvent::MemberAdded(_)));
+        // existing members learn about the new ones
+        assert_eq!(nfs[0].users, HashSet::from([1, 2, 3, 4, 5]));
+        assert_eq!(change(&nfs[0]).actor, 1);
+        assert_eq!(change(&nfs[0]).members, vec![4, 5]);
+    }
+
+    #[test]
+    fn t_members_removed() {
+        let old = chat(None, ChatType::Group, &[1, 2, 3, 4]);
+        let new = chat(None, ChatType::Group, &[1, 2]);
+        let nfs = updated(old, new);
+        assert_eq!(nfs.len(), 1);
+        assert!(matches!(nfs[0].event, NotifyEvent::RemovedFromChat(_)));
+        assert_eq!(nfs[0].users, HashSet::from([3, 4]));
+        assert_eq!(change(&nfs[0]).members, vec![3, 4]);
+    }
+
+    #[test]
+    fn t_members_replaced() {
+        let old = chat(None, ChatType::Group, &[1, 2, 3]);
+        let new = chat(None, ChatType::Group, &[1, 2, 4]);
+        let nfs = updated(old, new);
+        assert_eq!(nfs.len(), 2);
+        assert!(matches!(nfs[0].event, NotifyEvent::MemberAdded(_)));
+        assert_eq!(nfs[0].users, HashSet::from([1, 2, 4]));
+        assert_eq!(change(&nfs[0]).members, vec![4]);
+        assert!(matches!(nfs[1].event, NotifyEvent::RemovedFromChat(_)));
+        assert_eq!(nfs[1].users, HashSet::from([3]));
+        assert_eq!(change(&nfs[1]).members, vec![3]);
+    }
+
+    #[test]
+    fn t_chat_renamed() {
+        let old = chat(Some("a"), ChatType::PublicChannel, &[1, 2]);
+        let new = chat(Some("b"), ChatType::PublicChannel, &[1, 2]);
+        let nfs = updated(old, new);
+        assert_eq!(nfs.len(), 1);
+        assert!(matches!(nfs[0].event, NotifyEvent::ChatUpdated(_)));
+        assert_eq!(nfs[0].users, HashSet::from([1, 2]));
+        assert_eq!(change(&nfs[0]).chat.name.as_deref(), Some("b"));
+        assert!(change(&nfs[0]).members.is_empty());
+
+        // type change together with a new member
+        let old = chat(Some("b"), ChatType::PublicChannel, &[1, 2]);
+        let new = chat(Some("b"), ChatType::PrivateChannel, &[1, 2, 3]);
+        let nfs = updated(old, new);
+        assert_eq!(nfs.len(), 2);
+        assert!(matches!(nfs[0].event, NotifyEvent::MemberAdded(_)));
+        assert!(matches!(nfs[1].event, NotifyEvent::ChatUpdated(_)));
+        assert_eq!(nfs[1].users, HashSet::from([1, 2, 3]));
+    }
+
+    #[test]
+    fn t_chat_unchanged() {
+        let old = chat(None, ChatType::Group, &[1, 2, 3]);
+        let new = chat(None, ChatType::Group, &[3, 2, 1]);
+        assert!(updated(old, new).is_empty());
+    }
+
+    #[test]
+    fn t_message_created() {
+        let message = Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 2,
+            content: "hi".to_string(),
+            files: vec![],
+            created_at: Utc::now(),
+        };
+        let nfs = notifications(ChatEvent::MessageCreated {
+            message,
+            members: vec![1, 2],
+        });
+        assert_eq!(nfs.len(), 1);
+        assert_eq!(nfs[0].users, HashSet::from([1, 2]));
+        assert!(matches!(nfs[0].event, NotifyEvent::NewMessage(_)));
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+
+use chat_core::{Message, RowID};
+use sqlx::PgPool;
+
+/// Recipients of the message who muted the chat, only want mentions and weren't mentioned,
+/// or are in their do-not-disturb window. Settings are stored by chat_server.
+pub(crate) async fn silent_users(
+    db: &PgPool,
+    ws_id: RowID,
+    message: &Message,
+    users: &HashSet<RowID>,
+) -> anyhow::Result<HashSet<RowID>> {
+    let users: Vec<RowID> = users.iter().copied().collect();
+    let silent: Vec<RowID> = sqlx::query_scalar(
+        r#"
+        SELECT u.id FROM UNNEST($1::BIGINT[]) AS u(id)
+        WHERE EXISTS (
+            SELECT 1 FROM chat_settings s
+            WHERE s.user_id = u.id AND s.chat_id = $2
+                AND (s.muted_until > NOW() OR (s.level = 'mentions' AND NOT u.id = ANY($3)))
+        ) OR EXISTS (
+            SELECT 1 FROM dnd_schedules d,
+                LATERAL (SELECT (NOW() AT TIME ZONE d.timezone)::TIME AS now) t
+            WHERE d.user_id = u.id AND d.ws_id = $4
+                AND CASE WHEN d.start_time <= d.end_time
+                    THEN t.now >= d.start_time AND t.now < d.end_time
+                    ELSE t.now >= d.start_time OR t.now < d.end_time
+                END
+        )
+        "#,
+    )
+    .bind(users)
+    .bind(message.chat_id)
+    .bind(message.mentions())
+    .bind(ws_id)
+    .fetch_all(db)
+    .await?;
+    Ok(silent.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::*;
+
+    fn message(content: &str) -> Message {
+        Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 1,
+            content: content.to_string(),
+            files: vec![],
+            created_at: Utc::now(),
+        }
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_silent_users(pool: PgPool) {
+        let users = HashSet::from([1, 2, 3]);
+        let silent = silent_users(&pool, 1, &message("hi"), &users)
+            .await
+            .unwrap();
+        assert!(silent.is_empty());
+
+        // user 1 only wants mentions, user 2 muted the chat
+        sqlx::query(
+            r#"
+            INSERT INTO chat_settings (user_id, chat_id, level, muted_until)
+            VALUES (1, 1, 'mentions', NULL), (2, 1, 'all', $1), (3, 1, 'all', $2)
+            "#,
+        )
+        .bind(Utc::now() + Duration::hours(1))
+        .bind(Utc::now() - Duration::hours(1))
+        .execute(&pool)
+        .await
+        .unwrap();
+        let silent = silent_users(&pool, 1, &message("hi"), &users)
+            .await
+            .unwrap();
+        assert_eq!(silent, HashSet::from([1, 2]));
+        let silent = silent_users(&pool, 1, &message("hi <@1> <@2>"), &users)
+            .await
+            .unwrap();
+        assert_eq!(silent, HashSet::from([2]));
+
+        // user 3 is in the do-not-disturb window, which wraps around midnight
+        let now = Utc::now();
+        let start = (now - Duration::hours(1)).time();
+        let end = (now + Duration::hours(1)).time();
+        sqlx::query(
+            "INSERT INTO dnd_schedules (user_id, ws_id, start_time, end_time) VALUES (3, 1, $1, $2)",
+        )
+        .bind(start)
+        .bind(end)
+        .execute(&pool)
+        .await
+        .unwrap();
+        let silent = silent_users(&pool, 1, &message("hi <@1>"), &users)
+            .await
+            .unwrap();
+        assert_eq!(silent, HashSet::from([2, 3]));
+
+        // outside of the window
+        sqlx::query("UPDATE dnd_schedules SET start_time = $2, end_time = $1")
+            .bind(start)
+            .bind(end)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let silent = silent_users(&pool, 1, &message("hi <@1>"), &users)
+            .await
+            .unwrap();
+        assert_eq!(silent, HashSet::from([2]));
+    }
+}
@@ -0,0 +1,279 @@
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey},
+    agreement::{self, EphemeralPrivateKey, UnparsedPublicKey},
+    hkdf,
+    rand::{SecureRandom, SystemRandom},
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde_json::json;
+
+/// Record size written into the header, the payload always fits in one record
+const RECORD_SIZE: u32 = 4096;
+/// Length of an uncompressed P-256 point
+const PUBLIC_KEY_LEN: usize = 65;
+const AUTH_SECRET_LEN: usize = 16;
+/// VAPID tokens are valid for this long, push services reject more than 24 hours
+const VAPID_TTL_SECS: i64 = 12 * 3600;
+
+/// Signs the requests to push services (RFC 8292)
+pub struct Vapid {
+    key: EcdsaKeyPair,
+    subject: String,
+    rng: SystemRandom,
+}
+
+/// Keys of the subscription, base64url encoded as the browser reports them
+pub struct UserAgentKeys<'a> {
+    pub p256dh: &'a str,
+    pub auth: &'a str,
+}
+
+impl Vapid {
+    /// Load a PKCS#8 PEM encoded P-256 private key
+    pub fn load(pem: &str, subject: &str) -> anyhow::Result<Self> {
+        let der: String = pem
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with("-----"))
+            .collect();
+        let der = base64::engine::general_purpose::STANDARD
+            .decode(der)
+            .context("invalid vapid key pem")?;
+        let rng = SystemRandom::new();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der, &rng)
+            .map_err(|e| anyhow!("invalid vapid key: {}", e))?;
+        Ok(Self {
+            key,
+            subject: subject.to_string(),
+            rng,
+        })
+    }
+
+    /// The application server key browsers subscribe with, base64url encoded
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.key.public_key().as_ref())
+    }
+
+    /// Value of the Authorization header for a request to `endpoint`
+    pub fn authorization(&self, endpoint: &str) -> anyhow::Result<String> {
+        let url = reqwest::Url::parse(endpoint).context("invalid push endpoint")?;
+        let claims = json!({
+            "aud": url.origin().ascii_serialization(),
+            "exp": chrono::Utc::now().timestamp() + VAPID_TTL_SECS,
+            "sub": self.subject,
+        });
+        let header = URL_SAFE_NO_PAD.encode(br#"{"typ":"JWT","alg":"ES256"}"#);
+        let claims = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let message = format!("{}.{}", header, claims);
+        let sig = self
+            .key
+            .sign(&self.rng, message.as_bytes())
+            .map_err(|e| anyhow!("failed to sign vapid token: {}", e))?;
+        Ok(format!(
+            "vapid t={}.{}, k={}",
+            message,
+            URL_SAFE_NO_PAD.encode(sig.as_ref()),
+            self.public_key()
+        ))
+    }
+}
+
+impl UserAgentKeys<'_> {
+    /// Decode and check the length of the keys
+    pub fn decode(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let p256dh = URL_SAFE_NO_PAD
+            .decode(self.p256dh.trim_end_matches('='))
+            .context("invalid p256dh key")?;
+        let auth = URL_SAFE_NO_PAD
+            .decode(self.auth.trim_end_matches('='))
+            .context("invalid auth secret")?;
+        if p256dh.len() != PUBLIC_KEY_LEN || p256dh[0] != 4 {
+            bail!("p256dh must be an uncompressed P-256 point");
+        }
+        if auth.len() != AUTH_SECRET_LEN {
+            bail!("auth secret must be {} bytes", AUTH_SECRET_LEN);
+        }
+        Ok((p256dh, auth))
+    }
+}
+
+/// Encrypt the payload for the user agent with the aes128gcm content encoding (RFC 8291)
+pub fn encrypt(keys: &UserAgentKeys, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let (ua_public, auth) = keys.decode()?;
+    let rng = SystemRandom::new();
+
+    let private = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng)
+        .map_err(|e| anyhow!("failed to generate key: {}", e))?;
+    let as_public = private
+        .compute_public_key()
+        .map_err(|e| anyhow!("failed to compute public key: {}", e))?;
+    let ecdh_secret = agreement::agree_ephemeral(
+        private,
+        &UnparsedPublicKey::new(&agreement::ECDH_P256, &ua_public),
+        |secret| secret.to_vec(),
+    )
+    .map_err(|e| anyhow!("key agreement failed: {}", e))?;
+
+    let mut key_info = b"WebPush: info\0".to_vec();
+    key_info.extend_from_slice(&ua_public);
+    key_info.extend_from_slice(as_public.as_ref());
+    let ikm = hkdf_sha256(&auth, &ecdh_secret, &key_info, 32)?;
+
+    let mut salt = [0u8; 16];
+    rng.fill(&mut salt)
+        .map_err(|e| anyhow!("failed to generate salt: {}", e))?;
+    let cek = hkdf_sha256(&salt, &ikm, b"Content-Encoding: aes128gcm\0", 16)?;
+    let nonce = hkdf_sha256(&salt, &ikm, b"Content-Encoding: nonce\0", 12)?;
+
+    // a single record, terminated by the last record delimiter
+    let mut record = payload.to_vec();
+    record.push(2);
+    if record.len() + aead::AES_128_GCM.tag_len() > RECORD_SIZE as usize {
+        bail!("push payload too large: {} bytes", payload.len());
+    }
+    let key = UnboundKey::new(&aead::AES_128_GCM, &cek)
+        .map_err(|e| anyhow!("invalid content encryption key: {}", e))?;
+    let nonce =
+        Nonce::try_assume_unique_for_key(&nonce).map_err(|e| anyhow!("invalid nonce: {}", e))?;
+    LessSafeKey::new(key)
+        .seal_in_place_append_tag(nonce, Aad::empty(), &mut record)
+        .map_err(|e| anyhow!("failed to encrypt: {}", e))?;
+
+    let mut body = Vec::with_capacity(16 + 4 + 1 + PUBLIC_KEY_LEN + record.len());
+    body.extend_from_slice(&salt);
+    body.extend_from_slice(&RECORD_SIZE.to_be_bytes());
+    body.push(PUBLIC_KEY_LEN as u8);
+    body.extend_from_slice(as_public.as_ref());
+    body.extend_from_slice(&record);
+    Ok(body)
+}
+
+struct Len(usize);
+
+impl hkdf::KeyType for Len {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(ikm);
+    let info = [info];
+    let okm = prk
+        .expand(&info, Len(len))
+        .map_err(|e| anyhow!("hkdf expand failed: {}", e))?;
+    let mut out = vec![0; len];
+    okm.fill(&mut out)
+        .map_err(|e| anyhow!("hkdf fill failed: {}", e))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use ring::signature::{UnparsedPublicKey as SigPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    use super::*;
+
+    /// The browser side of a subscription
+    pub(crate) struct UserAgent {
+        private: EphemeralPrivateKey,
+        pub p256dh: String,
+        pub auth: String,
+        auth_secret: Vec<u8>,
+    }
+
+    impl UserAgent {
+        pub(crate) fn new() -> Self {
+            let rng = SystemRandom::new();
+            let private = EphemeralPrivateKey::generate(&agreement::ECDH_P256, &rng).unwrap();
+            let public = private.compute_public_key().unwrap();
+            let mut auth_secret = vec![0; AUTH_SECRET_LEN];
+            rng.fill(&mut auth_secret).unwrap();
+            Self {
+                private,
+                p256dh: URL_SAFE_NO_PAD.encode(public.as_ref()),
+                auth: URL_SAFE_NO_PAD.encode(&auth_secret),
+                auth_secret,
+            }
+        }
+
+        /// Decrypt a push message, the key can only be used once
+        pub(crate) fn decrypt(self, body: &[u8]) -> Vec<u8> {
+            let (salt, rest) = body.split_at(16);
+            let (rs, rest) = rest.split_at(4);
+            assert_eq!(u32::from_be_bytes(rs.try_into().unwrap()), RECORD_SIZE);
+            assert_eq!(rest[0] as usize, PUBLIC_KEY_LEN);
+            let (as_public, record) = rest[1..].split_at(PUBLIC_KEY_LEN);
+
+            let ua_public = URL_SAFE_NO_PAD.decode(&self.p256dh).unwrap();
+            let ecdh_secret = agreement::agree_ephemeral(
+                self.private,
+                &UnparsedPublicKey::new(&agreement::ECDH_P256, as_public),
+                |secret| secret.to_vec(),
+            )
+            .unwrap();
+            let mut key_info = b"WebPush: info\0".to_vec();
+            key_info.extend_from_slice(&ua_public);
+            key_info.extend_from_slice(as_public);
+            let ikm = hkdf_sha256(&self.auth_secret, &ecdh_secret, &key_info, 32).unwrap();
+            let cek = hkdf_sha256(salt, &ikm, b"Content-Encoding: aes128gcm\0", 16).unwrap();
+            let nonce = hkdf_sha256(salt, &ikm, b"Content-Encoding: nonce\0", 12).unwrap();
+
+            let key = LessSafeKey::new(UnboundKey::new(&aead::AES_128_GCM, &cek).unwrap());
+            let nonce = Nonce::try_assume_unique_for_key(&nonce).unwrap();
+            let mut record = record.to_vec();
+            let plain = key.open_in_place(nonce, Aad::empty(), &mut record).unwrap();
+            assert_eq!(plain.last(), Some(&2));
+            plain[..plain.len() - 1].to_vec()
+        }
+    }
+
+    #[test]
+    fn t_encrypt_payload() {
+        let ua = UserAgent::new();
+        let keys = UserAgentKeys {
+            p256dh: &ua.p256dh,
+            auth: &ua.auth,
+        };
+        let body = encrypt(&keys, b"hello push").unwrap();
+        assert_eq!(ua.decrypt(&body), b"hello push");
+
+        let keys = UserAgentKeys {
+            p256dh: "AAAA",
+            auth: "AAAA",
+        };
+        assert!(encrypt(&keys, b"hello").is_err());
+    }
+
+    #[test]
+    fn t_vapid_authorization() {
+        let vapid = Vapid::load(
+            include_str!("../../../fixtures/vapid.pem"),
+            "mailto:ops@example.com",
+        )
+        .unwrap();
+        let auth = vapid
+            .authorization("https://push.example.com/send/abc")
+            .unwrap();
+        let (token, key) = auth
+            .strip_prefix("vapid t=")
+            .unwrap()
+            .split_once(", k=")
+            .unwrap();
+        assert_eq!(key, vapid.public_key());
+
+        let (message, sig) = token.rsplit_once('.').unwrap();
+        let claims = message.split_once('.').unwrap().1;
+        let claims: serde_json::Value =
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).unwrap()).unwrap();
+        assert_eq!(claims["aud"], "https://push.example.com");
+        assert_eq!(claims["sub"], "mailto:ops@example.com");
+
+        let public = URL_SAFE_NO_PAD.decode(key).unwrap();
+        SigPublicKey::new(&ECDSA_P256_SHA256_FIXED, public)
+            .verify(message.as_bytes(), &URL_SAFE_NO_PAD.decode(sig).unwrap())
+            .unwrap();
+    }
+}
@@ -0,0 +1,564 @@
+mod crypto;
+mod transport;
+
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, Extension, Json};
+use chat_core::{notify::NotifyEvent, utils::UserCliams, RowID};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::prelude::FromRow;
+use tokio::time;
+use tracing::{info, warn};
+
+pub use crypto::{encrypt, UserAgentKeys, Vapid};
+pub use transport::{HttpTransport, PushRequest, PushTransport};
+
+use crate::{
+    cluster::INSTANCE_TTL_SECS,
+    config::PushConfig,
+    error::{AppError, AppResult},
+    notify_event::AppNotification,
+    outbox::EVENT_RETENTION_SECS,
+    NotifyState,
+};
+
+/// Characters of the message content included in the push message
+const PREVIEW_CHARS: usize = 100;
+/// How often old deliveries are removed
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Delivers events to the devices of users without an open stream
+pub struct PushService {
+    vapid: Vapid,
+    transport: Box<dyn PushTransport>,
+    ttl: u32,
+    max_attempts: u32,
+    backoff: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct SubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+/// The `PushSubscription` of the browser
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateSubscription {
+    endpoint: String,
+    keys: SubscriptionKeys,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DeleteSubscription {
+    endpoint: String,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+struct Subscription {
+    id: RowID,
+    endpoint: String,
+    p256dh: String,
+    auth: String,
+}
+
+enum Outcome {
+    Delivered(u16),
+    /// The subscription expired or was revoked
+    Gone(u16),
+    Failed(Option<u16>, Option<String>),
+}
+
+// GET /push/key, the application server key to subscribe with
+pub(crate) async fn push_key_handler(
+    State(state): State<NotifyState>,
+) -> AppResult<Json<serde_json::Value>> {
+    let push = enabled(&state)?;
+    Ok(Json(json!({ "public_key": push.vapid.public_key() })))
+}
+
+// POST /push/subscriptions
+pub(crate) async fn create_subscription_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+    Json(input): Json<CreateSubscription>,
+) -> AppResult<(StatusCode, Json<serde_json::Value>)> {
+    enabled(&state)?;
+    let keys = UserAgentKeys {
+        p256dh: &input.keys.p256dh,
+        auth: &input.keys.auth,
+    };
+    keys.decode()
+        .map_err(|e| AppError::invalid_input(e.to_string()))?;
+    if reqwest::Url::parse(&input.endpoint).is_err() {
+        return Err(AppError::invalid_input("invalid push endpoint"));
+    }
+
+    // a device is subscribed once, by the user last signed in on it
+    let id: RowID = sqlx::query_scalar(
+        r#"
+        INSERT INTO push_subscriptions (user_id, endpoint, p256dh, auth)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (endpoint) DO UPDATE SET user_id = $1, p256dh = $3, auth = $4
+        RETURNING id
+        "#,
+    )
+    .bind(user.uid)
+    .bind(&input.endpoint)
+    .bind(&input.keys.p256dh)
+    .bind(&input.keys.auth)
+    .fetch_one(&state.db)
+    .await?;
+    Ok((StatusCode::CREATED, Json(json!({ "id": id }))))
+}
+
+// DELETE /push/subscriptions
+pub(crate) async fn delete_subscription_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+    Json(input): Json<DeleteSubscription>,
+) -> AppResult<StatusCode> {
+    sqlx::query("DELETE FROM push_subscriptions WHERE endpoint = $1 AND user_id = $2")
+        .bind(&input.endpoint)
+        .bind(user.uid)
+        .execute(&state.db)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Periodically remove old deliveries, they only prevent pushing an event twice
+pub fn setup_push(state: NotifyState) {
+    if state.push.is_none() {
+        return;
+    }
+    let mut interval = time::interval(PRUNE_INTERVAL);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.shutdown.cancelled() => break,
+            }
+            if let Err(e) = prune_deliveries(&state).await {
+                warn!("failed to prune push deliveries: {:#}", e);
+            }
+        }
+    });
+}
+
+/// Deliveries are kept as long as the events, an event is never pushed after that
+pub async fn prune_deliveries(state: &NotifyState) -> anyhow::Result<u64> {
+    let ret = sqlx::query(
+        "DELETE FROM push_deliveries WHERE created_at < NOW() - make_interval(secs => $1)",
+    )
+    .bind(EVENT_RETENTION_SECS)
+    .execute(&state.db)
+    .await?;
+    Ok(ret.rows_affected())
+}
+
+/// Push the event to recipients without an open stream on any instance. Every instance
+/// consumes the event, a delivery is claimed by the first one to get to it.
+pub(crate) fn notify_offline(state: &NotifyState, event_id: RowID, nf: &AppNotification) {
+    if state.push.is_none() {
+        return;
+    }
+    let Some((sender, payload)) = payload(&nf.event) else {
+        return;
+    };
+    let users: Vec<RowID> = nf
+        .users
+        .iter()
+        .copied()
+        .filter(|uid| *uid != sender && !nf.silent.contains(uid) && !state.users.contains_key(uid))
+        .collect();
+    if users.is_empty() {
+        return;
+    }
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = deliver(&state, event_id, &users, &payload).await {
+            warn!("failed to push event {}: {:#}", event_id, e);
+        }
+    });
+}
+
+impl PushService {
+    pub fn new(config: &PushConfig, transport: Box<dyn PushTransport>) -> anyhow::Result<Self> {
+        Ok(Self {
+            vapid: Vapid::load(&config.vapid_key, &config.subject)?,
+            transport,
+            ttl: config.ttl,
+            max_attempts: config.max_attempts.max(1),
+            backoff: Duration::from_millis(config.retry_backoff_ms),
+        })
+    }
+
+    async fn send(&self, sub: &Subscription, payload: &[u8]) -> anyhow::Result<u16> {
+        let keys = UserAgentKeys {
+            p256dh: &sub.p256dh,
+            auth: &sub.auth,
+        };
+        let request = PushRequest {
+            endpoint: sub.endpoint.clone(),
+            authorization: self.vapid.authorization(&sub.endpoint)?,
+            ttl: self.ttl,
+            body: encrypt(&keys, payload)?,
+        };
+        self.transport.send(request).await
+    }
+
+    /// Retry rate limits, server errors and network errors with exponential backoff
+    async fn send_with_retry(&self, sub: &Subscription, payload: &[u8]) -> (u32, Outcome) {
+        let mut backoff = self.backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (code, error) = match self.send(sub, payload).await {
+                Ok(code @ 200..=299) => return (attempts, Outcome::Delivered(code)),
+                Ok(code @ (404 | 410)) => return (attempts, Outcome::Gone(code)),
+                Ok(code) if code == 429 || code >= 500 => (Some(code), None),
+                Ok(code) => return (attempts, Outcome::Failed(Some(code), None)),
+                Err(e) => (None, Some(format!("{:#}", e))),
+            };
+            if attempts >= self.max_attempts {
+                return (attempts, Outcome::Failed(code, error));
+            }
+            time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+fn enabled(state: &NotifyState) -> AppResult<&PushService> {
+    state
+        .push
+        .as_ref()
+        .ok_or_else(|| AppError::not_found("push notifications are not enabled"))
+}
+
+/// Sender and payload of the events worth a push, the payload has to fit in a push message
+fn payload(event: &NotifyEvent) -> Option<(RowID, Vec<u8>)> {
+    match event {
+        NotifyEvent::NewMessage(message) => {
+            let preview: String = message.content.chars().take(PREVIEW_CHARS).collect();
+            let payload = json!({
+                "event": event.name(),
+                "chat_id": message.chat_id,
+                "message_id": message.id,
+                "sender_id": message.sender_id,
+                "preview": preview,
+            });
+            Some((message.sender_id, payload.to_string().into_bytes()))
+        }
+        _ => None,
+    }
+}
+
+async fn deliver(
+    state: &NotifyState,
+    event_id: RowID,
+    users: &[RowID],
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let Some(push) = &state.push else {
+        return Ok(());
+    };
+    let subs: Vec<Subscription> = sqlx::query_as(
+        r#"
+        WITH claimed AS (
+            INSERT INTO push_deliveries (event_id, subscription_id)
+            SELECT $1, s.id FROM push_subscriptions s
+            WHERE s.user_id = ANY($2) AND NOT EXISTS (
+                SELECT 1 FROM notify_connections c
+                JOIN notify_instances i ON i.id = c.instance_id
+                WHERE c.user_id = s.user_id AND c.instance_id <> $3
+                    AND i.heartbeat_at > NOW() - make_interval(secs => $4)
+            )
+            ON CONFLICT DO NOTHING
+            RETURNING subscription_id
+        )
+        SELECT s.id, s.endpoint, s.p256dh, s.auth
+        FROM push_subscriptions s JOIN claimed c ON c.subscription_id = s.id
+        "#,
+    )
+    .bind(event_id)
+    .bind(users)
+    .bind(&state.instance_id)
+    .bind(INSTANCE_TTL_SECS)
+    .fetch_all(&state.db)
+    .await?;
+
+    let sent = subs.iter().map(|sub| async move {
+        let (attempts, outcome) = push.send_with_retry(sub, payload).await;
+        if let Err(e) = record(state, event_id, sub, attempts, &outcome).await {
+            warn!("failed to record push delivery: {:#}", e);
+        }
+    });
+    futures::future::join_all(sent).await;
+    Ok(())
+}
+
+async fn record(
+    state: &NotifyState,
+    event_id: RowID,
+    sub: &Subscription,
+    attempts: u32,
+    outcome: &Outcome,
+) -> anyhow::Result<()> {
+    let (status, error, delivered) = match outcome {
+        Outcome::Delivered(code) => (Some(*code), None, true),
+        Outcome::Gone(code) => (Some(*code), None, false),
+        Outcome::Failed(code, error) => (*code, error.clone(), false),
+    };
+    // the outcome and the removal of an expired subscription are seen together
+    let mut tx = state.db.begin().await?;
+    sqlx::query(
+        r#"
+        UPDATE push_deliveries
+        SET attempts = $3, status = $4, error = $5,
+            delivered_at = CASE WHEN $6 THEN NOW() END
+        WHERE event_id = $1 AND subscription_id = $2
+        "#,
+    )
+    .bind(event_id)
+    .bind(sub.id)
+    .bind(attempts as i32)
+    .bind(status.map(i32::from))
+    .bind(error)
+    .bind(delivered)
+    .execute(&mut *tx)
+    .await?;
+
+    if let Outcome::Gone(code) = outcome {
+        info!("removing push subscription {}: {}", sub.id, code);
+        sqlx::query("DELETE FROM push_subscriptions WHERE id = $1")
+            .bind(sub.id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use axum::{
+        body::Bytes,
+        extract::{Path, State},
+        http::HeaderMap,
+        routing::post,
+        Router,
+    };
+    use chat_core::utils::UserCliams;
+    use sqlx::PgPool;
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::{crypto::tests::UserAgent, *};
+    use crate::{
+        config::{AuthConfig, ServerConfig},
+        outbox::{consume, init_cursor, tests::append_message},
+        NotifyConfig,
+    };
+
+    struct Received {
+        path: String,
+        authorization: String,
+        body: Bytes,
+    }
+
+    type Sink = (mpsc::UnboundedSender<Received>, Arc<AtomicU32>);
+
+    /// Push service stand-in, `flaky` fails the first request and `gone` is expired
+    async fn push_service() -> (String, mpsc::UnboundedReceiver<Received>) {
+        async fn handler(
+            Path(path): Path<String>,
+            State((tx, flaky)): State<Sink>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> StatusCode {
+            let authorization = headers["authorization"].to_str().unwrap().to_string();
+            let code = match path.as_str() {
+                "gone" => StatusCode::GONE,
+                "flaky" if flaky.fetch_add(1, Ordering::Relaxed) == 0 => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+                _ => StatusCode::CREATED,
+            };
+            let _ = tx.send(Received {
+                path,
+                authorization,
+                body,
+            });
+            code
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let router = Router::new()
+            .route("/push/:path", post(handler))
+            .with_state((tx, Arc::new(AtomicU32::new(0))));
+        let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", ls.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(ls, router).await.unwrap() });
+        (addr, rx)
+    }
+
+    fn config() -> NotifyConfig {
+        NotifyConfig {
+            server: ServerConfig {
+                host: "127.0.0.1".to_string(),
+                port: 0,
+                db_url: "".to_string(),
+            },
+            auth: AuthConfig {
+                pk: include_str!("../../../fixtures/public.pem").to_string(),
+            },
+            push: Some(PushConfig {
+                vapid_key: include_str!("../../../fixtures/vapid.pem").to_string(),
+                subject: "mailto:ops@example.com".to_string(),
+                ttl: 60,
+                max_attempts: 3,
+                retry_backoff_ms: 10,
+            }),
+            digest: None,
+            stream: Default::default(),
+            webhook: Default::default(),
+        }
+    }
+
+    async fn subscribe(state: &NotifyState, uid: RowID, endpoint: String, ua: &UserAgent) {
+        let input = CreateSubscription {
+            endpoint,
+            keys: SubscriptionKeys {
+                p256dh: ua.p256dh.clone(),
+                auth: ua.auth.clone(),
+            },
+        };
+        let user = UserCliams {
+            uid,
+            ws_id: 1,
+            ..Default::default()
+        };
+        let (code, _) =
+            create_subscription_handler(Extension(user), State(state.clone()), Json(input))
+                .await
+                .unwrap();
+        assert_eq!(code, StatusCode::CREATED);
+    }
+
+    async fn last_event_id(pool: &PgPool) -> RowID {
+        sqlx::query_scalar("SELECT MAX(id) FROM events")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../../fixtures/test.sql")
+    )]
+    async fn t_push_offline_users(pool: PgPool) {
+        let state = NotifyState::new(config(), pool.clone()).unwrap();
+        init_cursor(&state).await.unwrap();
+        let (addr, mut rx) = push_service().await;
+
+        let ua = UserAgent::new();
+        subscribe(&state, 1, format!("{}/push/ok", addr), &ua).await;
+        subscribe(&state, 1, format!("{}/push/flaky", addr), &UserAgent::new()).await;
+        subscribe(&state, 1, format!("{}/push/gone", addr), &UserAgent::new()).await;
+        let bad = CreateSubscription {
+            endpoint: format!("{}/push/bad", addr),
+            keys: SubscriptionKeys {
+                p256dh: "AAAA".to_string(),
+                auth: ua.auth.clone(),
+            },
+        };
+        let user = UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        };
+        let ret = create_subscription_handler(Extension(user), State(state.clone()), Json(bad));
+        assert!(matches!(ret.await, Err(AppError::InvalidInput(_))));
+
+        // user 2 sends, user 1 has no stream open
+        append_message(&pool, 1, "hello push").await;
+        consume(&state).await.unwrap();
+        let event_id = last_event_id(&pool).await;
+
+        let mut ok = None;
+        let mut flaky = 0;
+        let mut gone = 0;
+        while ok.is_none() || flaky < 2 || gone < 1 {
+            let req = rx.recv().await.unwrap();
+            assert!(req.authorization.starts_with("vapid t="));
+            match req.path.as_str() {
+                "ok" => ok = Some(req.body),
+                "flaky" => flaky += 1,
+                "gone" => gone += 1,
+                path => panic!("unexpected push to {}", path),
+            }
+        }
+        let payload: serde_json::Value = serde_json::from_slice(&ua.decrypt(&ok.unwrap())).unwrap();
+        assert_eq!(payload["event"], "NewMessage");
+        assert_eq!(payload["message_id"], 1);
+        assert_eq!(payload["preview"], "hello push");
+
+        // deliveries are recorded and the expired subscription is pruned
+        let deliveries: Vec<(i32, Option<i32>, bool)> = loop {
+            let rows: Vec<(i32, Option<i32>, bool)> = sqlx::query_as(
+                r#"
+                SELECT attempts, status, delivered_at IS NOT NULL FROM push_deliveries
+                WHERE event_id = $1 ORDER BY subscription_id
+                "#,
+            )
+            .bind(event_id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            if rows.iter().all(|r| r.1.is_some()) {
+                break rows;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(
+            deliveries,
+            vec![
+                (1, Some(201), true),
+                (2, Some(201), true),
+                (1, Some(410), false)
+            ]
+        );
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM push_subscriptions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // another instance consuming the same event doesn't push it again
+        let other = NotifyState::new(config(), pool.clone()).unwrap();
+        other.event_cursor.store(event_id - 1, Ordering::Relaxed);
+        consume(&other).await.unwrap();
+
+        // connected users are not pushed
+        let _stream = crate::subscriber::UserStream::subscribe(&state.users, 1, 10);
+        append_message(&pool, 2, "hello stream").await;
+        consume(&state).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+
+        // deliveries are kept for as long as the events
+        assert_eq!(prune_deliveries(&state).await.unwrap(), 0);
+        sqlx::query("UPDATE push_deliveries SET created_at = NOW() - INTERVAL '2 hours'")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(prune_deliveries(&state).await.unwrap(), 3);
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+/// An encrypted message for a push service
+#[derive(Debug, Clone)]
+pub struct PushRequest {
+    pub endpoint: String,
+    pub authorization: String,
+    /// Seconds the push service keeps the message for an offline device
+    pub ttl: u32,
+    pub body: Vec<u8>,
+}
+
+/// How push messages reach the push services, replaced in tests
+#[async_trait]
+pub trait PushTransport: Send + Sync + 'static {
+    /// Send the request and return the status code of the response
+    async fn send(&self, request: PushRequest) -> anyhow::Result<u16>;
+}
+
+pub struct HttpTransport {
+    client: reqwest::Client,
+}
+
+impl HttpTransport {
+    pub fn new() -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl PushTransport for HttpTransport {
+    async fn send(&self, request: PushRequest) -> anyhow::Result<u16> {
+        let resp = self
+            .client
+            .post(&request.endpoint)
+            .header("authorization", request.authorization)
+            .header("content-encoding", "aes128gcm")
+            .header("content-type", "application/octet-stream")
+            .header("ttl", request.ttl)
+            .body(request.body)
+            .send()
+            .await?;
+        Ok(resp.status().as_u16())
+    }
+}
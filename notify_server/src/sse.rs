@@ -1,51 +1,67 @@
 use std::time::Duration;
 
 use axum::{
-    extract::State,
+    extract::{Query, State},
+    http::HeaderMap,
     response::{
         sse::{Event, KeepAlive},
         Sse,
     },
     Extension,
 };
-use chat_core::utils::UserCliams;
+use chat_core::{utils::UserCliams, RowID};
 use futures::Stream;
-use tokio::sync::broadcast;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+use tokio_stream::StreamExt;
 use tracing::info;
 
-use crate::{notify_event::NotifyEvent, NotifyState};
+use crate::{
+    error::AppResult,
+    filter::{EventFilter, FilterParams},
+    notify_event::UserEvent,
+    subscriber::user_events,
+    NotifyState,
+};
+
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
 
-// FIXME: when sse connection is closed, we should remove it from users
 pub(crate) async fn sse_handler(
     Extension(user): Extension<UserCliams>,
     State(state): State<NotifyState>,
+    Query(params): Query<FilterParams>,
+    headers: HeaderMap,
     // TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
-) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+) -> AppResult<Sse<impl Stream<Item = Result<Event, axum::Error>>>> {
+    let filter = params.parse()?;
     info!("user {} connected", user.uid);
-    let users = state.users.clone();
-
-    let rx = if let Some(tx) = users.get(&user.uid) {
-        tx.subscribe()
-    } else {
-        let (tx, rx) = broadcast::channel(10);
-        users.insert(user.uid, tx);
-        rx
-    };
 
-    let stream = BroadcastStream::new(rx).filter_map(Result::ok).map(|ev| {
-        let name = match &ev {
-            NotifyEvent::NewChat(_) => "NewChat",
-            NotifyEvent::AddToChat(_) => "AddToChat",
-            NotifyEvent::NewMessage(_) => "NewMessage",
-            NotifyEvent::RemoveFromChat(_) => "RemoveFromChat",
-        };
-        Event::default().event(name).json_data(ev)
-    });
+    let last_event_id = headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let uid = user.uid;
+    let keep_alive = state.config.stream.keep_alive_secs;
+    let matcher = filter.clone();
+    let stream = user_events(&state, &user, last_event_id)
+        .filter(move |ev| matcher.matches(ev))
+        .map(move |ev| sse_event(&state.instance_id, uid, &filter, &ev));
 
-    Sse::new(stream).keep_alive(
+    Ok(Sse::new(stream).keep_alive(
         KeepAlive::new()
-            .interval(Duration::from_secs(1))
+            .interval(Duration::from_secs(keep_alive.max(1)))
             .text("keep-alive"),
-    )
+    ))
+}
+
+fn sse_event(
+    instance_id: &str,
+    uid: RowID,
+    filter: &EventFilter,
+    ev: &UserEvent,
+) -> Result<Event, axum::Error> {
+    let mut envelope = ev.envelope(instance_id, uid);
+    filter.apply(&mut envelope);
+    let mut event = Event::default().event(envelope.event.name());
+    if let Some(id) = &envelope.id {
+        event = event.id(id);
+    }
+    event.json_data(envelope)
 }
@@ -0,0 +1,336 @@
+use std::{
+    collections::BTreeMap,
+    pin::Pin,
+    sync::atomic::Ordering,
+    task::{Context, Poll},
+};
+
+use axum::{extract::State, Extension, Json};
+use chat_core::{utils::UserCliams, RowID};
+use futures::{future, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt,
+};
+use tracing::{info, warn};
+
+use crate::{
+    config::LagPolicy,
+    error::{AppError, AppResult},
+    event_log::{parse_event_id, Replay},
+    notify_event::UserEvent,
+    presence::{self, PresenceGuard},
+    LagMap, NotifyState, UserMap,
+};
+
+/// Events of one user, the user is removed from `UserMap` and `LagMap` when its last stream is dropped
+pub(crate) struct UserStream {
+    uid: RowID,
+    users: UserMap,
+    lagged: Option<LagMap>,
+    inner: Option<BroadcastStream<UserEvent>>,
+    presence: Option<PresenceGuard>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ConnectionStats {
+    users: usize,
+    connections: usize,
+    /// Events dropped for slow consumers since the start
+    lagged_events: u64,
+}
+
+/// Events of the user, starting with the ones missed after `last_event_id`
+pub(crate) fn user_events(
+    state: &NotifyState,
+    user: &UserCliams,
+    last_event_id: Option<&str>,
+) -> impl Stream<Item = UserEvent> + Send + 'static {
+    let uid = user.uid;
+    // subscribe before reading the log, events in both are skipped by id
+    let capacity = state.config.stream.channel_capacity;
+    let live = UserStream::subscribe(&state.users, uid, capacity)
+        .with_lag_counter(state.lagged.clone())
+        .with_presence(presence::connect(state, user));
+
+    // ids of other instances can't be replayed
+    let last_event_id =
+        last_event_id.map(|id| parse_event_id(&state.instance_id, id).unwrap_or(u64::MAX));
+    let (replay, mut last_id) = match last_event_id {
+        Some(last_id) => match state.events.lock().unwrap().replay(uid, last_id) {
+            Replay::Events(events) => {
+                let last_id = events.last().map_or(last_id, |ev| ev.id);
+                (events.into_iter().map(UserEvent::Logged).collect(), last_id)
+            }
+            Replay::Resync { last_id } => (
+                vec![UserEvent::Resync {
+                    last_id: Some(last_id),
+                }],
+                last_id,
+            ),
+        },
+        // the receiver only gets events published after subscribing
+        None => (vec![], 0),
+    };
+
+    let owned = state.clone();
+    let live = live.filter_map(move |ev| match ev {
+        Ok(UserEvent::Logged(ev)) if ev.id <= last_id => None,
+        Ok(UserEvent::Logged(ev)) => {
+            last_id = ev.id;
+            Some(UserEvent::Logged(ev))
+        }
+        Ok(ev) => Some(ev),
+        Err(BroadcastStreamRecvError::Lagged(n)) => Some(lagged(&owned, uid, n)),
+    });
+    // nothing follows a disconnect
+    let live = futures::StreamExt::scan(live, false, |closed, ev| {
+        let ev = (!*closed).then_some(ev);
+        *closed = matches!(ev, Some(UserEvent::Disconnect { .. }));
+        future::ready(ev)
+    });
+    // clients reconnect to other instances when this one is drained
+    let stream = tokio_stream::iter(replay).chain(live);
+    futures::StreamExt::take_until(stream, state.shutdown.clone().cancelled_owned())
+}
+
+/// Count the dropped events and apply the lag policy
+fn lagged(state: &NotifyState, uid: RowID, n: u64) -> UserEvent {
+    *state.lagged.entry(uid).or_default() += n;
+    state.lagged_total.fetch_add(n, Ordering::Relaxed);
+    let policy = state.config.stream.lag_policy;
+    warn!("user {} lagged {} events, {:?}", uid, n, policy);
+    match policy {
+        LagPolicy::Resync => UserEvent::Resync { last_id: None },
+        LagPolicy::Disconnect => UserEvent::Disconnect {
+            reason: "too slow to keep up with events",
+        },
+    }
+}
+
+// GET /stats, connection totals of the instance for workspace owners
+pub(crate) async fn stats_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+) -> AppResult<Json<ConnectionStats>> {
+    if !user.is_workspace_owner(&state.db).await? {
+        return Err(AppError::forbidden(
+            "only the workspace owner can see the stats",
+        ));
+    }
+    let mut stats = ConnectionStats::collect(&state.users);
+    stats.lagged_events = state.lagged_total.load(Ordering::Relaxed);
+    Ok(Json(stats))
+}
+
+// GET /stats/lagged, events dropped for the connected users of the workspace
+pub(crate) async fn lagged_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+) -> AppResult<Json<BTreeMap<RowID, u64>>> {
+    if !user.is_workspace_owner(&state.db).await? {
+        return Err(AppError::forbidden(
+            "only the workspace owner can see the stats",
+        ));
+    }
+    let lagged = state
+        .lagged
+        .iter()
+        .filter(|kv| {
+            state
+                .presence
+                .get(kv.key())
+                .is_some_and(|p| p.ws_id == user.ws_id)
+        })
+        .map(|kv| (*kv.key(), *kv.value()))
+        .collect();
+    Ok(Json(lagged))
+}
+
+impl UserStream {
+    pub(crate) fn subscribe(users: &UserMap, uid: RowID, capacity: usize) -> Self {
+        // subscribe under the entry lock, so a dropping stream can't remove the sender in between
+        let rx = users
+            .entry(uid)
+            .or_insert_with(|| broadcast::channel(capacity.max(1)).0)
+            .subscribe();
+        Self {
+            uid,
+            users: users.clone(),
+            lagged: None,
+            inner: Some(BroadcastStream::new(rx)),
+            presence: None,
+        }
+    }
+
+    /// Forget the dropped events of the user once it is gone
+    pub(crate) fn with_lag_counter(mut self, lagged: LagMap) -> Self {
+        self.lagged = Some(lagged);
+        self
+    }
+
+    /// Keep the user online while the stream is alive
+    pub(crate) fn with_presence(mut self, guard: PresenceGuard) -> Self {
+        self.presence = Some(guard);
+        self
+    }
+}
+
+impl Stream for UserStream {
+    type Item = Result<UserEvent, BroadcastStreamRecvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.inner.as_mut() {
+            Some(inner) => Pin::new(inner).poll_next(cx),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+impl Drop for UserStream {
+    fn drop(&mut self) {
+        // release the receiver first, otherwise it is always counted
+        drop(self.inner.take());
+        if self
+            .users
+            .remove_if(&self.uid, |_, tx| tx.receiver_count() == 0)
+            .is_some()
+        {
+            if let Some(lagged) = &self.lagged {
+                lagged.remove(&self.uid);
+            }
+            info!("user {} disconnected", self.uid);
+        }
+    }
+}
+
+impl ConnectionStats {
+    fn collect(users: &UserMap) -> Self {
+        let mut stats = Self {
+            users: 0,
+            connections: 0,
+            lagged_events: 0,
+        };
+        for kv in users.iter() {
+            stats.users += 1;
+            stats.connections += kv.receiver_count();
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use dashmap::DashMap;
+
+    use chat_core::{notify::NotifyEvent, Chat};
+    use sqlx::PgPool;
+
+    use super::*;
+    use crate::{
+        event_log::EventLog,
+        notify_event::{publish, AppNotification},
+        NotifyConfig,
+    };
+
+    fn new_chat() -> Chat {
+        serde_json::from_value(serde_json::json!({
+            "id": 1, "ws_id": 1, "name": null, "type": "single",
+            "members": [1, 2], "created_at": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn t_user_stream_cleanup() {
+        let users: UserMap = Arc::new(DashMap::new());
+        let s1 = UserStream::subscribe(&users, 1, 10);
+        let mut s2 = UserStream::subscribe(&users, 1, 10);
+        let s3 = UserStream::subscribe(&users, 2, 10);
+
+        let stats = ConnectionStats::collect(&users);
+        assert_eq!((stats.users, stats.connections), (2, 3));
+
+        // the user stays while any stream is alive
+        drop(s1);
+        assert!(users.contains_key(&1));
+        let record = EventLog::new(1).push(vec![1], vec![], NotifyEvent::NewChat(new_chat()));
+        let ev = UserEvent::Logged(record);
+        users.get(&1).unwrap().send(ev).unwrap();
+        let ev = s2.next().await.unwrap().unwrap();
+        assert!(matches!(ev.event(), Some(NotifyEvent::NewChat(_))));
+
+        drop(s2);
+        assert!(!users.contains_key(&1));
+        drop(s3);
+        let stats = ConnectionStats::collect(&users);
+        assert_eq!((stats.users, stats.connections), (0, 0));
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_lag_policy(pool: PgPool) {
+        for policy in [LagPolicy::Resync, LagPolicy::Disconnect] {
+            let mut config = NotifyConfig::new_for_test();
+            config.stream.channel_capacity = 2;
+            config.stream.lag_policy = policy;
+            let state = NotifyState::new(config, pool.clone()).unwrap();
+            let user = UserCliams {
+                uid: 1,
+                ws_id: 1,
+                ..Default::default()
+            };
+            let mut events = Box::pin(user_events(&state, &user, None));
+
+            // nobody reads the stream while the events are published
+            for _ in 0..5 {
+                publish(
+                    &state,
+                    AppNotification {
+                        users: [1].into(),
+                        silent: Default::default(),
+                        event: NotifyEvent::NewChat(new_chat()),
+                    },
+                );
+            }
+
+            let ev = events.next().await.unwrap();
+            match policy {
+                LagPolicy::Resync => {
+                    assert!(matches!(ev, UserEvent::Resync { last_id: None }));
+                    // the events still buffered follow
+                    assert_eq!(events.next().await.unwrap().id(), Some(4));
+                }
+                LagPolicy::Disconnect => {
+                    assert!(matches!(ev, UserEvent::Disconnect { .. }));
+                    assert!(events.next().await.is_none());
+                }
+            }
+            assert_eq!(state.lagged.get(&1).map(|v| *v), Some(3));
+
+            // only the owner of the workspace sees the users
+            let Json(lagged) = lagged_handler(Extension(user.clone()), State(state.clone()))
+                .await
+                .unwrap();
+            assert_eq!(lagged, BTreeMap::from([(1, 3)]));
+            let member = UserCliams {
+                uid: 2,
+                ws_id: 1,
+                ..Default::default()
+            };
+            let ret = lagged_handler(Extension(member), State(state.clone())).await;
+            assert!(matches!(ret, Err(AppError::Forbidden(_))));
+
+            // forgotten once the user is gone, still counted in the total
+            drop(events);
+            assert!(state.lagged.is_empty());
+            assert_eq!(state.lagged_total.load(Ordering::Relaxed), 3);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension,
+};
+use chat_core::{
+    notify::{NotifyEvent, Typing},
+    utils::UserCliams,
+    RowID,
+};
+use dashmap::DashMap;
+use sqlx::PgPool;
+use tokio::time::{self, Instant};
+
+use crate::{
+    cluster::Audience,
+    error::{AppError, AppResult},
+    notify_event::publish_ephemeral,
+    NotifyState,
+};
+
+/// Typing stops when it isn't refreshed within this time
+pub const TYPING_TTL: Duration = Duration::from_secs(5);
+
+/// (chat_id, user_id) -> when typing expires
+pub type TypingMap = Arc<DashMap<(RowID, RowID), Instant>>;
+
+// /chats/:id/typing
+pub(crate) async fn typing_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+    Path(chat_id): Path<RowID>,
+) -> AppResult<StatusCode> {
+    start_typing(&state, chat_id, user.uid).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Tell the other chat members that the user is typing.
+/// Signals within `TYPING_TTL` only extend it, members are told again when it expires.
+pub(crate) async fn start_typing(state: &NotifyState, chat_id: RowID, uid: RowID) -> AppResult<()> {
+    let members = chat_members(&state.db, chat_id).await?;
+    if !members.contains(&uid) {
+        return Err(AppError::forbidden(
+            "user is not the chat member or chat not exist",
+        ));
+    }
+
+    let key = (chat_id, uid);
+    let expires_at = Instant::now() + TYPING_TTL;
+    if state.typing.insert(key, expires_at).is_some() {
+        return Ok(());
+    }
+    notify_typing(state, &members, chat_id, uid, true);
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut deadline = expires_at;
+        loop {
+            time::sleep_until(deadline).await;
+            let expired = state
+                .typing
+                .remove_if(&key, |_, at| *at <= Instant::now())
+                .is_some();
+            if expired {
+                break;
+            }
+            match state.typing.get(&key) {
+                Some(at) => deadline = *at,
+                None => return,
+            }
+        }
+        notify_typing(&state, &members, chat_id, uid, false);
+    });
+    Ok(())
+}
+
+fn notify_typing(state: &NotifyState, members: &[RowID], chat_id: RowID, uid: RowID, typing: bool) {
+    let others = members.iter().copied().filter(|id| *id != uid).collect();
+    let event = NotifyEvent::Typing(Typing {
+        chat_id,
+        user_id: uid,
+        typing,
+    });
+    publish_ephemeral(state, Audience::Users(others), event);
+}
+
+async fn chat_members(pool: &PgPool, chat_id: RowID) -> AppResult<Vec<RowID>> {
+    let members: Option<Vec<RowID>> = sqlx::query_scalar("SELECT members FROM chats WHERE id = $1")
+        .bind(chat_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(members.unwrap_or_default())
+}
@@ -0,0 +1,959 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chat_core::{notify::EventEnvelope, utils::UserCliams, RowID};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::prelude::FromRow;
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{
+    config::WebhookConfig,
+    error::{AppError, AppResult},
+    notify_event::AppNotification,
+    NotifyState,
+};
+
+/// Events a webhook can subscribe to, the changes of chats
+const WEBHOOK_EVENTS: [&str; 5] = [
+    "NewChat",
+    "MemberAdded",
+    "RemovedFromChat",
+    "ChatUpdated",
+    "NewMessage",
+];
+
+const SECRET_PREFIX: &str = "whsec_";
+/// Random bytes of a secret
+const SECRET_BYTES: usize = 24;
+/// Deliveries listed per webhook, the latest first
+const DELIVERIES_LIMIT: i64 = 100;
+/// How often deliveries left unsent by dead instances are looked for
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+/// Added to the longest a delivery can take before it is considered abandoned
+const STALE_MARGIN: Duration = Duration::from_secs(60);
+
+/// `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`
+pub const SIGNATURE_HEADER: &str = "x-chat-signature";
+/// Name of the event in the body
+pub const EVENT_HEADER: &str = "x-chat-event";
+/// Id of the delivery, the same when it is replayed
+pub const DELIVERY_HEADER: &str = "x-chat-delivery";
+
+/// Delivers chat events to the webhooks of workspaces
+pub struct WebhookService {
+    client: reqwest::Client,
+    max_attempts: u32,
+    backoff: Duration,
+    timeout: Duration,
+    allow_http: bool,
+    retention: Duration,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateWebhook {
+    url: String,
+    /// Event names, e.g. `NewMessage`
+    events: Vec<String>,
+    /// Only events of these chats, all chats of the workspace if empty
+    #[serde(default)]
+    chat_ids: Vec<RowID>,
+}
+
+/// A webhook without its secret
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub(crate) struct Webhook {
+    id: RowID,
+    ws_id: RowID,
+    url: String,
+    events: Vec<String>,
+    chat_ids: Vec<RowID>,
+    created_by: RowID,
+    created_at: DateTime<Utc>,
+}
+
+/// The only time the secret is returned
+#[derive(Debug, Serialize)]
+pub(crate) struct NewWebhook {
+    secret: String,
+    #[serde(flatten)]
+    webhook: Webhook,
+}
+
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct Delivery {
+    id: RowID,
+    webhook_id: RowID,
+    event_id: RowID,
+    event: String,
+    attempts: i32,
+    status: Option<i32>,
+    error: Option<String>,
+    delivered_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+}
+
+/// A delivery with what it takes to send it
+#[derive(Debug, FromRow)]
+struct Request {
+    id: RowID,
+    url: String,
+    secret: String,
+    event: String,
+    body: String,
+}
+
+enum Outcome {
+    Delivered(u16),
+    Failed(Option<u16>, Option<String>),
+}
+
+// GET /webhooks
+pub(crate) async fn list_webhooks_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+) -> AppResult<Json<Vec<Webhook>>> {
+    ensure_admin(&state, &user).await?;
+    let webhooks = sqlx::query_as(
+        r#"
+        SELECT id, ws_id, url, events, chat_ids, created_by, created_at
+        FROM webhooks WHERE ws_id = $1 ORDER BY id
+        "#,
+    )
+    .bind(user.ws_id)
+    .fetch_all(&state.db)
+    .await?;
+    Ok(Json(webhooks))
+}
+
+// POST /webhooks
+pub(crate) async fn create_webhook_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+    Json(mut input): Json<CreateWebhook>,
+) -> AppResult<(StatusCode, Json<NewWebhook>)> {
+    ensure_admin(&state, &user).await?;
+    state.webhook.check_url(&input.url)?;
+    input.events.sort();
+    input.events.dedup();
+    if input.events.is_empty() {
+        return Err(AppError::invalid_input("webhook must have an event"));
+    }
+    if let Some(name) = input
+        .events
+        .iter()
+        .find(|name| !WEBHOOK_EVENTS.contains(&name.as_str()))
+    {
+        return Err(AppError::invalid_input(format!(
+            "unknown event type: {}",
+            name
+        )));
+    }
+    input.chat_ids.sort();
+    input.chat_ids.dedup();
+    let chats: i64 =
+        sqlx::query_scalar("SELECT COUNT(*) FROM chats WHERE ws_id = $1 AND id = ANY($2)")
+            .bind(user.ws_id)
+            .bind(&input.chat_ids)
+            .fetch_one(&state.db)
+            .await?;
+    if chats != input.chat_ids.len() as i64 {
+        return Err(AppError::invalid_input("chat not found in the workspace"));
+    }
+
+    let secret = generate_secret();
+    let webhook = sqlx::query_as(
+        r#"
+        INSERT INTO webhooks (ws_id, url, secret, events, chat_ids, created_by)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, ws_id, url, events, chat_ids, created_by, created_at
+        "#,
+    )
+    .bind(user.ws_id)
+    .bind(&input.url)
+    .bind(&secret)
+    .bind(&input.events)
+    .bind(&input.chat_ids)
+    .bind(user.uid)
+    .fetch_one(&state.db)
+    .await?;
+    Ok((StatusCode::CREATED, Json(NewWebhook { secret, webhook })))
+}
+
+// DELETE /webhooks/:id, the deliveries go with it
+pub(crate) async fn delete_webhook_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+    Path(id): Path<RowID>,
+) -> AppResult<StatusCode> {
+    ensure_admin(&state, &user).await?;
+    let mut tx = state.db.begin().await?;
+    let ret = sqlx::query("DELETE FROM webhooks WHERE id = $1 AND ws_id = $2")
+        .bind(id)
+        .bind(user.ws_id)
+        .execute(&mut *tx)
+        .await?;
+    if ret.rows_affected() == 0 {
+        return Err(AppError::not_found("webhook not found"));
+    }
+    sqlx::query("DELETE FROM webhook_deliveries WHERE webhook_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /webhooks/:id/deliveries
+pub(crate) async fn list_deliveries_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+    Path(id): Path<RowID>,
+) -> AppResult<Json<Vec<Delivery>>> {
+    ensure_admin(&state, &user).await?;
+    ensure_webhook(&state, user.ws_id, id).await?;
+    let deliveries = sqlx::query_as(
+        r#"
+        SELECT id, webhook_id, event_id, event, attempts, status, error, delivered_at, created_at
+        FROM webhook_deliveries WHERE webhook_id = $1 ORDER BY id DESC LIMIT $2
+        "#,
+    )
+    .bind(id)
+    .bind(DELIVERIES_LIMIT)
+    .fetch_all(&state.db)
+    .await?;
+    Ok(Json(deliveries))
+}
+
+// POST /webhooks/:id/deliveries/:delivery_id/replay, sent once with the body of the delivery
+pub(crate) async fn replay_delivery_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+    Path((id, delivery_id)): Path<(RowID, RowID)>,
+) -> AppResult<Json<Delivery>> {
+    ensure_admin(&state, &user).await?;
+    let req: Request = sqlx::query_as(
+        r#"
+        SELECT d.id, w.url, w.secret, d.event, d.body
+        FROM webhook_deliveries d JOIN webhooks w ON w.id = d.webhook_id
+        WHERE d.id = $1 AND w.id = $2 AND w.ws_id = $3
+        "#,
+    )
+    .bind(delivery_id)
+    .bind(id)
+    .bind(user.ws_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| AppError::not_found("delivery not found"))?;
+
+    let outcome = match state.webhook.send(&req).await {
+        Ok(code @ 200..=299) => Outcome::Delivered(code),
+        Ok(code) => Outcome::Failed(Some(code), None),
+        Err(e) => Outcome::Failed(None, Some(format!("{:#}", e))),
+    };
+    let delivery = record(&state, &req, 1, &outcome).await?;
+    Ok(Json(delivery))
+}
+
+/// Periodically send the deliveries claimed by instances that died before sending them
+pub fn setup_webhooks(state: NotifyState) {
+    let mut interval = time::interval(SWEEP_INTERVAL);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.shutdown.cancelled() => break,
+            }
+            match retry_stale(&state).await {
+                Ok(0) => {}
+                Ok(n) => info!("sent {} abandoned webhook deliveries", n),
+                Err(e) => warn!("failed to send abandoned webhook deliveries: {:#}", e),
+            }
+            match prune_deliveries(&state).await {
+                Ok(0) => {}
+                Ok(n) => info!("pruned {} webhook deliveries", n),
+                Err(e) => warn!("failed to prune webhook deliveries: {:#}", e),
+            }
+        }
+    });
+}
+
+/// Claim the deliveries never sent by the instance that claimed them and send them, return
+/// how many were sent. Failed deliveries are recorded and only a replay sends them again.
+pub async fn retry_stale(state: &NotifyState) -> anyhow::Result<usize> {
+    let stale_after = state.webhook.stale_after().as_secs_f64();
+    let reqs: Vec<Request> = sqlx::query_as(
+        r#"
+        UPDATE webhook_deliveries d SET claimed_at = NOW()
+        FROM webhooks w
+        WHERE w.id = d.webhook_id AND d.attempts = 0
+            AND d.claimed_at < NOW() - make_interval(secs => $1)
+        RETURNING d.id, w.url, w.secret, d.event, d.body
+        "#,
+    )
+    .bind(stale_after)
+    .fetch_all(&state.db)
+    .await?;
+    send_all(state, &reqs).await;
+    Ok(reqs.len())
+}
+
+/// Remove deliveries older than the retention that were delivered or ran out of attempts,
+/// return the number of removed deliveries
+pub async fn prune_deliveries(state: &NotifyState) -> anyhow::Result<u64> {
+    let ret = sqlx::query(
+        r#"
+        DELETE FROM webhook_deliveries
+        WHERE created_at < NOW() - make_interval(secs => $1)
+            AND (delivered_at IS NOT NULL OR attempts >= $2)
+        "#,
+    )
+    .bind(state.webhook.retention.as_secs_f64())
+    .bind(state.webhook.max_attempts as i32)
+    .execute(&state.db)
+    .await?;
+    Ok(ret.rows_affected())
+}
+
+/// Send the event to the webhooks of the workspace subscribed to it. Every instance
+/// consumes the event, a delivery is claimed by the first one to get to it.
+pub(crate) fn notify_webhooks(
+    state: &NotifyState,
+    event_id: RowID,
+    ws_id: RowID,
+    nf: &AppNotification,
+) {
+    let name = nf.event.name();
+    if !WEBHOOK_EVENTS.contains(&name) {
+        return;
+    }
+    let chat_id = nf.event.chat_id();
+    let envelope = EventEnvelope::new(nf.event.clone(), None, Utc::now());
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = deliver(&state, event_id, ws_id, chat_id, &envelope).await {
+            warn!("failed to send event {} to webhooks: {:#}", event_id, e);
+        }
+    });
+}
+
+impl WebhookService {
+    pub fn new(config: &WebhookConfig) -> anyhow::Result<Self> {
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self {
+            client,
+            max_attempts: config.max_attempts.max(1),
+            backoff: Duration::from_millis(config.retry_backoff_ms),
+            timeout,
+            allow_http: config.allow_http,
+            retention: Duration::from_secs(config.retention_days as u64 * 24 * 3600),
+        })
+    }
+
+    /// A claimed delivery not sent after this was abandoned by its instance
+    fn stale_after(&self) -> Duration {
+        // backoffs double, they add up to the last one times two
+        let retries = self.max_attempts - 1;
+        let backoffs = self.backoff.saturating_mul(1 << retries.min(16));
+        self.timeout.saturating_mul(self.max_attempts) + backoffs + STALE_MARGIN
+    }
+
+    fn check_url(&self, url: &str) -> AppResult<()> {
+        let url =
+            reqwest::Url::parse(url).map_err(|_| AppError::invalid_input("invalid webhook url"))?;
+        match url.scheme() {
+            "https" => Ok(()),
+            "http" if self.allow_http => Ok(()),
+            _ => Err(AppError::invalid_input("webhook url must be https")),
+        }
+    }
+
+    async fn send(&self, req: &Request) -> anyhow::Result<u16> {
+        let ts = Utc::now().timestamp();
+        let res = self
+            .client
+            .post(&req.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, &req.event)
+            .header(DELIVERY_HEADER, req.id)
+            .header(SIGNATURE_HEADER, sign(&req.secret, ts, &req.body))
+            .body(req.body.clone())
+            .send()
+            .await?;
+        Ok(res.status().as_u16())
+    }
+
+    /// Retry timeouts, rate limits, server errors and network errors with exponential backoff
+    async fn send_with_retry(&self, req: &Request) -> (u32, Outcome) {
+        let mut backoff = self.backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let (code, error) = match self.send(req).await {
+                Ok(code @ 200..=299) => return (attempts, Outcome::Delivered(code)),
+                Ok(code) if code == 408 || code == 429 || code >= 500 => (Some(code), None),
+                Ok(code) => return (attempts, Outcome::Failed(Some(code), None)),
+                Err(e) => (None, Some(format!("{:#}", e))),
+            };
+            if attempts >= self.max_attempts {
+                return (attempts, Outcome::Failed(code, error));
+            }
+            time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+/// Signature of a delivery, receivers compute it with their secret to check the body
+pub fn sign(secret: &str, ts: i64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any size");
+    mac.update(format!("{}.{}", ts, body).as_bytes());
+    format!("t={},v1={}", ts, hex::encode(mac.finalize().into_bytes()))
+}
+
+fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    format!("{}{}", SECRET_PREFIX, hex::encode(bytes))
+}
+
+/// Webhooks are managed by the owner of the workspace
+async fn ensure_admin(state: &NotifyState, user: &UserCliams) -> AppResult<()> {
+    if !user.is_workspace_owner(&state.db).await? {
+        return Err(AppError::forbidden(
+            "only the workspace owner can manage webhooks",
+        ));
+    }
+    Ok(())
+}
+
+async fn ensure_webhook(state: &NotifyState, ws_id: RowID, id: RowID) -> AppResult<()> {
+    let exists: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM webhooks WHERE id = $1 AND ws_id = $2)")
+            .bind(id)
+            .bind(ws_id)
+            .fetch_one(&state.db)
+            .await?;
+    if !exists {
+        return Err(AppError::not_found("webhook not found"));
+    }
+    Ok(())
+}
+
+async fn deliver(
+    state: &NotifyState,
+    event_id: RowID,
+    ws_id: RowID,
+    chat_id: Option<RowID>,
+    envelope: &EventEnvelope,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_string(envelope)?;
+    let reqs: Vec<Request> = sqlx::query_as(
+        r#"
+        WITH claimed AS (
+            INSERT INTO webhook_deliveries (webhook_id, event_id, event, body)
+            SELECT w.id, $1, $2, $3 FROM webhooks w
+            WHERE w.ws_id = $4 AND $2 = ANY(w.events)
+                AND (cardinality(w.chat_ids) = 0 OR $5 = ANY(w.chat_ids))
+            ON CONFLICT DO NOTHING
+            RETURNING id, webhook_id, event, body
+        )
+        SELECT c.id, w.url, w.secret, c.event, c.body
+        FROM claimed c JOIN webhooks w ON w.id = c.webhook_id
+        "#,
+    )
+    .bind(event_id)
+    .bind(envelope.event.name())
+    .bind(&body)
+    .bind(ws_id)
+    .bind(chat_id)
+    .fetch_all(&state.db)
+    .await?;
+    send_all(state, &reqs).await;
+    Ok(())
+}
+
+async fn send_all(state: &NotifyState, reqs: &[Request]) {
+    let sent = reqs.iter().map(|req| async move {
+        let (attempts, outcome) = state.webhook.send_with_retry(req).await;
+        if let Err(e) = record(state, req, attempts, &outcome).await {
+            warn!("failed to record webhook delivery: {:#}", e);
+        }
+    });
+    futures::future::join_all(sent).await;
+}
+
+/// Attempts add up over replays, the status is the one of the last attempt
+async fn record(
+    state: &NotifyState,
+    req: &Request,
+    attempts: u32,
+    outcome: &Outcome,
+) -> anyhow::Result<Delivery> {
+    let (status, error, delivered) = match outcome {
+        Outcome::Delivered(code) => (Some(*code), None, true),
+        Outcome::Failed(code, error) => {
+            info!(
+                "webhook delivery {} to {} failed: {:?} {:?}",
+                req.id, req.url, code, error
+            );
+            (*code, error.clone(), false)
+        }
+    };
+    let delivery = sqlx::query_as(
+        r#"
+        UPDATE webhook_deliveries
+        SET attempts = attempts + $2, status = $3, error = $4,
+            delivered_at = CASE WHEN $5 THEN NOW() END
+        WHERE id = $1
+        RETURNING id, webhook_id, event_id, event, attempts, status, error, delivered_at, created_at
+        "#,
+    )
+    .bind(req.id)
+    .bind(attempts as i32)
+    .bind(status.map(i32::from))
+    .bind(error)
+    .bind(delivered)
+    .fetch_one(&state.db)
+    .await?;
+    Ok(delivery)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use axum::{body::Bytes, http::HeaderMap, routing::post, Router};
+    use chat_core::{event::ChatEvent, notify::NotifyEvent, Chat};
+    use sqlx::PgPool;
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    use super::*;
+    use crate::{
+        outbox::{consume, init_cursor, tests::append_message},
+        NotifyConfig,
+    };
+
+    struct Received {
+        path: String,
+        headers: HeaderMap,
+        body: String,
+    }
+
+    type Sink = (
+        mpsc::UnboundedSender<Received>,
+        Arc<AtomicU32>,
+        Arc<AtomicU32>,
+    );
+
+    /// Webhook receiver, `flaky` fails the first request with a server error and `strict`
+    /// rejects the first one
+    async fn receiver() -> (String, mpsc::UnboundedReceiver<Received>) {
+        async fn handler(
+            Path(path): Path<String>,
+            State((tx, flaky, strict)): State<Sink>,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> StatusCode {
+            let code = match path.as_str() {
+                "flaky" if flaky.fetch_add(1, Ordering::Relaxed) == 0 => {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+                "strict" if strict.fetch_add(1, Ordering::Relaxed) == 0 => StatusCode::BAD_REQUEST,
+                _ => StatusCode::NO_CONTENT,
+            };
+            let _ = tx.send(Received {
+                path,
+                headers,
+                body: String::from_utf8(body.to_vec()).unwrap(),
+            });
+            code
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let counters = (Arc::new(AtomicU32::new(0)), Arc::new(AtomicU32::new(0)));
+        let router = Router::new()
+            .route("/hooks/:path", post(handler))
+            .with_state((tx, counters.0, counters.1));
+        let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", ls.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(ls, router).await.unwrap() });
+        (addr, rx)
+    }
+
+    fn state(pool: &PgPool) -> NotifyState {
+        let mut config = NotifyConfig::new_for_test();
+        config.webhook = WebhookConfig {
+            max_attempts: 3,
+            retry_backoff_ms: 10,
+            timeout_secs: 5,
+            allow_http: true,
+            retention_days: 7,
+        };
+        NotifyState::new(config, pool.clone()).unwrap()
+    }
+
+    fn owner() -> UserCliams {
+        UserCliams {
+            uid: 1,
+            ws_id: 1,
+            ..Default::default()
+        }
+    }
+
+    async fn create(
+        state: &NotifyState,
+        user: UserCliams,
+        url: String,
+        events: &[&str],
+        chat_ids: Vec<RowID>,
+    ) -> AppResult<NewWebhook> {
+        let input = CreateWebhook {
+            url,
+            events: events.iter().map(|e| e.to_string()).collect(),
+            chat_ids,
+        };
+        let (code, Json(webhook)) =
+            create_webhook_handler(Extension(user), State(state.clone()), Json(input)).await?;
+        assert_eq!(code, StatusCode::CREATED);
+        Ok(webhook)
+    }
+
+    async fn deliveries(state: &NotifyState, id: RowID) -> Vec<Delivery> {
+        let path = Path(id);
+        let Json(ret) = list_deliveries_handler(Extension(owner()), State(state.clone()), path)
+            .await
+            .unwrap();
+        ret
+    }
+
+    #[test]
+    fn t_sign() {
+        let signature = sign("whsec_a", 1700000000, r#"{"a":1}"#);
+        let (ts, mac) = signature.split_once(',').unwrap();
+        assert_eq!(ts, "t=1700000000");
+        assert_eq!(mac.len(), "v1=".len() + 64);
+        assert_ne!(signature, sign("whsec_b", 1700000000, r#"{"a":1}"#));
+        assert_ne!(signature, sign("whsec_a", 1700000001, r#"{"a":1}"#));
+        assert!(generate_secret().starts_with(SECRET_PREFIX));
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_create_webhook(pool: PgPool) {
+        let state = state(&pool);
+        let url = "https://example.com/hook".to_string();
+        let member = UserCliams {
+            uid: 2,
+            ws_id: 1,
+            ..Default::default()
+        };
+        let ret = create(&state, member, url.clone(), &["NewMessage"], vec![]).await;
+        assert!(matches!(ret, Err(AppError::Forbidden(_))));
+
+        for (url, events, chat_ids) in [
+            ("not a url", vec!["NewMessage"], vec![]),
+            ("ftp://example.com", vec!["NewMessage"], vec![]),
+            ("https://example.com", vec![], vec![]),
+            ("https://example.com", vec!["Typing"], vec![]),
+            // chat 2 is in another workspace
+            ("https://example.com", vec!["NewMessage"], vec![2]),
+        ] {
+            let ret = create(&state, owner(), url.to_string(), &events, chat_ids).await;
+            assert!(matches!(ret, Err(AppError::InvalidInput(_))), "{}", url);
+        }
+
+        let events = ["NewMessage", "NewChat", "NewMessage"];
+        let new = create(&state, owner(), url, &events, vec![1, 1])
+            .await
+            .unwrap();
+        assert!(new.secret.starts_with(SECRET_PREFIX));
+        assert_eq!(new.webhook.events, vec!["NewChat", "NewMessage"]);
+        assert_eq!(new.webhook.chat_ids, vec![1]);
+
+        let Json(list) = list_webhooks_handler(Extension(owner()), State(state.clone()))
+            .await
+            .unwrap();
+        assert_eq!(list.len(), 1);
+        let json = serde_json::to_value(&list[0]).unwrap();
+        assert!(json.get("secret").is_none());
+
+        // plain http needs to be allowed
+        let strict = NotifyState::new_for_test(pool.clone());
+        let url = "http://example.com/hook".to_string();
+        let ret = create(&strict, owner(), url, &["NewMessage"], vec![]).await;
+        assert!(matches!(ret, Err(AppError::InvalidInput(_))));
+
+        let other = UserCliams {
+            uid: 3,
+            ws_id: 2,
+            ..Default::default()
+        };
+        let path = Path(new.webhook.id);
+        let ret = delete_webhook_handler(Extension(other), State(state.clone()), path).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let path = Path(new.webhook.id);
+        let code = delete_webhook_handler(Extension(owner()), State(state.clone()), path)
+            .await
+            .unwrap();
+        assert_eq!(code, StatusCode::NO_CONTENT);
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_deliver_webhooks(pool: PgPool) {
+        let state = state(&pool);
+        init_cursor(&state).await.unwrap();
+        let (addr, mut rx) = receiver().await;
+
+        let ok = create(
+            &state,
+            owner(),
+            format!("{}/hooks/ok", addr),
+            &["NewMessage"],
+            vec![1],
+        )
+        .await
+        .unwrap();
+        let flaky = create(
+            &state,
+            owner(),
+            format!("{}/hooks/flaky", addr),
+            &["NewMessage"],
+            vec![],
+        )
+        .await
+        .unwrap();
+        let strict = create(
+            &state,
+            owner(),
+            format!("{}/hooks/strict", addr),
+            &["NewMessage"],
+            vec![],
+        )
+        .await
+        .unwrap();
+        // not subscribed to messages
+        create(
+            &state,
+            owner(),
+            format!("{}/hooks/chats", addr),
+            &["NewChat"],
+            vec![],
+        )
+        .await
+        .unwrap();
+
+        append_message(&pool, 1, "hello hook").await;
+        consume(&state).await.unwrap();
+
+        let mut received = vec![];
+        while received.len() < 4 {
+            received.push(rx.recv().await.unwrap());
+        }
+        let req = received.iter().find(|r| r.path == "ok").unwrap();
+        assert_eq!(req.headers[EVENT_HEADER], "NewMessage");
+        let signature = req.headers[SIGNATURE_HEADER].to_str().unwrap();
+        let ts: i64 = signature[2..signature.find(',').unwrap()].parse().unwrap();
+        assert_eq!(signature, sign(&ok.secret, ts, &req.body));
+        let envelope = EventEnvelope::decode(&req.body).unwrap();
+        let NotifyEvent::NewMessage(message) = envelope.event else {
+            panic!("unexpected event: {:?}", envelope.event);
+        };
+        assert_eq!(message.content, "hello hook");
+        assert_eq!(received.iter().filter(|r| r.path == "flaky").count(), 2);
+        assert_eq!(received.iter().filter(|r| r.path == "strict").count(), 1);
+
+        // deliveries are recorded with the response codes
+        let delivered = |id| {
+            let state = state.clone();
+            async move {
+                loop {
+                    let ret = deliveries(&state, id).await;
+                    if ret.iter().all(|d| d.status.is_some()) {
+                        break ret;
+                    }
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        let ret = delivered(ok.webhook.id).await;
+        assert_eq!((ret[0].attempts, ret[0].status), (1, Some(204)));
+        assert!(ret[0].delivered_at.is_some());
+        let ret = delivered(flaky.webhook.id).await;
+        assert_eq!((ret[0].attempts, ret[0].status), (2, Some(204)));
+        let ret = delivered(strict.webhook.id).await;
+        assert_eq!((ret[0].attempts, ret[0].status), (1, Some(400)));
+        assert!(ret[0].delivered_at.is_none());
+
+        // another instance consuming the same event doesn't send it again
+        let other = self::state(&pool);
+        other
+            .event_cursor
+            .store(ret[0].event_id - 1, Ordering::Relaxed);
+        consume(&other).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+
+        // the failed delivery is replayed with the same body
+        let path = Path((strict.webhook.id, ret[0].id));
+        let Json(replayed) =
+            replay_delivery_handler(Extension(owner()), State(state.clone()), path)
+                .await
+                .unwrap();
+        assert_eq!((replayed.attempts, replayed.status), (2, Some(204)));
+        assert!(replayed.delivered_at.is_some());
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.path, "strict");
+        assert_eq!(req.headers[DELIVERY_HEADER], ret[0].id.to_string().as_str());
+        let first = received.iter().find(|r| r.path == "strict").unwrap();
+        assert_eq!(req.body, first.body);
+
+        let path = Path((ok.webhook.id, ret[0].id));
+        let ret = replay_delivery_handler(Extension(owner()), State(state.clone()), path).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_member_added_webhook(pool: PgPool) {
+        let state = state(&pool);
+        init_cursor(&state).await.unwrap();
+        let (addr, mut rx) = receiver().await;
+        let url = format!("{}/hooks/members", addr);
+        create(&state, owner(), url, &["MemberAdded"], vec![1])
+            .await
+            .unwrap();
+
+        // a member added by user 1 the way chat_server records it
+        let old: Chat = sqlx::query_as("SELECT * FROM chats WHERE id = 1")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let mut new = old.clone();
+        new.members.push(5);
+        let event = ChatEvent::ChatUpdated { actor: 1, old, new };
+        sqlx::query("INSERT INTO events (ws_id, payload) VALUES (1, $1)")
+            .bind(sqlx::types::Json(event))
+            .execute(&pool)
+            .await
+            .unwrap();
+        consume(&state).await.unwrap();
+
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.headers[EVENT_HEADER], "MemberAdded");
+        let envelope = EventEnvelope::decode(&req.body).unwrap();
+        let NotifyEvent::MemberAdded(change) = envelope.event else {
+            panic!("unexpected event: {:?}", envelope.event);
+        };
+        assert_eq!((change.actor, change.members), (1, vec![5]));
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_retry_stale_deliveries(pool: PgPool) {
+        let state = state(&pool);
+        let (addr, mut rx) = receiver().await;
+        let url = format!("{}/hooks/ok", addr);
+        let hook = create(&state, owner(), url, &["NewMessage"], vec![])
+            .await
+            .unwrap();
+
+        // claimed by instances that died before sending them, one of them long ago
+        for (event_id, claimed_secs_ago) in [(1, 3600), (2, 0)] {
+            sqlx::query(
+                r#"
+                INSERT INTO webhook_deliveries (webhook_id, event_id, event, body, claimed_at)
+                VALUES ($1, $2, 'NewMessage', '{}', NOW() - make_interval(secs => $3))
+                "#,
+            )
+            .bind(hook.webhook.id)
+            .bind(event_id)
+            .bind(claimed_secs_ago as f64)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(retry_stale(&state).await.unwrap(), 1);
+        let req = rx.recv().await.unwrap();
+        assert_eq!(req.path, "ok");
+        let ret = deliveries(&state, hook.webhook.id).await;
+        assert_eq!((ret[1].event_id, ret[1].status), (1, Some(204)));
+        assert_eq!((ret[0].event_id, ret[0].attempts), (2, 0));
+
+        // sent deliveries are not sent again
+        assert_eq!(retry_stale(&state).await.unwrap(), 0);
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_prune_deliveries(pool: PgPool) {
+        let state = state(&pool);
+        let url = "http://127.0.0.1:1/hooks".to_string();
+        let hook = create(&state, owner(), url, &["NewMessage"], vec![])
+            .await
+            .unwrap();
+
+        // delivered and failed long ago, still being retried, delivered recently
+        let rows = [
+            (1, 8, 1, true),
+            (2, 8, 3, false),
+            (3, 8, 1, false),
+            (4, 1, 1, true),
+        ];
+        for (event_id, days_ago, attempts, delivered) in rows {
+            sqlx::query(
+                r#"
+                INSERT INTO webhook_deliveries
+                    (webhook_id, event_id, event, body, attempts, delivered_at, created_at)
+                VALUES ($1, $2, 'NewMessage', '{}', $3,
+                    CASE WHEN $4 THEN NOW() END, NOW() - make_interval(days => $5))
+                "#,
+            )
+            .bind(hook.webhook.id)
+            .bind(event_id)
+            .bind(attempts)
+            .bind(delivered)
+            .bind(days_ago)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(prune_deliveries(&state).await.unwrap(), 2);
+        let mut kept: Vec<_> = deliveries(&state, hook.webhook.id)
+            .await
+            .iter()
+            .map(|d| d.event_id)
+            .collect();
+        kept.sort();
+        assert_eq!(kept, vec![3, 4]);
+        assert_eq!(prune_deliveries(&state).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,289 @@
+use std::time::Duration;
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::Response,
+    Extension,
+};
+use chat_core::{
+    notify::{NotifyEvent, ReadMarker},
+    utils::UserCliams,
+    RowID,
+};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::{
+    cluster::Audience,
+    notify_event::{publish_ephemeral, UserEvent},
+    presence::set_away,
+    subscriber::user_events,
+    typing::start_typing,
+    NotifyState,
+};
+
+/// Keep the connection alive through proxies that close idle sockets
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct WsParams {
+    last_event_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum ClientFrame {
+    Ping,
+    Typing {
+        chat_id: RowID,
+    },
+    Read(ReadMarker),
+    Heartbeat {
+        #[serde(default)]
+        away: bool,
+    },
+}
+
+/// Replies to client frames, events are sent as `EventEnvelope`
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame {
+    Pong,
+    Error { message: String },
+}
+
+pub(crate) async fn ws_handler(
+    Extension(user): Extension<UserCliams>,
+    State(state): State<NotifyState>,
+    Query(params): Query<WsParams>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| handle_socket(socket, state, user, params.last_event_id))
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: NotifyState,
+    user: UserCliams,
+    last_event_id: Option<String>,
+) {
+    info!("user {} connected via websocket", user.uid);
+
+    let (mut sender, mut receiver) = socket.split();
+    let mut events = Box::pin(user_events(&state, &user, last_event_id.as_deref()));
+    let mut ping = time::interval(PING_INTERVAL);
+
+    loop {
+        let msg = tokio::select! {
+            ev = events.next() => match ev {
+                Some(UserEvent::Disconnect { reason }) => {
+                    let frame = CloseFrame {
+                        code: close_code::AGAIN,
+                        reason: reason.into(),
+                    };
+                    let _ = sender.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+                Some(ev) => text_message(&ev.envelope(&state.instance_id, user.uid)),
+                None => {
+                    // the instance is drained, the client should reconnect
+                    let frame = CloseFrame {
+                        code: close_code::RESTART,
+                        reason: "server restarting".into(),
+                    };
+                    let _ = sender.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+            },
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    match handle_client_frame(&state, &user, &text).await {
+                        Some(frame) => text_message(&frame),
+                        None => continue,
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                // pings are answered by axum
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    warn!("websocket of user {} failed: {}", user.uid, e);
+                    break;
+                }
+            },
+            _ = ping.tick() => Message::Ping(vec![]),
+        };
+
+        if let Err(e) = sender.send(msg).await {
+            warn!("failed to send to user {}: {}", user.uid, e);
+            break;
+        }
+    }
+
+    info!("user {} websocket closed", user.uid);
+}
+
+async fn handle_client_frame(
+    state: &NotifyState,
+    user: &UserCliams,
+    text: &str,
+) -> Option<ServerFrame> {
+    let frame = match serde_json::from_str::<ClientFrame>(text) {
+        Ok(frame) => frame,
+        Err(e) => {
+            return Some(ServerFrame::Error {
+                message: format!("invalid frame: {}", e),
+            })
+        }
+    };
+
+    match frame {
+        ClientFrame::Ping => Some(ServerFrame::Pong),
+        ClientFrame::Read(marker) => {
+            let audience = Audience::Users(vec![user.uid]);
+            publish_ephemeral(state, audience, NotifyEvent::ReadMarker(marker));
+            None
+        }
+        ClientFrame::Typing { chat_id } => match start_typing(state, chat_id, user.uid).await {
+            Ok(_) => None,
+            Err(e) => Some(ServerFrame::Error {
+                message: e.to_string(),
+            }),
+        },
+        ClientFrame::Heartbeat { away } => {
+            set_away(state, user.uid, away);
+            None
+        }
+    }
+}
+
+fn text_message(frame: &impl Serialize) -> Message {
+    // frames only contain plain data, serializing can't fail
+    Message::Text(serde_json::to_string(frame).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use chat_core::utils::JwtEncodingKey;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+
+    use super::*;
+    use crate::{
+        event_log::format_event_id,
+        get_router,
+        notify_event::{publish, AppNotification},
+    };
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn connect(addr: &str, token: &str, query: &str) -> Client {
+        let url = format!("ws://{}/ws?access_token={}{}", addr, token, query);
+        tokio_tungstenite::connect_async(url).await.unwrap().0
+    }
+
+    async fn next_frame(client: &mut Client) -> Value {
+        loop {
+            match client.next().await.unwrap().unwrap() {
+                tungstenite::Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                _ => continue,
+            }
+        }
+    }
+
+    async fn send_frame(client: &mut Client, frame: Value) {
+        let text = frame.to_string();
+        client.send(tungstenite::Message::Text(text)).await.unwrap();
+    }
+
+    #[sqlx::test(
+        migrator = "chat_server::tests::MIGRATOR",
+        fixtures("../../fixtures/test.sql")
+    )]
+    async fn t_ws_events(pool: PgPool) {
+        let state = NotifyState::new_for_test(pool);
+        let ek = JwtEncodingKey::load(include_bytes!("../../fixtures/private.pem")).unwrap();
+        let token = ek
+            .sign(&UserCliams {
+                uid: 1,
+                ws_id: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        let token2 = ek
+            .sign(&UserCliams {
+                uid: 2,
+                ws_id: 1,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let router = get_router(state.clone()).await.unwrap();
+        let ls = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = ls.local_addr().unwrap().to_string();
+        tokio::spawn(async move { axum::serve(ls, router).await.unwrap() });
+
+        let mut client = connect(&addr, &token, "").await;
+        send_frame(&mut client, json!({ "type": "ping" })).await;
+        assert_eq!(next_frame(&mut client).await, json!({ "type": "pong" }));
+
+        send_frame(&mut client, json!({ "type": "unknown" })).await;
+        assert_eq!(next_frame(&mut client).await["type"], "error");
+
+        // read markers are echoed to the connections of the user
+        let marker = json!({ "type": "read", "chat_id": 1, "message_id": 2 });
+        send_frame(&mut client, marker).await;
+        let frame = next_frame(&mut client).await;
+        assert_eq!(frame["type"], "ReadMarker");
+        assert_eq!(frame["version"], 1);
+        assert_eq!(frame["id"], Value::Null);
+        assert_eq!(frame["data"]["message_id"], 2);
+
+        let message = serde_json::from_value(json!({
+            "id": 1, "chat_id": 1, "sender_id": 2, "content": "hello",
+            "files": [], "created_at": "2024-01-01T00:00:00Z"
+        }))
+        .unwrap();
+        publish(
+            &state,
+            AppNotification {
+                users: HashSet::from([1, 2]),
+                silent: HashSet::from([1]),
+                event: NotifyEvent::NewMessage(message),
+            },
+        );
+        let frame = next_frame(&mut client).await;
+        assert_eq!(frame["type"], "NewMessage");
+        assert_eq!(frame["id"], format_event_id(&state.instance_id, 1));
+        assert_eq!(frame["silent"], true);
+
+        // typing is sent to the other chat members
+        let mut client2 = connect(&addr, &token2, "").await;
+        let frame = next_frame(&mut client).await;
+        assert_eq!(frame["data"]["user_id"], 2);
+        send_frame(&mut client, json!({ "type": "typing", "chat_id": 1 })).await;
+        let frame = next_frame(&mut client2).await;
+        assert_eq!(frame["type"], "Typing");
+        assert_eq!(
+            frame["data"],
+            json!({ "chat_id": 1, "user_id": 1, "typing": true })
+        );
+        send_frame(&mut client, json!({ "type": "typing", "chat_id": 2 })).await;
+        assert_eq!(next_frame(&mut client).await["type"], "error");
+        client.close(None).await.unwrap();
+
+        // missed events are replayed on reconnect
+        let query = format!("&last_event_id={}", format_event_id(&state.instance_id, 0));
+        let mut client = connect(&addr, &token, &query).await;
+        let frame = next_frame(&mut client).await;
+        assert_eq!(frame["data"]["content"], "hello");
+    }
+}